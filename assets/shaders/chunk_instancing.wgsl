@@ -8,6 +8,38 @@ var<uniform> mesh: Mesh;
 #import bevy_pbr::mesh_functions
 
 #import bevy_pbr::pbr_types
+
+// Same bindings as bevy_pbr::pbr_bindings but in group 3, prepare_normal needs them declared before pbr_functions
+#ifdef STANDARD_MATERIAL
+@group(3) @binding(0)
+var<uniform> material: StandardMaterial;
+@group(3) @binding(1)
+var base_color_texture: texture_2d<f32>;
+@group(3) @binding(2)
+var base_color_sampler: sampler;
+@group(3) @binding(3)
+var emissive_texture: texture_2d<f32>;
+@group(3) @binding(4)
+var emissive_sampler: sampler;
+@group(3) @binding(5)
+var metallic_roughness_texture: texture_2d<f32>;
+@group(3) @binding(6)
+var metallic_roughness_sampler: sampler;
+@group(3) @binding(7)
+var occlusion_texture: texture_2d<f32>;
+@group(3) @binding(8)
+var occlusion_sampler: sampler;
+@group(3) @binding(9)
+var normal_map_texture: texture_2d<f32>;
+@group(3) @binding(10)
+var normal_map_sampler: sampler;
+#else
+@group(3) @binding(0)
+var diffuse_texture: texture_2d_array<f32>;
+@group(3) @binding(1)
+var diffuse_sampler: sampler;
+#endif
+
 #import bevy_pbr::utils
 #import bevy_pbr::clustered_forward
 #import bevy_pbr::lighting
@@ -18,16 +50,29 @@ struct Vertex {
     @location(0) position: vec3<f32>,
     @location(1) normal: vec3<f32>,
     @location(2) uv: vec2<f32>,
+#ifdef VERTEX_TANGENTS
+    @location(3) tangent: vec4<f32>,
+#endif
     @builtin(instance_index) instance_index: u32,
 
 };
 
 struct InstanceInput {
-    @location(3) xyz: vec4<f32>,
+    @location(8) pos_scale: vec4<f32>,
+#ifdef INSTANCE_FULL_TRANSFORM
+    @location(9) rotation: vec4<f32>,
+    @location(10) scale: vec4<f32>,
+#endif
+#ifdef INSTANCE_VARIATION
+    @location(11) tint_layer: vec4<f32>,
+#endif
 }
 
 struct PlantChunk{
-    model_transform: mat4x4<f32>
+    model_transform: mat4x4<f32>,
+    normal_transform: mat4x4<f32>, //Inverse transpose of model_transform
+    alpha_cutoff: vec4<f32>, //Only x is used
+    fade: vec4<f32>, //Only x is used, 1 is fully visible
 }
 
  @group(2) @binding(0)
@@ -39,13 +84,19 @@ struct VertexOutput {
     @location(0) world_position: vec4<f32>,
     @location(1) world_normal: vec3<f32>,
     @location(2) uv: vec2<f32>,
+    @location(3) tint: vec3<f32>,
+    @location(4) @interpolate(flat) texture_layer: i32,
+#ifdef VERTEX_TANGENTS
+    @location(5) world_tangent: vec4<f32>,
+#endif
 };
 
 
+#import bevy_efficient_forest_rendering::rng
 
-// [0,1.0]
-fn rand(co: vec2<f32>, seed: f32)-> f32{
-    return fract(sin(dot(co, vec2(12.9898, 78.233))) * 43758.5453);
+fn quat_rotate(q: vec4<f32>, v: vec3<f32>) -> vec3<f32> {
+    let t = 2.0 * cross(q.xyz, v);
+    return v + q.w * t + cross(q.xyz, t);
 }
 
 @vertex
@@ -55,33 +106,45 @@ fn vertex(vertex: Vertex,
 
     var out: VertexOutput;
     out.uv = vertex.uv;
+#ifdef INSTANCE_VARIATION
+    out.tint = instance.tint_layer.rgb;
+    out.texture_layer = i32(round(instance.tint_layer.w));
+#else
+    out.tint = vec3<f32>(1.0);
+    out.texture_layer = 0;
+#endif
 
-    let rand_scale = rand(vec2<f32>(instance.xyz.y, 42.546*sin(instance.xyz.x)), 3.0)*0.2+0.9;
-    let transformed_position = plant_chunk.model_transform*vec4<f32>(vertex.position, 1.0)*instance.xyz.w*rand_scale;
-
-    let rot_y = rand(vec2<f32>(instance.xyz.x, 10.1512515*cos(instance.xyz.z)),
-         1.0)*3.1415*2.0;
+#ifdef INSTANCE_FULL_TRANSFORM
+    let rotation = instance.rotation;
+    let scale = instance.scale.xyz * instance.pos_scale.w;
+#else
+    //Random yaw and scale jitter from the position, Instance::transform mirrors this
+    let hash = pcg_hash(bitcast<u32>(instance.pos_scale.x) ^ pcg_hash(bitcast<u32>(instance.pos_scale.z)));
+    let rot_y = hash_to_unit(hash) * 6.2831855;
+    let rand_scale = hash_to_unit(pcg_hash(hash)) * 0.2 + 0.9;
+    let rotation = vec4<f32>(0.0, sin(rot_y * 0.5), 0.0, cos(rot_y * 0.5));
+    let scale = vec3<f32>(instance.pos_scale.w * rand_scale);
+#endif
 
-    let rot_mat = mat2x2<f32>(vec2<f32>(cos(rot_y), -sin(rot_y)), vec2<f32>(sin(rot_y), cos(rot_y)));
-    let rotated_xz = rot_mat*transformed_position.xz;
-    let position= vec4<f32>(transformed_position.x+instance.xyz.x, transformed_position.y+instance.xyz.y, transformed_position.z+instance.xyz.z, 1.0);
+    let model_position = (plant_chunk.model_transform * vec4<f32>(vertex.position, 1.0)).xyz;
+    let position = vec4<f32>(quat_rotate(rotation, model_position * scale) + instance.pos_scale.xyz, 1.0);
 
-    //Somthing not right about the normals?
-    let transformed_normals = plant_chunk.model_transform*vec4<f32>(vertex.normal, 1.0);
-    let rotated_normals = transformed_normals.xy;
-    let normals= vec3<f32>(rotated_normals.x,rotated_normals.y,transformed_normals.z);
+    //Normals need the inverse transpose, for the instance scale that is dividing instead of multiplying
+    let model_normal = (plant_chunk.normal_transform * vec4<f32>(vertex.normal, 0.0)).xyz;
+    let normal = normalize(quat_rotate(rotation, model_normal / scale));
 
     out.world_position = mesh_position_local_to_world(mesh.model, position);
-    out.world_normal = mesh_normal_local_to_world(normals);
+    out.world_normal = mesh_normal_local_to_world(normal);
+#ifdef VERTEX_TANGENTS
+    //Tangents follow the surface so they transform like positions
+    let model_tangent = (plant_chunk.model_transform * vec4<f32>(vertex.tangent.xyz, 0.0)).xyz;
+    let tangent = quat_rotate(rotation, model_tangent * scale);
+    out.world_tangent = mesh_tangent_local_to_world(mesh.model, vec4<f32>(tangent, vertex.tangent.w));
+#endif
     out.clip_position = mesh_position_world_to_clip(out.world_position);
     return out;
 }
 
-@group(3) @binding(0)
-var diffuse_texture: texture_2d<f32>;
-@group(3) @binding(1)
-var diffuse_sampler: sampler;
-
 // @fragment
 // fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
 //     return textureSample(diffuse_texture, diffuse_sampler, in.uv);
@@ -96,15 +159,140 @@ struct FragmentInput {
     @location(0) world_position: vec4<f32>,
     @location(1) world_normal: vec3<f32>,
     @location(2) uv: vec2<f32>,
+    @location(3) tint: vec3<f32>,
+    @location(4) @interpolate(flat) texture_layer: i32,
+#ifdef VERTEX_TANGENTS
+    @location(5) world_tangent: vec4<f32>,
+#endif
 };
 
+// Screen-door transparency: discards a growing share of pixels in a 4x4 bayer pattern as the chunk fades out,
+// so it stays in the opaque passes
+fn dither_fade(frag_coord: vec2<f32>) {
+    if (plant_chunk.fade.x >= 1.0) {
+        return;
+    }
+    var bayer = array<f32, 16>(
+        0.0, 8.0, 2.0, 10.0,
+        12.0, 4.0, 14.0, 6.0,
+        3.0, 11.0, 1.0, 9.0,
+        15.0, 7.0, 13.0, 5.0,
+    );
+    let pixel = vec2<u32>(frag_coord) % vec2<u32>(4u);
+    let threshold = (bayer[pixel.y * 4u + pixel.x] + 0.5) / 16.0;
+    if (plant_chunk.fade.x < threshold) {
+        discard;
+    }
+}
+
+// Shadow pass of alpha mask layers and of chunks inside their fade band, dithers like the main pass and cuts out
+// the texels below the cutoff. Shadow views only bind the view uniform in group 0, nothing of the lighting can be used here
+@fragment
+fn fragment_depth(in: FragmentInput) {
+    dither_fade(in.frag_coord.xy);
+#ifdef ALPHA_MASK
+#ifdef STANDARD_MATERIAL
+    var alpha = material.base_color.a;
+    if ((material.flags & STANDARD_MATERIAL_FLAGS_BASE_COLOR_TEXTURE_BIT) != 0u) {
+        alpha = alpha * textureSample(base_color_texture, base_color_sampler, in.uv).a;
+    }
+    let alpha_cutoff = material.alpha_cutoff;
+#else
+    let alpha = textureSample(diffuse_texture, diffuse_sampler, in.uv, in.texture_layer).a;
+    let alpha_cutoff = plant_chunk.alpha_cutoff.x;
+#endif
+    if (alpha < alpha_cutoff) {
+        discard;
+    }
+#endif
+}
+
+#ifdef STANDARD_MATERIAL
+// Same as bevy_pbr::pbr with the instance tint on top of the base color
+@fragment
+fn fragment(in: FragmentInput) -> @location(0) vec4<f32> {
+    dither_fade(in.frag_coord.xy);
+    var output_color: vec4<f32> = material.base_color * vec4<f32>(in.tint, 1.0);
+    if ((material.flags & STANDARD_MATERIAL_FLAGS_BASE_COLOR_TEXTURE_BIT) != 0u) {
+        output_color = output_color * textureSample(base_color_texture, base_color_sampler, in.uv);
+    }
+#ifdef ALPHA_MASK
+    if (output_color.a < material.alpha_cutoff) {
+        discard;
+    }
+#endif
+
+    if ((material.flags & STANDARD_MATERIAL_FLAGS_UNLIT_BIT) != 0u) {
+        return output_color;
+    }
+
+    var pbr_input: PbrInput;
+    pbr_input.material.base_color = output_color;
+    pbr_input.material.reflectance = material.reflectance;
+    pbr_input.material.flags = material.flags;
+    pbr_input.material.alpha_cutoff = material.alpha_cutoff;
+
+    var emissive: vec4<f32> = material.emissive;
+    if ((material.flags & STANDARD_MATERIAL_FLAGS_EMISSIVE_TEXTURE_BIT) != 0u) {
+        emissive = vec4<f32>(emissive.rgb * textureSample(emissive_texture, emissive_sampler, in.uv).rgb, 1.0);
+    }
+    pbr_input.material.emissive = emissive;
+
+    var metallic: f32 = material.metallic;
+    var perceptual_roughness: f32 = material.perceptual_roughness;
+    if ((material.flags & STANDARD_MATERIAL_FLAGS_METALLIC_ROUGHNESS_TEXTURE_BIT) != 0u) {
+        let metallic_roughness = textureSample(metallic_roughness_texture, metallic_roughness_sampler, in.uv);
+        // Sampling from GLTF standard channels for now
+        metallic = metallic * metallic_roughness.b;
+        perceptual_roughness = perceptual_roughness * metallic_roughness.g;
+    }
+    pbr_input.material.metallic = metallic;
+    pbr_input.material.perceptual_roughness = perceptual_roughness;
+
+    var occlusion: f32 = 1.0;
+    if ((material.flags & STANDARD_MATERIAL_FLAGS_OCCLUSION_TEXTURE_BIT) != 0u) {
+        occlusion = textureSample(occlusion_texture, occlusion_sampler, in.uv).r;
+    }
+    pbr_input.occlusion = occlusion;
+
+    pbr_input.frag_coord = in.frag_coord;
+    pbr_input.world_position = in.world_position;
+    pbr_input.world_normal = in.world_normal;
+
+    pbr_input.is_orthographic = view.projection[3].w == 1.0;
+
+    pbr_input.N = prepare_normal(
+        material.flags,
+        in.world_normal,
+#ifdef VERTEX_TANGENTS
+#ifdef STANDARDMATERIAL_NORMAL_MAP
+        in.world_tangent,
+#endif
+#endif
+        in.uv,
+        in.is_front,
+    );
+    pbr_input.V = calculate_view(in.world_position, pbr_input.is_orthographic);
+
+    return tone_mapping(pbr(pbr_input));
+}
+#else
 @fragment
 fn fragment(in: FragmentInput) -> @location(0) vec4<f32> {
+    dither_fade(in.frag_coord.xy);
     // Prepare a 'processed' StandardMaterial by sampling all textures to resolve
     // the material members
     var pbr_input: PbrInput = pbr_input_new();
 
-    pbr_input.material.base_color = textureSample(diffuse_texture, diffuse_sampler, in.uv);
+    pbr_input.material.base_color = textureSample(diffuse_texture, diffuse_sampler, in.uv, in.texture_layer) * vec4<f32>(in.tint, 1.0);
+#ifdef ALPHA_MASK
+    if (pbr_input.material.base_color.a < plant_chunk.alpha_cutoff.x) {
+        discard;
+    }
+#endif
+#ifdef ALPHA_BLEND
+    pbr_input.material.flags = STANDARD_MATERIAL_FLAGS_ALPHA_MODE_BLEND;
+#endif
     pbr_input.material.reflectance = 0.0;
     // pbr_input.material.emissive = 0.0;
 
@@ -124,3 +312,4 @@ fn fragment(in: FragmentInput) -> @location(0) vec4<f32> {
 
     return tone_mapping(pbr(pbr_input));
 }
+#endif
@@ -0,0 +1,13 @@
+#define_import_path bevy_efficient_forest_rendering::rng
+
+// Same as rng.rs, integer only so it matches on every gpu
+fn pcg_hash(input: u32) -> u32 {
+    let state = input * 747796405u + 2891336453u;
+    let word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    return (word >> 22u) ^ word;
+}
+
+// [0,1.0)
+fn hash_to_unit(hash: u32) -> f32 {
+    return f32(hash >> 8u) * (1.0 / 16777216.0);
+}
@@ -7,6 +7,8 @@ var<uniform> mesh: Mesh;
 
 // NOTE: Bindings must come before functions that use them!
 #import bevy_pbr::mesh_functions
+#import bevy_pbr::shadows
+#import bevy_pbr::clustered_forward
 
 
 
@@ -103,7 +105,11 @@ fn perlinNoise3(P: vec3<f32>) -> f32 {
     chunk_half_extents: vec2<f32>,
     growth_texture_id: vec4<i32>,
     height_modifier: vec4<f32>,
-    scale_modifier: vec4<f32>
+    scale_modifier: vec4<f32>,
+    alpha_cutoff: vec4<f32>,
+    fade: vec4<f32>, //Only x is used, 1 is fully visible
+    seed: vec4<u32>, //Only x is used
+    max_growth: vec4<f32>, //Only x is used
  };
 
  @group(2) @binding(0)
@@ -116,11 +122,35 @@ fn perlinNoise3(P: vec3<f32>) -> f32 {
 //  var<uniform> grass_pool: GrassPool;
 
 
-@group(3) @binding(0)
-var growth_textures: texture_2d_array<f32>;
-@group(3) @binding(1)
-var growth_sampler: sampler;
+// Binds growth_textures at group 3 binding 0
+#import bevy_efficient_forest_rendering::grass_placement
+
+struct GpuTerrain {
+    origin: vec2<f32>,
+    size: vec2<f32>,
+    resolution: vec4<i32>,
+};
 
+@group(3) @binding(1)
+var terrain_heights: texture_2d<f32>;
+@group(3) @binding(2)
+var<uniform> terrain: GpuTerrain;
+
+// Bilinear height at a world xz, same as ForestTerrain::height_at. R32Float can't be filtered by the sampler
+fn terrain_height(world_xz: vec2<f32>) -> f32 {
+    let last = terrain.resolution.xy - vec2<i32>(1);
+    let spacing = terrain.size / vec2<f32>(last);
+    let texel = clamp((world_xz - terrain.origin) / spacing, vec2<f32>(0.0), vec2<f32>(last));
+    let i0 = vec2<i32>(floor(texel));
+    let i1 = min(i0 + vec2<i32>(1), last);
+    let t = texel - vec2<f32>(i0);
+
+    let h00 = textureLoad(terrain_heights, i0, 0).x;
+    let h10 = textureLoad(terrain_heights, vec2<i32>(i1.x, i0.y), 0).x;
+    let h01 = textureLoad(terrain_heights, vec2<i32>(i0.x, i1.y), 0).x;
+    let h11 = textureLoad(terrain_heights, i1, 0).x;
+    return mix(mix(h00, h10, t.x), mix(h01, h11, t.x), t.y);
+}
 
  struct GpuGridConfig {
     grid_center_xy: vec2<f32>, //Assume axis aligned grid otherwise need to calc homogenous coordinate matrix
@@ -155,40 +185,48 @@ fn rand(co: vec2<f32>)-> f32{
     return fract(sin(dot(co, vec2(12.9898, 78.233))) * 438.5453);
 }
 
-
 @vertex
 fn vertex(vertex: Vertex,
 ) -> VertexOutput {
     var out: VertexOutput;
 
-    //Random Base World position
-    // let seed = sqrt(material.chunk_xy.x*material.chunk_xy.y+1.1);
-    let x = (rand(vec2<f32>(sin(f32(vertex.instance_index)), 1.1512515*cos(f32(vertex.instance_index))))*material.chunk_half_extents.x*2.0);
-    let z = (rand(vec2<f32>(0.902415*sin(f32(vertex.instance_index)), cos(f32(vertex.instance_index))))*material.chunk_half_extents.y*2.0);
+    //Random Base World position, seeded per chunk so chunks don't repeat
+    let hash = blade_hash(material.seed.x, vertex.instance_index);
+    let placement = blade_placement(hash, material.chunk_half_extents);
+    let x = placement.x;
+    let z = placement.y;
 
     let base_position = vec4<f32>(x,0.0,z,1.0);
     let base_position_world = mesh_position_local_to_world(mesh.model, base_position);
 
     //Random Rotate
-    let rot_z = rand(vec2<f32>(12.554215, f32(vertex.instance_index)))*30.1415;
+    let rot_z = placement.z;
     let rot_mat = mat2x2<f32>(vec2<f32>(cos(rot_z), -sin(rot_z)), vec2<f32>(sin(rot_z), cos(rot_z)));
     let rotated_xy = rot_mat*vertex.position.xz*material.scale_modifier.x;
     let local_y = vertex.position.y*material.scale_modifier.x*material.height_modifier.x;    
+    let rotated_normal_xz = rot_mat*vertex.normal.xz;
+    out.world_normal = normalize(vec3<f32>(rotated_normal_xz.x, vertex.normal.y, rotated_normal_xz.y));
     out.world_position= vec4<f32>(rotated_xy.x+base_position_world.x, local_y+base_position_world.y, rotated_xy.y+ base_position_world.z, 1.0);
 
     //Growth height adjustments
     let growth_uv = (base_position_world.xz-grid_config.grid_center_xy+grid_config.grid_half_extents)/(grid_config.grid_half_extents*2.0);
     out.uv = growth_uv; // out.uv = vertex.uv;
-    let growth = textureSampleLevel(growth_textures,growth_sampler, growth_uv,material.growth_texture_id.x, 0.0).x;
+    let growth = growth_at(growth_uv, material.growth_texture_id.x);
     out.world_position.y = out.world_position.y*growth;
 
-    // TODO: This breaks and leaves black static grass
-    // "Hide" grass under map (This can be done better, probably by sampling 5x times and adjusting nr_instances based on texture sum over chunk)
-    // if growth<0.5 && growth/0.5<rand(vec2<f32>(f32(vertex.instance_index), 18.956724*cos(f32(vertex.instance_index)*2.9515))){
-    //     out.world_position.z = out.world_position.z+(-10.0);
-    //     out.clip_position = mesh_position_world_to_clip(out.world_position);
-    //     return out;
-    // } 
+    //Distance fade, every blade shrinks to zero at its own point in the fade band so the chunk thins out gradually.
+    //Shadow passes run this with the fade of their camera, the shadows thin out with the blades
+    let fade_offset = hash_to_unit(hash);
+    out.world_position.y = out.world_position.y*smoothstep(fade_offset, fade_offset+0.25, material.fade.x*1.25);
+
+    //Density, blades whose threshold is above the growth are collapsed into degenerate triangles and skip the rest.
+    //nr_instances is already scaled by the chunk's max growth (ChunkGrass::with_density) so the threshold is relative to it.
+    //The growth comes from growth_at, so ChunkGrass::blade thins out the same blades on the cpu
+    let density_threshold = blade_density_threshold(hash);
+    if (growth <= density_threshold*material.max_growth.x) {
+        out.clip_position = vec4<f32>(0.0, 0.0, 0.0, 1.0);
+        return out;
+    }
 
     //Straw distortion
     var scale = 0.1*vertex.position.y*material.height_modifier.x*material.scale_modifier.x;
@@ -231,14 +269,87 @@ fn vertex(vertex: Vertex,
         out.color =  base_color;
     }
 
+    //Everything above is relative to the ground, the blade moves up with the terrain at its root
+    out.world_position.y = out.world_position.y + terrain_height(base_position_world.xz);
+
     out.clip_position = mesh_position_world_to_clip(out.world_position);
 
     return out;
 }
 
 
+// Directional lights and the point and spot lights of the fragment's cluster. Point and spot lights only shadow
+// the blades within their range and cone, blades they don't light aren't darkened
+fn grass_shadow(frag_coord: vec2<f32>, world_position: vec4<f32>, world_normal: vec3<f32>) -> f32 {
+    var shadow: f32 = 1.0;
+    if ((mesh.flags & MESH_FLAGS_SHADOW_RECEIVER_BIT) == 0u) {
+        return shadow;
+    }
+    let n_directional_lights = lights.n_directional_lights;
+    for (var i: u32 = 0u; i < n_directional_lights; i = i + 1u) {
+        if ((lights.directional_lights[i].flags & DIRECTIONAL_LIGHT_FLAGS_SHADOWS_ENABLED_BIT) != 0u) {
+            shadow = min(shadow, fetch_directional_shadow(i, world_position, world_normal));
+        }
+    }
+
+    //Same cluster lookup as bevy_pbr::pbr_functions
+    let is_orthographic = view.projection[3].w == 1.0;
+    let view_z = dot(vec4<f32>(
+        view.inverse_view[0].z,
+        view.inverse_view[1].z,
+        view.inverse_view[2].z,
+        view.inverse_view[3].z
+    ), world_position);
+    let cluster_index = fragment_cluster_index(frag_coord, view_z, is_orthographic);
+    let offset_and_counts = unpack_offset_and_counts(cluster_index);
+    let point_lights_end = offset_and_counts[0] + offset_and_counts[1];
+    let spot_lights_end = point_lights_end + offset_and_counts[2];
+    for (var i: u32 = offset_and_counts[0]; i < spot_lights_end; i = i + 1u) {
+        let light_id = get_light_id(i);
+        let light = point_lights.data[light_id];
+        if ((light.flags & POINT_LIGHT_FLAGS_SHADOWS_ENABLED_BIT) == 0u) {
+            continue;
+        }
+        //Range falloff of bevy's point lights, 0 past the range
+        let light_to_frag = light.position_radius.xyz - world_position.xyz;
+        let distance_factor = dot(light_to_frag, light_to_frag) * light.color_inverse_square_range.w;
+        var reach = saturate(1.0 - distance_factor * distance_factor);
+        var light_shadow: f32;
+        if (i < point_lights_end) {
+            light_shadow = fetch_point_shadow(light_id, world_position, world_normal);
+        } else {
+            //Cone falloff, same as bevy_pbr::lighting::spot_light
+            var spot_dir = vec3<f32>(light.light_custom_data.x, 0.0, light.light_custom_data.y);
+            spot_dir.y = sqrt(max(0.0, 1.0 - spot_dir.x * spot_dir.x - spot_dir.z * spot_dir.z));
+            if ((light.flags & POINT_LIGHT_FLAGS_SPOT_LIGHT_Y_NEGATIVE) != 0u) {
+                spot_dir.y = -spot_dir.y;
+            }
+            let cd = dot(-spot_dir, normalize(light_to_frag));
+            reach = reach * saturate(cd * light.light_custom_data.z + light.light_custom_data.w);
+            light_shadow = fetch_spot_shadow(light_id, world_position, world_normal);
+        }
+        shadow = min(shadow, mix(1.0, light_shadow, reach));
+    }
+    return shadow;
+}
+
 @fragment
 fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
     // return  vec4<f32>(0.5,0.5,0.5,1.0);
-    return in.color;
-}
\ No newline at end of file
+#ifdef ALPHA_MASK
+    if (in.color.a < material.alpha_cutoff.x) {
+        discard;
+    }
+#endif
+    //Grass is unlit so shadowed blades are darkened instead
+    let shadow = grass_shadow(in.clip_position.xy, in.world_position, in.world_normal);
+    return vec4<f32>(in.color.rgb * mix(0.5, 1.0, shadow), in.color.a);
+}
+
+// Shadow pass of alpha mask grass, only cuts out the blades. Shadow views only bind the view uniform in group 0
+@fragment
+fn fragment_depth(in: VertexOutput) {
+    if (in.color.a < material.alpha_cutoff.x) {
+        discard;
+    }
+}
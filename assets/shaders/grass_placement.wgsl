@@ -0,0 +1,49 @@
+#define_import_path bevy_efficient_forest_rendering::grass_placement
+
+#import bevy_efficient_forest_rendering::rng
+
+// Placement and growth of the grass blades. Mirrored by ChunkGrass::blade_placement, blade_density_threshold
+// and sample_growth in chunk_grass.rs, tests/grass_placement.rs runs these against them
+
+@group(3) @binding(0)
+var growth_textures: texture_2d_array<f32>;
+
+// Per blade hash, seeded per chunk so chunks don't repeat
+fn blade_hash(seed: u32, instance_index: u32) -> u32 {
+    return pcg_hash(seed ^ pcg_hash(instance_index));
+}
+
+// Chunk local x and z of the blade and its yaw
+fn blade_placement(hash: u32, chunk_half_extents: vec2<f32>) -> vec3<f32> {
+    let x_hash = pcg_hash(hash);
+    let z_hash = pcg_hash(x_hash);
+    let x = hash_to_unit(x_hash)*chunk_half_extents.x*2.0;
+    let z = hash_to_unit(z_hash)*chunk_half_extents.y*2.0;
+    let yaw = hash_to_unit(pcg_hash(z_hash))*6.2831855;
+    return vec3<f32>(x, z, yaw);
+}
+
+// Growth relative to the chunk's max growth the blade needs to be drawn
+fn blade_density_threshold(hash: u32) -> f32 {
+    return hash_to_unit(pcg_hash(hash ^ 0x2545f491u));
+}
+
+// Bilinear growth with clamped edges, same as sample_growth in chunk_grass.rs. The sampler only filters
+// with a few bits of precision, blades near the density threshold would differ from the cpu with it
+fn growth_at(uv: vec2<f32>, layer_id: i32) -> f32 {
+    let size = textureDimensions(growth_textures);
+    let layer = clamp(layer_id, 0, textureNumLayers(growth_textures) - 1);
+    let position = uv * vec2<f32>(size) - 0.5;
+    let base = floor(position);
+    let t = position - base;
+    let i0 = clamp(vec2<i32>(base), vec2<i32>(0), size - vec2<i32>(1));
+    let i1 = clamp(vec2<i32>(base) + vec2<i32>(1), vec2<i32>(0), size - vec2<i32>(1));
+
+    let g00 = textureLoad(growth_textures, i0, layer, 0).x;
+    let g10 = textureLoad(growth_textures, vec2<i32>(i1.x, i0.y), layer, 0).x;
+    let g01 = textureLoad(growth_textures, vec2<i32>(i0.x, i1.y), layer, 0).x;
+    let g11 = textureLoad(growth_textures, i1, layer, 0).x;
+    let top = g00 + (g10 - g00) * t.x;
+    let bottom = g01 + (g11 - g01) * t.x;
+    return top + (bottom - top) * t.y;
+}
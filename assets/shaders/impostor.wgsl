@@ -0,0 +1,178 @@
+#import bevy_pbr::mesh_types
+#import bevy_pbr::mesh_view_bindings
+
+@group(1) @binding(0)
+var<uniform> mesh: Mesh;
+
+// NOTE: Bindings must come before functions that use them!
+#import bevy_pbr::mesh_functions
+
+#import bevy_pbr::pbr_types
+#import bevy_pbr::utils
+#import bevy_pbr::clustered_forward
+#import bevy_pbr::lighting
+#import bevy_pbr::shadows
+#import bevy_pbr::pbr_functions
+
+struct InstanceInput {
+    @location(8) pos_scale: vec4<f32>,
+#ifdef INSTANCE_FULL_TRANSFORM
+    @location(9) rotation: vec4<f32>,
+    @location(10) scale: vec4<f32>,
+#endif
+#ifdef INSTANCE_VARIATION
+    @location(11) tint_layer: vec4<f32>,
+#endif
+}
+
+struct Impostor {
+    frames: u32,
+    columns: u32,
+    rows: u32,
+    _padding: u32,
+    half_width: f32,
+    min_y: f32,
+    max_y: f32,
+    _padding_2: f32,
+}
+
+@group(2) @binding(0)
+var<uniform> impostor: Impostor;
+@group(2) @binding(1)
+var atlas_texture: texture_2d<f32>;
+@group(2) @binding(2)
+var atlas_sampler: sampler;
+
+// Chunk uniform of chunk_instancing.wgsl, only the fade of the view is used
+struct PlantChunk{
+    model_transform: mat4x4<f32>,
+    normal_transform: mat4x4<f32>,
+    alpha_cutoff: vec4<f32>,
+    fade: vec4<f32>, //Only x is used, 1 is fully visible
+}
+
+@group(3) @binding(0)
+var<uniform> plant_chunk: PlantChunk;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) world_position: vec4<f32>,
+    @location(1) world_normal: vec3<f32>,
+    @location(2) uv: vec2<f32>,
+    @location(3) tint: vec3<f32>,
+};
+
+#import bevy_efficient_forest_rendering::rng
+
+fn quat_rotate(q: vec4<f32>, v: vec3<f32>) -> vec3<f32> {
+    let t = 2.0 * cross(q.xyz, v);
+    return v + q.w * t + cross(q.xyz, t);
+}
+
+@vertex
+fn vertex(@builtin(vertex_index) vertex_index: u32,
+    instance: InstanceInput,
+) -> VertexOutput {
+    var out: VertexOutput;
+#ifdef INSTANCE_VARIATION
+    out.tint = instance.tint_layer.rgb;
+#else
+    out.tint = vec3<f32>(1.0);
+#endif
+
+#ifdef INSTANCE_FULL_TRANSFORM
+    let rotation = instance.rotation;
+    let scale = instance.scale.xyz * instance.pos_scale.w;
+#else
+    let hash = pcg_hash(bitcast<u32>(instance.pos_scale.x) ^ pcg_hash(bitcast<u32>(instance.pos_scale.z)));
+    let rot_y = hash_to_unit(hash) * 6.2831855;
+    let rand_scale = hash_to_unit(pcg_hash(hash)) * 0.2 + 0.9;
+    let rotation = vec4<f32>(0.0, sin(rot_y * 0.5), 0.0, cos(rot_y * 0.5));
+    let scale = vec3<f32>(instance.pos_scale.w * rand_scale);
+#endif
+
+    //Quad corners, x in [-1, 1] and y in [0, 1]
+    var corners = array<vec2<f32>, 6>(
+        vec2<f32>(-1.0, 0.0),
+        vec2<f32>(1.0, 0.0),
+        vec2<f32>(1.0, 1.0),
+        vec2<f32>(-1.0, 0.0),
+        vec2<f32>(1.0, 1.0),
+        vec2<f32>(-1.0, 1.0),
+    );
+    let corner = corners[vertex_index];
+
+    let instance_world = mesh_position_local_to_world(mesh.model, vec4<f32>(instance.pos_scale.xyz, 1.0));
+    let to_camera = view.world_position.xyz - instance_world.xyz;
+    let facing = normalize(vec3<f32>(to_camera.x, 0.0, to_camera.z) + vec3<f32>(0.0001, 0.0, 0.0));
+    let right = cross(vec3<f32>(0.0, 1.0, 0.0), facing);
+
+    //Pick the baked view closest to the camera direction in the instance's own space, frame i was baked from yaw i/frames*TAU
+    let inverse_rotation = vec4<f32>(-rotation.xyz, rotation.w);
+    let local_dir = quat_rotate(inverse_rotation, to_camera);
+    let frames = f32(impostor.frames);
+    let frame = u32(round(atan2(local_dir.x, local_dir.z) / 6.2831855 * frames) + frames) % impostor.frames;
+    let tile = vec2<f32>(f32(frame % impostor.columns), f32(frame / impostor.columns));
+    let tile_uv = vec2<f32>(corner.x * 0.5 + 0.5, 1.0 - corner.y);
+    out.uv = (tile + tile_uv) / vec2<f32>(f32(impostor.columns), f32(impostor.rows));
+
+    let width = impostor.half_width * max(scale.x, scale.z);
+    let height = mix(impostor.min_y, impostor.max_y, corner.y) * scale.y;
+    let world_position = instance_world.xyz + right * corner.x * width + vec3<f32>(0.0, height, 0.0);
+
+    out.world_position = vec4<f32>(world_position, 1.0);
+    //Billboards have no real normal, leaning it up keeps the lighting close to the meshes
+    out.world_normal = normalize(facing + vec3<f32>(0.0, 1.0, 0.0));
+    out.clip_position = mesh_position_world_to_clip(out.world_position);
+    return out;
+}
+
+struct FragmentInput {
+    @builtin(position) frag_coord: vec4<f32>,
+    @location(0) world_position: vec4<f32>,
+    @location(1) world_normal: vec3<f32>,
+    @location(2) uv: vec2<f32>,
+    @location(3) tint: vec3<f32>,
+};
+
+// Same as chunk_instancing.wgsl, impostors dither out at the culling distance like the meshes
+fn dither_fade(frag_coord: vec2<f32>) {
+    if (plant_chunk.fade.x >= 1.0) {
+        return;
+    }
+    var bayer = array<f32, 16>(
+        0.0, 8.0, 2.0, 10.0,
+        12.0, 4.0, 14.0, 6.0,
+        3.0, 11.0, 1.0, 9.0,
+        15.0, 7.0, 13.0, 5.0,
+    );
+    let pixel = vec2<u32>(frag_coord) % vec2<u32>(4u);
+    let threshold = (bayer[pixel.y * 4u + pixel.x] + 0.5) / 16.0;
+    if (plant_chunk.fade.x < threshold) {
+        discard;
+    }
+}
+
+@fragment
+fn fragment(in: FragmentInput) -> @location(0) vec4<f32> {
+    dither_fade(in.frag_coord.xy);
+    let color = textureSample(atlas_texture, atlas_sampler, in.uv);
+    if (color.a < 0.5) {
+        discard;
+    }
+
+    var pbr_input: PbrInput = pbr_input_new();
+    pbr_input.material.base_color = vec4<f32>(color.rgb * in.tint, 1.0);
+    pbr_input.material.reflectance = 0.0;
+
+    pbr_input.frag_coord = in.frag_coord;
+    pbr_input.world_position = in.world_position;
+    pbr_input.world_normal = in.world_normal;
+
+    pbr_input.is_orthographic = view.projection[3].w == 1.0;
+
+    pbr_input.N = normalize(in.world_normal);
+    pbr_input.V = calculate_view(in.world_position, pbr_input.is_orthographic);
+
+    return tone_mapping(pbr(pbr_input));
+}
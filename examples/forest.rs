@@ -7,18 +7,20 @@ use bevy::{
     math::prelude::*,
     math::Vec3A,
     prelude::*,
-    render::{
-        mesh::{Indices, VertexAttributeValues},
-        primitives::Aabb,
-        render_resource::{AddressMode, FilterMode, PrimitiveTopology, SamplerDescriptor},
-        texture::ImageSampler,
-    },
+    render::{mesh::Indices, primitives::Aabb, render_resource::PrimitiveTopology},
     window::PresentMode,
 };
 use bevy_asset_loader::prelude::*;
 use bevy_efficient_forest_rendering::{
-    chunk_grass::{ChunkGrass, ChunkGrassBundle, GridConfig},
+    chunk_grass::{
+        ChunkGrass, ChunkGrassBundle, GridConfig, GrowthLayer, GrowthPaint, GrowthTextures,
+        GrowthTexturesConfig,
+    },
     chunk_instancing::{ChunkInstancing, ChunkInstancingBundle},
+    navigation::NavigationFootprint,
+    rng,
+    scatter::ScatterRule,
+    terrain::{set_repeat_sampler, ForestTerrain, TerrainChunkBundle, TerrainMeshSettings},
     DistanceCulling, ForestRenderingPlugin,
 };
 use bevy_inspector_egui::WorldInspectorPlugin;
@@ -121,7 +123,10 @@ pub fn get_grass_straw_mesh() -> Mesh {
 
 const NR_SIDE_CHUNKS: u32 = 30;
 const INSTANCE_DENSITY: i32 = 1; //4
+const GRASS_DENSITY: f32 = 50.0; //Blades per square metre at full growth
 const CHUNK_SIZE: f32 = 30.;
+const WORLD_SEED: u64 = 1; //Same seed, same forest
+const GRASS_LAYER: u32 = u32::MAX; //chunk_seed layer of the grass, the instanced layers use their index
 
 fn main() {
     let mut app = App::new();
@@ -143,6 +148,13 @@ fn main() {
         })
         .add_plugins(DefaultPlugins)
         .add_plugin(WorldInspectorPlugin::default())
+        .insert_resource(GrowthTexturesConfig {
+            resolution: UVec2::splat(100),
+            layers: vec![
+                GrowthLayer::perlin("forest_floor", 1, 0.1),
+                GrowthLayer::perlin("meadow", 2, 0.1),
+            ],
+        })
         .add_plugin(ForestRenderingPlugin)
         .init_resource::<GrassConfig>()
         .insert_resource(GridConfig {
@@ -152,11 +164,19 @@ fn main() {
                 NR_SIDE_CHUNKS as f32 * CHUNK_SIZE / 2.0,
             ],
         })
+        .insert_resource(ForestTerrain::from_noise(
+            WORLD_SEED as u32,
+            UVec2::splat(257),
+            Vec2::splat(-(NR_SIDE_CHUNKS as f32) * CHUNK_SIZE / 2.0),
+            Vec2::splat(NR_SIDE_CHUNKS as f32 * CHUNK_SIZE),
+            15.0,
+            0.004,
+        ))
         // shared helper plugin for examples
         .add_plugin(HelperPlugin)
         // Setup our scene
         .add_startup_system(spawn_camera_and_light) // add camera at startup
-        .add_enter_system(GameState::InGame, spawn_ground)
+        .add_system(mow_grass)
         .add_enter_system(GameState::InGame, spawn_foliage);
 
     #[cfg(target_family = "wasm")]
@@ -165,6 +185,30 @@ fn main() {
     app.run();
 }
 
+//Hold M to mow the grass around the camera
+fn mow_grass(
+    keys: Res<Input<KeyCode>>,
+    cameras: Query<&GlobalTransform, With<Camera>>,
+    grid_config: Res<GridConfig>,
+    mut growth_textures: ResMut<GrowthTextures>,
+) {
+    if !keys.pressed(KeyCode::M) {
+        return;
+    }
+    let layer = growth_textures.layer("meadow").unwrap();
+    for transform in cameras.iter() {
+        let position = transform.translation();
+        growth_textures.paint_circle(
+            layer,
+            &grid_config,
+            Vec2::new(position.x, position.z),
+            3.0,
+            GrowthPaint::Set(0.0),
+        )
+        .unwrap();
+    }
+}
+
 fn spawn_camera_and_light(mut commands: Commands) {
     // Camera
     commands
@@ -185,7 +229,7 @@ fn spawn_camera_and_light(mut commands: Commands) {
             },
             directional_light: DirectionalLight {
                 illuminance: 30000.0,
-                shadows_enabled: false,
+                shadows_enabled: true,
                 ..default()
             },
             ..default()
@@ -193,56 +237,6 @@ fn spawn_camera_and_light(mut commands: Commands) {
         .insert(Name::new("Directional Light"));
 }
 
-fn spawn_ground(
-    mut commands: Commands,
-    foliage_assets: Res<FoliageAssets>,
-    mut materials: ResMut<Assets<StandardMaterial>>,
-    mut images: ResMut<Assets<Image>>,
-    mut meshes: ResMut<Assets<Mesh>>,
-    grid_config: Res<GridConfig>,
-) {
-    // Setup ground texture to repeat
-    // TODO: should be cleaner way to do this
-    let grass_image = images.get_mut(&foliage_assets.ground_texture).unwrap();
-    grass_image.sampler_descriptor = ImageSampler::Descriptor(SamplerDescriptor {
-        address_mode_u: AddressMode::Repeat,
-        address_mode_v: AddressMode::Repeat,
-        mag_filter: FilterMode::Linear,
-        min_filter: FilterMode::Linear,
-        mipmap_filter: FilterMode::Linear,
-        ..default()
-    });
-
-    // setup ground mesh
-    let mut ground_mesh = Mesh::from(shape::Plane {
-        size: grid_config.get_size().x, // TODO: should it always be square
-    });
-    if let Some(VertexAttributeValues::Float32x2(uvs)) =
-        ground_mesh.attribute_mut(Mesh::ATTRIBUTE_UV_0)
-    {
-        for uv in uvs {
-            uv[0] *= grid_config.grid_half_extents[0] / 4.0; //How dense texture should be sampled
-            uv[1] *= grid_config.grid_half_extents[1] / 4.0;
-        }
-    }
-
-    // Ground
-    commands
-        .spawn_bundle(PbrBundle {
-            mesh: meshes.add(ground_mesh),
-            material: materials.add(StandardMaterial {
-                base_color: Color::rgb(0.34, 0.53, 0.255), //Adjust ground color
-                base_color_texture: Some(foliage_assets.ground_texture.clone()),
-                perceptual_roughness: 1.0,
-                reflectance: 0.0,
-                metallic: 0.0,
-                ..default()
-            }),
-            ..default()
-        })
-        .insert(Name::new("Ground"));
-}
-
 // Only here for ease of use to make refactoring easier while developing
 struct Layer {
     mesh: Handle<Mesh>,
@@ -250,6 +244,9 @@ struct Layer {
     transform: Transform,
     instance_count: u32,
     culling_distance: f32,
+    scatter_rule: ScatterRule,
+    align_to_ground: bool,
+    footprint: Option<NavigationFootprint>,
     name: &'static str,
 }
 
@@ -257,20 +254,41 @@ fn spawn_foliage(
     mut commands: Commands,
     foliage_assets: Res<FoliageAssets>,
     grass_config: Res<GrassConfig>,
+    terrain: Res<ForestTerrain>,
+    grid_config: Res<GridConfig>,
+    growth_textures: Res<GrowthTextures>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut images: ResMut<Assets<Image>>,
+    mut meshes: ResMut<Assets<Mesh>>,
 ) {
     let nr_instances = (CHUNK_SIZE * CHUNK_SIZE * INSTANCE_DENSITY as f32) as u32;
 
+    set_repeat_sampler(images.get_mut(&foliage_assets.ground_texture).unwrap());
+    let growth_image = growth_textures.image();
+    let ground_material = materials.add(StandardMaterial {
+        base_color: Color::rgb(0.34, 0.53, 0.255), //Adjust ground color
+        base_color_texture: Some(foliage_assets.ground_texture.clone()),
+        perceptual_roughness: 1.0,
+        reflectance: 0.0,
+        metallic: 0.0,
+        ..default()
+    });
+    let terrain_settings = TerrainMeshSettings::default();
+
     let mut tot_instances = 0;
     let mut tot_instances_grass = 0;
 
     let offset = CHUNK_SIZE * NR_SIDE_CHUNKS as f32 / 2.0;
     for chunk_x in 0..NR_SIDE_CHUNKS {
         for chunk_y in 0..NR_SIDE_CHUNKS {
-            let chunk_half_height = 2.0;
             let chunk_x_pos = chunk_x as f32 * CHUNK_SIZE - offset;
             let chunk_y_pos = chunk_y as f32 * CHUNK_SIZE - offset;
+            let chunk_origin = Vec2::new(chunk_x_pos, chunk_y_pos);
+            let (min_height, max_height) =
+                terrain.height_range(chunk_origin, chunk_origin + CHUNK_SIZE);
+            let chunk_half_height = (max_height - min_height) / 2.0 + 2.0;
             let chunk_aabb = Aabb {
-                center: Vec3A::Y * chunk_half_height,
+                center: Vec3A::Y * ((min_height + max_height) / 2.0 + 2.0),
                 half_extents: Vec3A::new(CHUNK_SIZE, chunk_half_height, CHUNK_SIZE), //Why do I need full chunk_size here?!, good question
             };
 
@@ -288,7 +306,7 @@ fn spawn_foliage(
                     Name::new(format!("Chunk {chunk_x}x{chunk_y}")),
                 ))
                 .with_children(|parent| {
-                    for layer in vec![
+                    for (layer_index, layer) in vec![
                         Layer {
                             name: "Mushroom",
                             mesh: foliage_assets.mushroom_mesh.clone(),
@@ -299,6 +317,9 @@ fn spawn_foliage(
                             },
                             instance_count: nr_instances / 5,
                             culling_distance: 100.0,
+                            scatter_rule: ScatterRule::Constant(1.0),
+                            align_to_ground: true,
+                            footprint: None,
                         },
                         Layer {
                             name: "Tree",
@@ -311,6 +332,13 @@ fn spawn_foliage(
                             },
                             instance_count: nr_instances / 15,
                             culling_distance: 200.0,
+                            //Trees only on gentle slopes below 300 m, thinned by noise
+                            scatter_rule: ScatterRule::slope(0.0, 20.0)
+                                .with_falloff(5.0)
+                                .multiply(ScatterRule::height(f32::MIN, 300.0))
+                                .multiply(ScatterRule::noise(WORLD_SEED as u32, 0.02)),
+                            align_to_ground: false,
+                            footprint: Some(NavigationFootprint::blocking(0.6)),
                         },
                         Layer {
                             name: "Bush",
@@ -323,6 +351,9 @@ fn spawn_foliage(
                             },
                             instance_count: nr_instances / 6,
                             culling_distance: 200.0,
+                            scatter_rule: ScatterRule::Constant(1.0),
+                            align_to_ground: false,
+                            footprint: Some(NavigationFootprint::cost(1.0, 2.0)),
                         },
                         Layer {
                             name: "Rock",
@@ -335,54 +366,96 @@ fn spawn_foliage(
                             },
                             instance_count: nr_instances / 10,
                             culling_distance: 200.0,
+                            scatter_rule: ScatterRule::Constant(1.0),
+                            align_to_ground: true,
+                            footprint: Some(NavigationFootprint::blocking(1.0)),
                         },
-                    ] {
-                        parent
-                            .spawn_bundle(ChunkInstancingBundle {
-                                mesh: layer.mesh.clone(),
-                                chunk_instancing: ChunkInstancing::new(
-                                    layer.instance_count,
-                                    layer.image.clone(),
-                                    layer.transform.clone(),
-                                    CHUNK_SIZE,
-                                ),
-                                distance_culling: DistanceCulling {
-                                    distance: layer.culling_distance,
-                                },
-                                aabb: chunk_aabb.clone(), // TODO: would like to avoid this all together and use parent AABB f
+                    ]
+                    .into_iter()
+                    .enumerate()
+                    {
+                        let chunk_instancing = ChunkInstancing::new_seeded(
+                            layer.instance_count,
+                            layer.image.clone(),
+                            layer.transform.clone(),
+                            CHUNK_SIZE,
+                            rng::chunk_seed(
+                                WORLD_SEED,
+                                [chunk_x as i32, chunk_y as i32],
+                                layer_index as u32,
+                            ),
+                        )
+                        .with_scatter_rule(&layer.scatter_rule, chunk_origin, &*terrain)
+                        .with_terrain(&*terrain, chunk_origin, layer.align_to_ground);
+                        tot_instances += chunk_instancing.instances().len();
+
+                        let mut layer_entity = parent.spawn_bundle(ChunkInstancingBundle {
+                            mesh: layer.mesh.clone(),
+                            chunk_instancing,
+                            distance_culling: DistanceCulling {
+                                distance: layer.culling_distance,
                                 ..default()
-                            })
-                            .insert(Name::new(layer.name));
-                        tot_instances += layer.instance_count;
+                            },
+                            aabb: chunk_aabb.clone(), // TODO: would like to avoid this all together and use parent AABB f
+                            ..default()
+                        });
+                        layer_entity.insert(Name::new(layer.name));
+                        if let Some(footprint) = layer.footprint {
+                            layer_entity.insert(footprint);
+                        }
                     }
 
+                    // Ground
+                    parent
+                        .spawn_bundle(TerrainChunkBundle::new(
+                            terrain.terrain_chunk(
+                                chunk_origin,
+                                CHUNK_SIZE,
+                                &terrain_settings,
+                                &mut meshes,
+                            ),
+                            ground_material.clone(),
+                        ))
+                        .insert(Name::new("Ground"));
+
                     // Grass
+                    let chunk_grass = ChunkGrass {
+                        time: 0.0,
+                        healthy_tip_color: grass_config.healthy_tip_color,
+                        healthy_middle_color: grass_config.healthy_middle_color,
+                        healthy_base_color: grass_config.healthy_base_color,
+
+                        unhealthy_tip_color: grass_config.unhealthy_tip_color,
+                        unhealthy_middle_color: grass_config.unhealthy_middle_color,
+                        unhealthy_base_color: grass_config.unhealthy_base_color,
+
+                        chunk_xy: [chunk_x_pos, chunk_y_pos],
+                        chunk_half_extents: [CHUNK_SIZE / 2.0, CHUNK_SIZE / 2.0],
+                        seed: rng::chunk_seed(
+                            WORLD_SEED,
+                            [chunk_x as i32, chunk_y as i32],
+                            GRASS_LAYER,
+                        ) as u32,
+                        scale: 1.6,
+                        height_modifier: 0.6,
+                        alpha_mode: AlphaMode::Opaque,
+                        ..default()
+                    }
+                    .with_growth_layer("meadow", &growth_textures)
+                    .with_density(GRASS_DENSITY, &grid_config, growth_image);
+                    tot_instances_grass += chunk_grass.nr_instances;
                     parent
                         .spawn_bundle(ChunkGrassBundle {
                             mesh: grass_config.mesh.clone(),
                             aabb: chunk_aabb.clone(),
-                            chunk_grass: ChunkGrass {
-                                time: 0.0,
-                                healthy_tip_color: grass_config.healthy_tip_color,
-                                healthy_middle_color: grass_config.healthy_middle_color,
-                                healthy_base_color: grass_config.healthy_base_color,
-
-                                unhealthy_tip_color: grass_config.unhealthy_tip_color,
-                                unhealthy_middle_color: grass_config.unhealthy_middle_color,
-                                unhealthy_base_color: grass_config.unhealthy_base_color,
-
-                                chunk_xy: [chunk_x_pos, chunk_y_pos],
-                                chunk_half_extents: [CHUNK_SIZE / 2.0, CHUNK_SIZE / 2.0],
-                                nr_instances: nr_instances * 50,
-                                growth_texture_id: 1,
-                                scale: 1.6,
-                                height_modifier: 0.6,
+                            chunk_grass,
+                            distance_culling: DistanceCulling {
+                                distance: 300.0,
+                                fade_band: 40.0,
                             },
-                            distance_culling: DistanceCulling { distance: 300.0 },
                             ..default()
                         })
                         .insert(Name::new(format!("Grass")));
-                    tot_instances_grass += nr_instances * 50;
                 });
         }
     }
@@ -1,8 +1,11 @@
 use bevy::{
-    core_pipeline::core_3d::Transparent3d,
+    core_pipeline::core_3d::{AlphaMask3d, Opaque3d, Transparent3d},
     ecs::system::{lifetimeless::*, SystemParamItem, SystemState},
     math::prelude::*,
-    pbr::{MeshPipeline, MeshPipelineKey, MeshUniform, SetMeshBindGroup, SetMeshViewBindGroup},
+    pbr::{
+        LightEntity, MeshPipeline, MeshPipelineKey, MeshUniform, SetMeshBindGroup,
+        SetMeshViewBindGroup, SetShadowViewBindGroup, Shadow, ShadowPipeline, ViewLightEntities,
+    },
     prelude::*,
     reflect::TypeUuid,
     render::{
@@ -10,15 +13,15 @@ use bevy::{
         extract_resource::{ExtractResource, ExtractResourcePlugin},
         mesh::{GpuBufferInfo, MeshVertexBufferLayout},
         primitives::Aabb,
-        render_asset::RenderAssets,
+        render_asset::{PrepareAssetLabel, RenderAssets},
         render_phase::{
             AddRenderCommand, DrawFunctions, EntityRenderCommand, RenderCommandResult, RenderPhase,
             SetItemPipeline, TrackedRenderPass,
         },
         render_resource::*,
-        renderer::RenderDevice,
-        texture::ImageSampler,
+        renderer::{RenderDevice, RenderQueue},
         view::{ExtractedView, Msaa},
+        Extract,
     },
     render::{
         extract_component::ExtractComponentPlugin,
@@ -26,33 +29,55 @@ use bevy::{
         render_resource::{PrimitiveTopology, ShaderType, SpecializedMeshPipelines},
         RenderApp, RenderStage,
     },
+    utils::HashSet,
 };
 use bytemuck::{Pod, Zeroable};
+use std::num::NonZeroU32;
 
 use noise::{NoiseFn, Perlin, Seedable};
 
-use super::{DistanceCulling};
+use super::{
+    rng::{hash_to_unit, pcg_hash},
+    scatter::Heightfield,
+    shadow::{
+        extract_shadow_casters, make_depth_only, LightVisibleEntities, ShadowCaster,
+        ShadowViewOwners,
+    },
+    terrain::TerrainTexture,
+    alpha_cutoff, AlphaModeKey, ChunkInRange, DistanceCulling,
+};
 
 pub struct ChunkGrassPlugin;
 
 impl Plugin for ChunkGrassPlugin {
     fn build(&self, app: &mut App) {
         app.add_plugin(ExtractComponentPlugin::<ChunkGrass>::extract_visible())
-            .add_plugin(ExtractResourcePlugin::<GrowthTextures>::default())
             .add_plugin(ExtractResourcePlugin::<GridConfig>::default())
+            .init_resource::<GrowthTexturesConfig>()
             .init_resource::<GrowthTextures>()
             .add_system(update_time_for_custom_material)
-            .add_system(grass_chunk_distance_culling);
+            .add_system(validate_growth_texture_ids);
 
         app.sub_app_mut(RenderApp)
+            .add_render_command::<Opaque3d, DrawCustom>()
+            .add_render_command::<AlphaMask3d, DrawCustom>()
             .add_render_command::<Transparent3d, DrawCustom>()
+            .add_render_command::<Shadow, DrawCustomShadow>()
             .init_resource::<CustomPipeline>()
             .init_resource::<SpecializedMeshPipelines<CustomPipeline>>()
             .init_resource::<GridConfigBindGroup>()
             .init_resource::<GrowthTexturesBindGroup>()
+            .init_resource::<ExtractedGrowthTextures>()
+            .add_system_to_stage(RenderStage::Extract, extract_growth_textures)
+            .add_system_to_stage(RenderStage::Extract, extract_shadow_casters::<ChunkGrass>)
             .add_system_to_stage(RenderStage::Queue, queue_custom_pipeline)
+            .add_system_to_stage(RenderStage::Queue, queue_custom_shadows)
             .add_system_to_stage(RenderStage::Prepare, prepare_grid_config_bind_group)
             .add_system_to_stage(RenderStage::Prepare, prepare_grass_chunk_bind_group)
+            .add_system_to_stage(
+                RenderStage::Prepare,
+                write_growth_textures.after(PrepareAssetLabel::AssetPrepare),
+            )
             .add_system_to_stage(RenderStage::Prepare, prepare_growth_textures_bind_group);
     }
 }
@@ -75,70 +100,432 @@ pub struct ChunkGrassBundle {
     pub material: Handle<StandardMaterial>,
 }
 
+//Warns once per chunk, ChunkGrass changes every frame because of the time
+fn validate_growth_texture_ids(
+    grass_chunks: Query<(Entity, &ChunkGrass)>,
+    growth_textures: Res<GrowthTextures>,
+    mut invalid: Local<HashSet<Entity>>,
+) {
+    for (entity, chunk_grass) in grass_chunks.iter() {
+        if (0..growth_textures.layer_count()).contains(&chunk_grass.growth_texture_id) {
+            invalid.remove(&entity);
+        } else if invalid.insert(entity) {
+            warn!(
+                "Grass chunk {:?} uses growth texture {} but there are only the layers {:?}",
+                entity, chunk_grass.growth_texture_id, growth_textures.layer_names
+            );
+        }
+    }
+}
+
 fn update_time_for_custom_material(mut grass_chunks: Query<&mut ChunkGrass>, time: Res<Time>) {
     for mut grass_chunk in grass_chunks.iter_mut() {
         grass_chunk.time = time.seconds_since_startup() as f32;
     }
 }
 
-fn grass_chunk_distance_culling(
-    mut query: Query<(&GlobalTransform, &mut Visibility, &DistanceCulling)>,
-    query_camera: Query<&GlobalTransform, With<Camera>>,
-) {
-    if let Ok(camera_global_trans) = query_camera.get_single() {
-        let camera_trans = camera_global_trans.compute_transform();
-        for (global_trans, mut visability, distance_culling) in query.iter_mut() {
-            let transform = global_trans.compute_transform();
-            if camera_trans.translation.distance(transform.translation) > distance_culling.distance {
-                visability.is_visible = false;
-            } else {
-                visability.is_visible = true;
+/// Layers of the growth texture array, insert before adding the plugin to replace the two default noise layers
+#[derive(Clone)]
+pub struct GrowthTexturesConfig {
+    /// Width and height of every layer, image layers are resampled to it
+    pub resolution: UVec2,
+    pub layers: Vec<GrowthLayer>,
+}
+
+impl Default for GrowthTexturesConfig {
+    fn default() -> Self {
+        Self {
+            resolution: UVec2::splat(100),
+            layers: vec![
+                GrowthLayer::perlin("noise_1", 1, 0.1),
+                GrowthLayer::perlin("noise_2", 2, 0.1),
+            ],
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum GrowthError {
+    UnknownLayer(String),
+    LayerOutOfRange(i32),
+    UnsupportedFormat(TextureFormat),
+    NoLayers,
+}
+
+impl std::fmt::Display for GrowthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GrowthError::UnknownLayer(name) => write!(f, "no growth layer named {name:?}"),
+            GrowthError::LayerOutOfRange(layer) => write!(f, "growth layer {layer} is out of range"),
+            GrowthError::UnsupportedFormat(format) => {
+                write!(f, "only R8Unorm and Rgba8Unorm images are supported, got {format:?}")
             }
+            GrowthError::NoLayers => write!(f, "GrowthTexturesConfig needs at least one layer"),
         }
     }
 }
 
+impl std::error::Error for GrowthError {}
+
+#[derive(Clone)]
+pub struct GrowthLayer {
+    /// Used by [`ChunkGrass::with_growth_layer`] instead of the layer index
+    pub name: String,
+    pub source: GrowthSource,
+}
+
+#[derive(Clone)]
+pub enum GrowthSource {
+    /// Perlin noise mapped from -1..1 to 0..1, `pattern_scale` is the noise frequency per texel
+    Perlin { seed: u32, pattern_scale: f64 },
+    /// Growth from the red channel, e.g. painted in an external tool or written by a simulation.
+    /// `R8Unorm` and `Rgba8Unorm(Srgb)` images of any size are supported
+    Image(Image),
+}
+
+impl GrowthLayer {
+    pub fn perlin(name: impl Into<String>, seed: u32, pattern_scale: f64) -> Self {
+        Self {
+            name: name.into(),
+            source: GrowthSource::Perlin {
+                seed,
+                pattern_scale,
+            },
+        }
+    }
+
+    pub fn image(name: impl Into<String>, image: Image) -> Self {
+        Self {
+            name: name.into(),
+            source: GrowthSource::Image(image),
+        }
+    }
+
+    /// Texels of the layer, row major at `resolution`
+    fn data(&self, resolution: UVec2) -> Result<Vec<u8>, GrowthError> {
+        let mut data = Vec::with_capacity((resolution.x * resolution.y) as usize);
+        match &self.source {
+            GrowthSource::Perlin {
+                seed,
+                pattern_scale,
+            } => {
+                let perlin = Perlin::new().set_seed(*seed); // from -1 to 1
+                for y in 0..resolution.y {
+                    for x in 0..resolution.x {
+                        let noise =
+                            perlin.get([x as f64 * pattern_scale, y as f64 * pattern_scale]) as f32;
+                        data.push(((noise + 1.0) / 2.0 * 255.0) as u8);
+                    }
+                }
+            }
+            GrowthSource::Image(image) => {
+                let image = red_channel(image)?;
+                //Texel centers of the layer sampled like the shader samples the layer
+                for y in 0..resolution.y {
+                    for x in 0..resolution.x {
+                        let uv = (Vec2::new(x as f32, y as f32) + 0.5) / resolution.as_vec2();
+                        data.push((sample_growth(&image, 0, uv) * 255.0).round() as u8);
+                    }
+                }
+            }
+        }
+        Ok(data)
+    }
+}
+
+/// First layer of the image as `R8Unorm`
+fn red_channel(image: &Image) -> Result<Image, GrowthError> {
+    let size = image.texture_descriptor.size;
+    let texels = (size.width * size.height) as usize;
+    let data = match image.texture_descriptor.format {
+        TextureFormat::R8Unorm => image.data[..texels].to_vec(),
+        TextureFormat::Rgba8Unorm | TextureFormat::Rgba8UnormSrgb => {
+            image.data[..texels * 4].iter().step_by(4).copied().collect()
+        }
+        format => return Err(GrowthError::UnsupportedFormat(format)),
+    };
+    Ok(Image::new(
+        Extent3d {
+            depth_or_array_layers: 1,
+            ..size
+        },
+        TextureDimension::D2,
+        data,
+        TextureFormat::R8Unorm,
+    ))
+}
+
+/// The growth texture array, its layers can be painted at runtime e.g. for mowing, fire or grazing.
+/// Only the painted rects are uploaded, `growth_texture_array_handle` keeps the initial data so read [`GrowthTextures::image`]
 #[derive(Clone, Component)]
 pub struct GrowthTextures {
     pub growth_texture_array_handle: Handle<Image>,
+    /// Names of the layers from [`GrowthTexturesConfig`], in texture array order
+    pub layer_names: Vec<String>,
+    //Cpu copy with the paints applied
+    image: Image,
+    edit_log: GrowthEditLog,
 }
 
-impl FromWorld for GrowthTextures {
-    fn from_world(world: &mut World) -> Self {
-        let mut images = world.resource_mut::<Assets<Image>>();
+/// How a paint call changes the growth, weighted by the brush and clamped to 0..1
+#[derive(Clone, Copy, Debug)]
+pub enum GrowthPaint {
+    /// Moves the growth towards the value, e.g. 0 for mowing or burning
+    Set(f32),
+    /// Adds to the growth, negative to subtract e.g. for grazing
+    Add(f32),
+}
+
+impl GrowthPaint {
+    fn apply(self, growth: f32, weight: f32) -> f32 {
+        match self {
+            GrowthPaint::Set(value) => growth + (value - growth) * weight,
+            GrowthPaint::Add(amount) => growth + amount * weight,
+        }
+        .clamp(0.0, 1.0)
+    }
+}
+
+/// Texels of one layer changed by a paint call, `max` is exclusive
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GrowthRect {
+    pub layer: u32,
+    pub min: UVec2,
+    pub max: UVec2,
+}
 
-        let size = 100;
-        let scale = 255.0;
+//Painted rects by revision so the render world can upload what changed since its last upload
+#[derive(Clone, Default)]
+struct GrowthEditLog {
+    revision: u64,
+    //Rects of revisions up to this one were dropped from the log
+    first_revision: u64,
+    rects: Vec<(u64, GrowthRect)>,
+}
+
+impl GrowthTextures {
+    /// The texture array with every layer of `config`, added to `images`
+    pub fn new(config: &GrowthTexturesConfig, images: &mut Assets<Image>) -> Result<Self, GrowthError> {
+        if config.layers.is_empty() {
+            return Err(GrowthError::NoLayers);
+        }
         let mut data = Vec::new();
-        let pattern_scale = 0.1;
-        let nr_textures = 2;
-        for i in 0..nr_textures {
-            let perlin = Perlin::new().set_seed(i + 1); // from -1 to 1
-
-            for y in 0..size {
-                for x in 0..size {
-                    let noise =
-                        perlin.get([x as f64 * pattern_scale, y as f64 * pattern_scale]) as f32;
-                    let value = (noise + 1.0) / 2.0 * scale;
-                    data.push(value as u8)
-                }
-            }
+        for layer in config.layers.iter() {
+            data.extend(layer.data(config.resolution)?);
         }
 
-        let image = Image::new(
+        let mut image = Image::new(
             Extent3d {
-                width: size,
-                height: size,
-                depth_or_array_layers: nr_textures,
+                width: config.resolution.x,
+                height: config.resolution.y,
+                depth_or_array_layers: config.layers.len() as u32,
             },
             TextureDimension::D2,
             data,
             TextureFormat::R8Unorm,
         );
+        //A single layer would otherwise get a D2 view, the shader binds an array
+        image.texture_view_descriptor = Some(TextureViewDescriptor {
+            dimension: Some(TextureViewDimension::D2Array),
+            ..default()
+        });
 
-        Self {
-            growth_texture_array_handle: images.add(image),
+        Ok(Self {
+            growth_texture_array_handle: images.add(image.clone()),
+            layer_names: config.layers.iter().map(|layer| layer.name.clone()).collect(),
+            image,
+            edit_log: GrowthEditLog::default(),
+        })
+    }
+
+    /// Texture array index of a named layer, for `ChunkGrass::growth_texture_id`
+    pub fn layer(&self, name: &str) -> Option<i32> {
+        self.layer_names
+            .iter()
+            .position(|layer| layer == name)
+            .map(|index| index as i32)
+    }
+
+    pub fn layer_count(&self) -> i32 {
+        self.layer_names.len() as i32
+    }
+
+    /// Current growth of every layer, pass it to [`ChunkGrass::blades`] and [`sample_growth`]
+    pub fn image(&self) -> &Image {
+        &self.image
+    }
+
+    /// Increases with every paint call
+    pub fn revision(&self) -> u64 {
+        self.edit_log.revision
+    }
+
+    pub fn resolution(&self) -> UVec2 {
+        let size = self.image.texture_descriptor.size;
+        UVec2::new(size.width, size.height)
+    }
+
+    /// Texels outside of the resolution are ignored
+    pub fn set_texel(&mut self, layer: i32, texel: UVec2, value: f32) -> Result<(), GrowthError> {
+        let layer = self.layer_index(layer)?;
+        let resolution = self.resolution();
+        if texel.x >= resolution.x || texel.y >= resolution.y {
+            return Ok(());
+        }
+        self.image.data[self.texel_index(layer, texel)] = (value.clamp(0.0, 1.0) * 255.0).round() as u8;
+        self.log_edit(GrowthRect {
+            layer,
+            min: texel,
+            max: texel + 1,
+        });
+        Ok(())
+    }
+
+    /// Paints the texels with their center within `radius` of the world x z `center`
+    pub fn paint_circle(
+        &mut self,
+        layer: i32,
+        grid_config: &GridConfig,
+        center: Vec2,
+        radius: f32,
+        paint: GrowthPaint,
+    ) -> Result<(), GrowthError> {
+        self.paint_area(layer, grid_config, center - radius, center + radius, paint, |world| {
+            if world.distance(center) <= radius {
+                1.0
+            } else {
+                0.0
+            }
+        })
+    }
+
+    /// Paints weighted by the red channel of `mask`, stretched over `size` world units around `center`
+    pub fn paint_brush(
+        &mut self,
+        layer: i32,
+        grid_config: &GridConfig,
+        center: Vec2,
+        size: Vec2,
+        mask: &Image,
+        paint: GrowthPaint,
+    ) -> Result<(), GrowthError> {
+        let mask = red_channel(mask)?;
+        let min = center - size / 2.0;
+        self.paint_area(layer, grid_config, min, min + size, paint, |world| {
+            sample_growth(&mask, 0, (world - min) / size)
+        })
+    }
+
+    //Paints the texels with their center in the world rect, weight gets the world x z of the texel center
+    fn paint_area(
+        &mut self,
+        layer: i32,
+        grid_config: &GridConfig,
+        min: Vec2,
+        max: Vec2,
+        paint: GrowthPaint,
+        weight: impl Fn(Vec2) -> f32,
+    ) -> Result<(), GrowthError> {
+        let layer = self.layer_index(layer)?;
+        let resolution = self.resolution().as_vec2();
+        let texel_min = (grid_config.growth_uv(min) * resolution - 0.5).ceil().max(Vec2::ZERO);
+        let texel_max = (grid_config.growth_uv(max) * resolution - 0.5).floor() + 1.0;
+        let texel_max = texel_max.min(resolution);
+        if texel_min.x >= texel_max.x || texel_min.y >= texel_max.y {
+            return Ok(());
+        }
+        let rect = GrowthRect {
+            layer,
+            min: texel_min.as_uvec2(),
+            max: texel_max.as_uvec2(),
+        };
+
+        let grid_min = Vec2::from(grid_config.grid_center_xy) - Vec2::from(grid_config.grid_half_extents);
+        for y in rect.min.y..rect.max.y {
+            for x in rect.min.x..rect.max.x {
+                let uv = (Vec2::new(x as f32, y as f32) + 0.5) / resolution;
+                let weight = weight(grid_min + uv * grid_config.get_size());
+                if weight <= 0.0 {
+                    continue;
+                }
+                let index = self.texel_index(layer, UVec2::new(x, y));
+                let growth = paint.apply(self.image.data[index] as f32 / 255.0, weight);
+                self.image.data[index] = (growth * 255.0).round() as u8;
+            }
         }
+        self.log_edit(rect);
+        Ok(())
+    }
+
+    fn layer_index(&self, layer: i32) -> Result<u32, GrowthError> {
+        match (0..self.layer_count()).contains(&layer) {
+            true => Ok(layer as u32),
+            false => Err(GrowthError::LayerOutOfRange(layer)),
+        }
+    }
+
+    fn texel_index(&self, layer: u32, texel: UVec2) -> usize {
+        let resolution = self.resolution();
+        ((layer * resolution.y + texel.y) * resolution.x + texel.x) as usize
+    }
+
+    fn log_edit(&mut self, rect: GrowthRect) {
+        let log = &mut self.edit_log;
+        log.revision += 1;
+        log.rects.push((log.revision, rect));
+        //A render world that is further behind uploads every layer instead
+        if log.rects.len() > 64 {
+            let (revision, _) = log.rects.remove(0);
+            log.first_revision = revision;
+        }
+    }
+
+    //Rects with their texels painted after `revision`, every layer without one or when the log doesn't go back that far
+    fn writes_since(&self, revision: Option<u64>) -> Vec<(GrowthRect, Vec<u8>)> {
+        let resolution = self.resolution();
+        let every_layer = revision.map_or(true, |revision| revision < self.edit_log.first_revision);
+        let rects: Vec<GrowthRect> = if every_layer {
+            (0..self.layer_count() as u32)
+                .map(|layer| GrowthRect {
+                    layer,
+                    min: UVec2::ZERO,
+                    max: resolution,
+                })
+                .collect()
+        } else {
+            self.edit_log
+                .rects
+                .iter()
+                .filter(|(edit_revision, _)| Some(*edit_revision) > revision)
+                .map(|(_, rect)| *rect)
+                .collect()
+        };
+        rects
+            .into_iter()
+            .map(|rect| {
+                let mut data = Vec::with_capacity(((rect.max.x - rect.min.x) * (rect.max.y - rect.min.y)) as usize);
+                for y in rect.min.y..rect.max.y {
+                    let row = self.texel_index(rect.layer, UVec2::new(rect.min.x, y));
+                    data.extend_from_slice(&self.image.data[row..row + (rect.max.x - rect.min.x) as usize]);
+                }
+                (rect, data)
+            })
+            .collect()
+    }
+}
+
+impl FromWorld for GrowthTextures {
+    fn from_world(world: &mut World) -> Self {
+        let config = world
+            .get_resource::<GrowthTexturesConfig>()
+            .cloned()
+            .unwrap_or_default();
+        let mut images = world.resource_mut::<Assets<Image>>();
+        //A broken config shouldn't take the app down, the grass still renders with the default layers
+        Self::new(&config, &mut images).unwrap_or_else(|error| {
+            error!("{}, using the default growth layers", error);
+            Self::new(&GrowthTexturesConfig::default(), &mut images).unwrap()
+        })
     }
 }
 
@@ -155,9 +542,16 @@ impl GridConfig {
             self.grid_half_extents[1] * 2.0,
         )
     }
+
+    /// Growth texture coordinate of a world x z position, same as grass.wgsl
+    pub fn growth_uv(&self, world_xz: Vec2) -> Vec2 {
+        let center = Vec2::from(self.grid_center_xy);
+        let half_extents = Vec2::from(self.grid_half_extents);
+        (world_xz - center + half_extents) / (half_extents * 2.0)
+    }
 }
 
-#[derive(TypeUuid, Debug, Clone, Component, Default)]
+#[derive(TypeUuid, Debug, Clone, Component)]
 #[uuid = "f690fdae-d598-42ab-8225-97e2a3f056e0"] //Dont know why this is needed?
 pub struct ChunkGrass {
     pub time: f32,
@@ -170,9 +564,194 @@ pub struct ChunkGrass {
     pub chunk_xy: [f32; 2],
     pub chunk_half_extents: [f32; 2],
     pub nr_instances: u32,
+    /// Seeds the blade placement so neighbouring chunks don't share a layout, see [`crate::rng::chunk_seed`]
+    pub seed: u32,
+    /// Highest growth over the chunk that `nr_instances` was scaled with, blades are thinned relative to it
+    /// so the visible blades per square metre follow the growth. 1 thins them by the growth itself, see [`ChunkGrass::with_density`]
+    pub max_growth: f32,
+    /// Layer of the growth texture array, see [`ChunkGrass::with_growth_layer`]
     pub growth_texture_id: i32,
     pub height_modifier: f32,
     pub scale: f32,
+    /// Opaque grass goes to the Opaque3d phase, Mask to AlphaMask3d (cut out below the cutoff) and Blend to Transparent3d.
+    /// Blend by default, opaque grass is cheaper when the blade mesh has no transparent parts
+    pub alpha_mode: AlphaMode,
+}
+
+impl Default for ChunkGrass {
+    fn default() -> Self {
+        Self {
+            time: 0.0,
+            healthy_tip_color: Color::default(),
+            healthy_middle_color: Color::default(),
+            healthy_base_color: Color::default(),
+            unhealthy_tip_color: Color::default(),
+            unhealthy_middle_color: Color::default(),
+            unhealthy_base_color: Color::default(),
+            chunk_xy: [0.0; 2],
+            chunk_half_extents: [0.0; 2],
+            nr_instances: 0,
+            seed: 0,
+            max_growth: 1.0,
+            growth_texture_id: 0,
+            height_modifier: 0.0,
+            scale: 0.0,
+            alpha_mode: AlphaMode::Blend,
+        }
+    }
+}
+
+/// One blade as placed by the grass.wgsl vertex shader, before the wind and noise displacements
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GrassBlade {
+    /// World position of the root, on the terrain
+    pub position: Vec3,
+    /// Rotation around y in radians
+    pub yaw: f32,
+    /// Growth texture value at the root, blades at 0 have no height
+    pub growth: f32,
+    /// World height of a blade mesh vertex at y = 1, includes `scale`, `height_modifier` and `growth`
+    pub height: f32,
+    /// False when the shader collapses the blade because of low growth
+    pub visible: bool,
+}
+
+impl ChunkGrass {
+    /// Chunk local x z and yaw of blade `index`. Only integer hashing and exact float math,
+    /// so this is bit identical to `blade_placement` in grass_placement.wgsl on every gpu
+    pub fn blade_placement(&self, index: u32) -> (Vec2, f32) {
+        let blade_hash = self.blade_hash(index);
+        let x_hash = pcg_hash(blade_hash);
+        let z_hash = pcg_hash(x_hash);
+        let x = hash_to_unit(x_hash) * self.chunk_half_extents[0] * 2.0;
+        let z = hash_to_unit(z_hash) * self.chunk_half_extents[1] * 2.0;
+        let yaw = hash_to_unit(pcg_hash(z_hash)) * 6.2831855;
+        (Vec2::new(x, z), yaw)
+    }
+
+    fn blade_hash(&self, index: u32) -> u32 {
+        pcg_hash(self.seed ^ pcg_hash(index))
+    }
+
+    /// Blade `index` is drawn where the growth is above this times `max_growth`
+    pub fn blade_density_threshold(&self, index: u32) -> f32 {
+        hash_to_unit(pcg_hash(self.blade_hash(index) ^ 0x2545f491))
+    }
+
+    /// Blade `index` of the chunk entity at `transform`, mirrors the shader including the growth scaled height.
+    /// The world position goes through a matrix multiply and the growth through a bilinear blend,
+    /// those match the gpu up to float rounding
+    pub fn blade(
+        &self,
+        index: u32,
+        transform: &GlobalTransform,
+        grid_config: &GridConfig,
+        growth_textures: &Image,
+        terrain: &impl Heightfield,
+    ) -> GrassBlade {
+        let (local, yaw) = self.blade_placement(index);
+        let base = transform
+            .compute_matrix()
+            .transform_point3(Vec3::new(local.x, 0.0, local.y));
+        let growth_uv = grid_config.growth_uv(Vec2::new(base.x, base.z));
+        let growth = sample_growth(growth_textures, self.growth_texture_id, growth_uv);
+        GrassBlade {
+            position: Vec3::new(base.x, base.y * growth + terrain.height_at(base.x, base.z), base.z),
+            yaw,
+            growth,
+            height: self.scale * self.height_modifier * growth,
+            visible: growth > self.blade_density_threshold(index) * self.max_growth,
+        }
+    }
+
+    /// Sets `growth_texture_id` to the layer named in [`GrowthTexturesConfig`]
+    pub fn try_with_growth_layer(mut self, name: &str, growth_textures: &GrowthTextures) -> Result<Self, GrowthError> {
+        self.growth_texture_id = growth_textures
+            .layer(name)
+            .ok_or_else(|| GrowthError::UnknownLayer(name.to_string()))?;
+        Ok(self)
+    }
+
+    /// Same as [`ChunkGrass::try_with_growth_layer`], unknown names are logged and keep `growth_texture_id`
+    pub fn with_growth_layer(self, name: &str, growth_textures: &GrowthTextures) -> Self {
+        match growth_textures.layer(name) {
+            Some(layer) => Self {
+                growth_texture_id: layer,
+                ..self
+            },
+            None => {
+                error!(
+                    "No growth layer {:?}, the layers are {:?}",
+                    name, growth_textures.layer_names
+                );
+                self
+            }
+        }
+    }
+
+    /// Highest of 8x8 growth samples over the chunk, `chunk_xy` being its world corner
+    pub fn max_growth(&self, grid_config: &GridConfig, growth_textures: &Image) -> f32 {
+        const SAMPLES: u32 = 8;
+        let corner = Vec2::from(self.chunk_xy);
+        let step = Vec2::from(self.chunk_half_extents) * 2.0 / SAMPLES as f32;
+        let mut max = 0.0f32;
+        for y in 0..SAMPLES {
+            for x in 0..SAMPLES {
+                let world_xz = corner + (Vec2::new(x as f32, y as f32) + 0.5) * step;
+                let uv = grid_config.growth_uv(world_xz);
+                max = max.max(sample_growth(growth_textures, self.growth_texture_id, uv));
+            }
+        }
+        max
+    }
+
+    /// Sets `nr_instances` from blades per square metre at full growth, scaled by the chunk's max growth.
+    /// Bare chunks cost nothing and the blades are thinned relative to the max, so everywhere in the chunk
+    /// `density` times the growth are drawn. Growth above the sampled max draws every blade
+    pub fn with_density(
+        mut self,
+        density: f32,
+        grid_config: &GridConfig,
+        growth_textures: &Image,
+    ) -> Self {
+        self.max_growth = self.max_growth(grid_config, growth_textures);
+        let area = self.chunk_half_extents[0] * self.chunk_half_extents[1] * 4.0;
+        self.nr_instances = (density * area * self.max_growth).round() as u32;
+        self
+    }
+
+    /// Every blade of the chunk, e.g. to count the grown straws in an area
+    pub fn blades<'a>(
+        &'a self,
+        transform: &'a GlobalTransform,
+        grid_config: &'a GridConfig,
+        growth_textures: &'a Image,
+        terrain: &'a impl Heightfield,
+    ) -> impl Iterator<Item = GrassBlade> + 'a {
+        (0..self.nr_instances)
+            .map(move |index| self.blade(index, transform, grid_config, growth_textures, terrain))
+    }
+}
+
+/// Bilinear sample of one layer of the R8Unorm growth texture array with clamped edges,
+/// the same as `growth_at` in grass_placement.wgsl
+pub fn sample_growth(growth_textures: &Image, layer: i32, uv: Vec2) -> f32 {
+    let size = growth_textures.texture_descriptor.size;
+    let (width, height) = (size.width as i32, size.height as i32);
+    let layer = layer.clamp(0, size.depth_or_array_layers as i32 - 1);
+    let texel = |x: i32, y: i32| {
+        let (x, y) = (x.clamp(0, width - 1), y.clamp(0, height - 1));
+        growth_textures.data[((layer * height + y) * width + x) as usize] as f32 / 255.0
+    };
+
+    let position = uv * Vec2::new(width as f32, height as f32) - 0.5;
+    let (x, y) = (position.x.floor(), position.y.floor());
+    let (fx, fy) = (position.x - x, position.y - y);
+    let (x, y) = (x as i32, y as i32);
+    let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+    let top = lerp(texel(x, y), texel(x + 1, y), fx);
+    let bottom = lerp(texel(x, y + 1), texel(x + 1, y + 1), fx);
+    lerp(top, bottom, fy)
 }
 
 // ██████████████████████████████████████████████████████████████████████████████████████████████████████████████████
@@ -199,14 +778,6 @@ impl ExtractComponent for ChunkGrass {
 }
 
 
-impl ExtractResource for GrowthTextures {
-    type Source = GrowthTextures;
-
-    fn extract_resource(res: &Self::Source) -> Self {
-        res.clone()
-    }
-}
-
 impl ExtractResource for GridConfig {
     type Source = GridConfig;
 
@@ -232,6 +803,17 @@ impl ExtractResource for GridConfig {
 #[derive(Component)]
 pub struct ChunkGrassBindGroup {
     pub grass_chunk_bind_group: BindGroup,
+    /// Uniforms of the cameras that see the chunk inside its fade band
+    faded_bind_groups: Vec<(Entity, BindGroup)>,
+}
+
+impl ChunkGrassBindGroup {
+    fn bind_group(&self, view: Entity) -> &BindGroup {
+        self.faded_bind_groups
+            .iter()
+            .find(|(camera, _)| *camera == view)
+            .map_or(&self.grass_chunk_bind_group, |(_, bind_group)| bind_group)
+    }
 }
 
 #[repr(C)]
@@ -254,10 +836,14 @@ pub struct GpuChunkGrass {
     pub growth_texture_id: [i32; 4],
     pub height_modifier: [f32; 4],
     pub scale: [f32; 4],
+    pub alpha_cutoff: [f32; 4],
+    pub fade: [f32; 4],
+    pub seed: [u32; 4],
+    pub max_growth: [f32; 4],
 }
 
 impl ChunkGrass {
-    fn to_raw(&self) -> GpuChunkGrass {
+    fn to_raw(&self, fade: f32) -> GpuChunkGrass {
         GpuChunkGrass {
             time: [self.time, 0.0, 0.0, 0.0],
             // wind_dir: [0.5, -0.5],
@@ -274,50 +860,173 @@ impl ChunkGrass {
             growth_texture_id: [self.growth_texture_id, 0, 0, 0], //To lazy to understand alingment XD
             height_modifier: [self.height_modifier, 0.0, 0.0, 0.0],
             scale: [self.scale, 0.0, 0.0, 0.0],
+            alpha_cutoff: [alpha_cutoff(self.alpha_mode), 0.0, 0.0, 0.0],
+            fade: [fade, 0.0, 0.0, 0.0],
+            seed: [self.seed, 0, 0, 0],
+            max_growth: [self.max_growth, 0.0, 0.0, 0.0],
         }
     }
 }
 
 fn prepare_grass_chunk_bind_group(
     mut commands: Commands,
-    query: Query<(Entity, &ChunkGrass)>,
+    query: Query<(Entity, &ChunkGrass, Option<&ChunkInRange>)>,
     render_device: Res<RenderDevice>,
     custom_pipeline: Res<CustomPipeline>,
 ) {
-    for (entity, grass_chunk) in &query {
-        let grass_chunk_buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
-            label: Some("Grass_chunk_buffer"),
-            contents: bytemuck::cast_slice(&[grass_chunk.to_raw()]),
-            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
-        });
-
-        let grass_chunk_bind_group = render_device.create_bind_group(&BindGroupDescriptor {
-            label: Some("Grass_chunk_bindgroup"),
-            layout: &custom_pipeline.grass_chunk_bind_group_layout,
-            entries: &[BindGroupEntry {
-                binding: 0,
-                resource: grass_chunk_buffer.as_entire_binding(),
-            }],
-        });
+    for (entity, grass_chunk, in_range) in &query {
+        let grass_chunk_bind_group =
+            create_grass_chunk_bind_group(&render_device, &custom_pipeline, grass_chunk.to_raw(1.0));
+        let faded_bind_groups = in_range
+            .map(|in_range| {
+                in_range
+                    .cameras
+                    .iter()
+                    .filter(|(_, fade)| *fade < 1.0)
+                    .map(|(camera, fade)| {
+                        let gpu_chunk = grass_chunk.to_raw(*fade);
+                        (*camera, create_grass_chunk_bind_group(&render_device, &custom_pipeline, gpu_chunk))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
         commands.entity(entity).insert(ChunkGrassBindGroup {
             grass_chunk_bind_group,
+            faded_bind_groups,
         });
     }
 }
 
+fn create_grass_chunk_bind_group(
+    render_device: &RenderDevice,
+    custom_pipeline: &CustomPipeline,
+    gpu_chunk: GpuChunkGrass,
+) -> BindGroup {
+    let grass_chunk_buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
+        label: Some("Grass_chunk_buffer"),
+        contents: bytemuck::cast_slice(&[gpu_chunk]),
+        usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+    });
+
+    render_device.create_bind_group(&BindGroupDescriptor {
+        label: Some("Grass_chunk_bindgroup"),
+        layout: &custom_pipeline.grass_chunk_bind_group_layout,
+        entries: &[BindGroupEntry {
+            binding: 0,
+            resource: grass_chunk_buffer.as_entire_binding(),
+        }],
+    })
+}
+
+/// Render world side of [`GrowthTextures`], the painted rects waiting to be uploaded
+#[derive(Default)]
+pub struct ExtractedGrowthTextures {
+    pub growth_texture_array_handle: Handle<Image>,
+    //None uploads every layer from the cpu copy, e.g. after the gpu image was recreated from the asset
+    uploaded_revision: Option<u64>,
+    revision: u64,
+    writes: Vec<(GrowthRect, Vec<u8>)>,
+}
+
+fn extract_growth_textures(
+    growth_textures: Extract<Res<GrowthTextures>>,
+    mut image_events: Extract<EventReader<AssetEvent<Image>>>,
+    mut extracted: ResMut<ExtractedGrowthTextures>,
+) {
+    //A new texture array starts out with the data from before any paints
+    if extracted.growth_texture_array_handle != growth_textures.growth_texture_array_handle {
+        extracted.growth_texture_array_handle = growth_textures.growth_texture_array_handle.clone();
+        extracted.uploaded_revision = Some(0);
+    }
+    //A modified asset is prepared again from its own data, without the paints
+    for event in image_events.iter() {
+        if let AssetEvent::Modified { handle } = event {
+            if *handle == extracted.growth_texture_array_handle {
+                extracted.uploaded_revision = None;
+            }
+        }
+    }
+    extracted.revision = growth_textures.revision();
+    extracted.writes = match extracted.uploaded_revision {
+        Some(uploaded) if uploaded == extracted.revision => Vec::new(),
+        uploaded => growth_textures.writes_since(uploaded),
+    };
+}
+
+fn write_growth_textures(
+    render_queue: Res<RenderQueue>,
+    images: Res<RenderAssets<Image>>,
+    mut extracted: ResMut<ExtractedGrowthTextures>,
+) {
+    if extracted.writes.is_empty() {
+        return;
+    }
+    //Until the texture exists the writes are extracted again every frame
+    let gpu_image = match images.get(&extracted.growth_texture_array_handle) {
+        Some(gpu_image) => gpu_image,
+        None => return,
+    };
+    for (rect, data) in extracted.writes.drain(..) {
+        let size = rect.max - rect.min;
+        render_queue.write_texture(
+            ImageCopyTexture {
+                texture: &gpu_image.texture,
+                mip_level: 0,
+                origin: Origin3d {
+                    x: rect.min.x,
+                    y: rect.min.y,
+                    z: rect.layer,
+                },
+                aspect: TextureAspect::All,
+            },
+            &data,
+            ImageDataLayout {
+                offset: 0,
+                bytes_per_row: NonZeroU32::new(size.x), //R8Unorm
+                rows_per_image: None,
+            },
+            Extent3d {
+                width: size.x,
+                height: size.y,
+                depth_or_array_layers: 1,
+            },
+        );
+    }
+    extracted.uploaded_revision = Some(extracted.revision);
+}
+
 #[derive(Default)]
 pub struct GrowthTexturesBindGroup {
     pub bind_group: Option<BindGroup>,
+    //Growth and terrain texture views the bind group was created with
+    views: Option<(TextureViewId, TextureViewId)>,
 }
 
 pub fn prepare_growth_textures_bind_group(
     render_device: Res<RenderDevice>,
     custom_pipeline: Res<CustomPipeline>,
     mut growth_textures_bind_group: ResMut<GrowthTexturesBindGroup>,
-    growth_textures: Res<GrowthTextures>,
+    growth_textures: Res<ExtractedGrowthTextures>,
+    terrain_texture: Res<TerrainTexture>,
     images: Res<RenderAssets<Image>>,
 ) {
-    if let Some(image) = images.get(&growth_textures.growth_texture_array_handle) {
+    if let (Some(image), Some(terrain_image)) = (
+        images.get(&growth_textures.growth_texture_array_handle),
+        images.get(&terrain_texture.image),
+    ) {
+        let cache = growth_textures_bind_group.as_mut();
+        let views = (image.texture_view.id(), terrain_image.texture_view.id());
+        if cache.views == Some(views) && !terrain_texture.is_changed() {
+            return;
+        }
+        cache.views = Some(views);
+
+        let terrain_buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
+            label: Some("terrain_buffer"),
+            contents: bytemuck::cast_slice(&[terrain_texture.to_raw()]),
+            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+        });
+
         let growth_texture_bind_group = render_device.create_bind_group(&BindGroupDescriptor {
             layout: &custom_pipeline.growth_bind_group_layout,
             entries: &[
@@ -327,15 +1036,17 @@ pub fn prepare_growth_textures_bind_group(
                 },
                 BindGroupEntry {
                     binding: 1,
-                    resource: BindingResource::Sampler(
-                        &render_device.create_sampler(&ImageSampler::linear_descriptor()),
-                    ),
+                    resource: BindingResource::TextureView(&terrain_image.texture_view),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: terrain_buffer.as_entire_binding(),
                 },
             ],
             label: Some("growth_texture_bind_group"),
         });
 
-        growth_textures_bind_group.as_mut().bind_group = Some(growth_texture_bind_group);
+        cache.bind_group = Some(growth_texture_bind_group);
     }
 }
 
@@ -402,59 +1113,164 @@ fn prepare_grid_config_bind_group(
 
 #[allow(clippy::too_many_arguments)]
 fn queue_custom_pipeline(
+    opaque_3d_draw_functions: Res<DrawFunctions<Opaque3d>>,
+    alpha_mask_3d_draw_functions: Res<DrawFunctions<AlphaMask3d>>,
     transparent_3d_draw_functions: Res<DrawFunctions<Transparent3d>>,
     custom_pipeline: Res<CustomPipeline>,
     msaa: Res<Msaa>,
     mut pipelines: ResMut<SpecializedMeshPipelines<CustomPipeline>>,
     mut pipeline_cache: ResMut<PipelineCache>,
     meshes: Res<RenderAssets<Mesh>>,
-    material_meshes: Query<(Entity, &MeshUniform, &Handle<Mesh>), With<ChunkGrass>>,
-    mut views: Query<(&ExtractedView, &mut RenderPhase<Transparent3d>)>,
+    material_meshes: Query<(
+        Entity,
+        &MeshUniform,
+        &Handle<Mesh>,
+        &ChunkGrass,
+        Option<&ChunkInRange>,
+    )>,
+    mut views: Query<(
+        Entity,
+        &ExtractedView,
+        &mut RenderPhase<Opaque3d>,
+        &mut RenderPhase<AlphaMask3d>,
+        &mut RenderPhase<Transparent3d>,
+    )>,
 ) {
-    let draw_custom = transparent_3d_draw_functions
+    let draw_opaque = opaque_3d_draw_functions.read().get_id::<DrawCustom>().unwrap();
+    let draw_alpha_mask = alpha_mask_3d_draw_functions
+        .read()
+        .get_id::<DrawCustom>()
+        .unwrap();
+    let draw_transparent = transparent_3d_draw_functions
         .read()
         .get_id::<DrawCustom>()
         .unwrap();
 
     let msaa_key = MeshPipelineKey::from_msaa_samples(msaa.samples);
 
-    for (view, mut transparent_phase) in &mut views {
+    for (view_entity, view, mut opaque_phase, mut alpha_mask_phase, mut transparent_phase) in
+        &mut views
+    {
         let rangefinder = view.rangefinder3d();
-        for (entity, mesh_uniform, mesh_handle) in &material_meshes {
+        for (entity, mesh_uniform, mesh_handle, chunk_grass, in_range) in &material_meshes {
+            if !ChunkInRange::is_in_range(in_range, view_entity) {
+                continue;
+            }
             if let Some(mesh) = meshes.get(mesh_handle) {
-                let key =
+                let alpha_mode = AlphaModeKey::from(chunk_grass.alpha_mode);
+                let mut mesh_key =
                     msaa_key | MeshPipelineKey::from_primitive_topology(mesh.primitive_topology);
+                if alpha_mode == AlphaModeKey::Blend {
+                    mesh_key |= MeshPipelineKey::TRANSPARENT_MAIN_PASS;
+                }
+                let key = ChunkGrassPipelineKey {
+                    mesh_key,
+                    alpha_mode,
+                    depth_only: false,
+                };
                 let pipeline = pipelines
                     .specialize(&mut pipeline_cache, &custom_pipeline, key, &mesh.layout)
                     .unwrap();
-                transparent_phase.add(Transparent3d {
-                    entity,
-                    pipeline,
-                    draw_function: draw_custom,
-                    distance: rangefinder.distance(&mesh_uniform.transform),
-                });
+                let distance = rangefinder.distance(&mesh_uniform.transform);
+                match alpha_mode {
+                    AlphaModeKey::Opaque => opaque_phase.add(Opaque3d {
+                        entity,
+                        pipeline,
+                        draw_function: draw_opaque,
+                        distance,
+                    }),
+                    AlphaModeKey::Mask => alpha_mask_phase.add(AlphaMask3d {
+                        entity,
+                        pipeline,
+                        draw_function: draw_alpha_mask,
+                        distance,
+                    }),
+                    AlphaModeKey::Blend => transparent_phase.add(Transparent3d {
+                        entity,
+                        pipeline,
+                        draw_function: draw_transparent,
+                        distance,
+                    }),
+                }
             }
         }
     }
 }
 
-// █████████████████████████████████████████████████████████████████████████████████████████████████████████████████████████
-// █░░░░░░░░░░░░░░█░░░░░░░░░░█░░░░░░░░░░░░░░█░░░░░░░░░░░░░░█░░░░░░█████████░░░░░░░░░░█░░░░░░██████████░░░░░░█░░░░░░░░░░░░░░█
-// █░░▄▀▄▀▄▀▄▀▄▀░░█░░▄▀▄▀▄▀░░█░░▄▀▄▀▄▀▄▀▄▀░░█░░▄▀▄▀▄▀▄▀▄▀░░█░░▄▀░░█████████░░▄▀▄▀▄▀░░█░░▄▀░░░░░░░░░░██░░▄▀░░█░░▄▀▄▀▄▀▄▀▄▀░░█
-// █░░▄▀░░░░░░▄▀░░█░░░░▄▀░░░░█░░▄▀░░░░░░▄▀░░█░░▄▀░░░░░░░░░░█░░▄▀░░█████████░░░░▄▀░░░░█░░▄▀▄▀▄▀▄▀▄▀░░██░░▄▀░░█░░▄▀░░░░░░░░░░█
-// █░░▄▀░░██░░▄▀░░███░░▄▀░░███░░▄▀░░██░░▄▀░░█░░▄▀░░█████████░░▄▀░░███████████░░▄▀░░███░░▄▀░░░░░░▄▀░░██░░▄▀░░█░░▄▀░░█████████
-// █░░▄▀░░░░░░▄▀░░███░░▄▀░░███░░▄▀░░░░░░▄▀░░█░░▄▀░░░░░░░░░░█░░▄▀░░███████████░░▄▀░░███░░▄▀░░██░░▄▀░░██░░▄▀░░█░░▄▀░░░░░░░░░░█
-// █░░▄▀▄▀▄▀▄▀▄▀░░███░░▄▀░░███░░▄▀▄▀▄▀▄▀▄▀░░█░░▄▀▄▀▄▀▄▀▄▀░░█░░▄▀░░███████████░░▄▀░░███░░▄▀░░██░░▄▀░░██░░▄▀░░█░░▄▀▄▀▄▀▄▀▄▀░░█
-// █░░▄▀░░░░░░░░░░███░░▄▀░░███░░▄▀░░░░░░░░░░█░░▄▀░░░░░░░░░░█░░▄▀░░███████████░░▄▀░░███░░▄▀░░██░░▄▀░░██░░▄▀░░█░░▄▀░░░░░░░░░░█
-// █░░▄▀░░███████████░░▄▀░░███░░▄▀░░█████████░░▄▀░░█████████░░▄▀░░███████████░░▄▀░░███░░▄▀░░██░░▄▀░░░░░░▄▀░░█░░▄▀░░█████████
-// █░░▄▀░░█████████░░░░▄▀░░░░█░░▄▀░░█████████░░▄▀░░░░░░░░░░█░░▄▀░░░░░░░░░░█░░░░▄▀░░░░█░░▄▀░░██░░▄▀▄▀▄▀▄▀▄▀░░█░░▄▀░░░░░░░░░░█
-// █░░▄▀░░█████████░░▄▀▄▀▄▀░░█░░▄▀░░█████████░░▄▀▄▀▄▀▄▀▄▀░░█░░▄▀▄▀▄▀▄▀▄▀░░█░░▄▀▄▀▄▀░░█░░▄▀░░██░░░░░░░░░░▄▀░░█░░▄▀▄▀▄▀▄▀▄▀░░█
-// █░░░░░░█████████░░░░░░░░░░█░░░░░░█████████░░░░░░░░░░░░░░█░░░░░░░░░░░░░░█░░░░░░░░░░█░░░░░░██████████░░░░░░█░░░░░░░░░░░░░░█
-// █████████████████████████████████████████████████████████████████████████████████████████████████████████████████████████
+#[allow(clippy::too_many_arguments)]
+fn queue_custom_shadows(
+    shadow_draw_functions: Res<DrawFunctions<Shadow>>,
+    custom_pipeline: Res<CustomPipeline>,
+    mut pipelines: ResMut<SpecializedMeshPipelines<CustomPipeline>>,
+    mut pipeline_cache: ResMut<PipelineCache>,
+    meshes: Res<RenderAssets<Mesh>>,
+    casting_meshes: Query<(&Handle<Mesh>, &ChunkGrass, Option<&ChunkInRange>), With<ShadowCaster>>,
+    view_lights: Query<(Entity, &ViewLightEntities)>,
+    mut view_light_shadow_phases: Query<(&LightEntity, &mut RenderPhase<Shadow>)>,
+    light_visible_entities: LightVisibleEntities,
+) {
+    let draw_shadow = shadow_draw_functions
+        .read()
+        .get_id::<DrawCustomShadow>()
+        .unwrap();
+
+    //Shadow views belong to a camera view, chunks culled for that camera cast no shadows in it
+    for (view_entity, view_lights) in &view_lights {
+        for view_light_entity in view_lights.lights.iter().copied() {
+            let (light_entity, mut shadow_phase) =
+                view_light_shadow_phases.get_mut(view_light_entity).unwrap();
+            //Lights with shadows disabled have no visible entities
+            let visible_entities = match light_visible_entities.get(light_entity) {
+                Some(visible_entities) => visible_entities,
+                None => continue,
+            };
+            for entity in visible_entities.iter().copied() {
+                if let Some((mesh, chunk_grass)) = casting_meshes
+                    .get(entity)
+                    .ok()
+                    .filter(|(_, _, in_range)| ChunkInRange::is_in_range(*in_range, view_entity))
+                    .and_then(|(mesh_handle, chunk_grass, _)| Some((meshes.get(mesh_handle)?, chunk_grass)))
+                {
+                    //Blended grass casts solid shadows, alpha mask grass cuts them out like the main pass
+                    let alpha_mode = match AlphaModeKey::from(chunk_grass.alpha_mode) {
+                        AlphaModeKey::Mask => AlphaModeKey::Mask,
+                        _ => AlphaModeKey::Opaque,
+                    };
+                    let key = ChunkGrassPipelineKey {
+                        mesh_key: MeshPipelineKey::from_primitive_topology(mesh.primitive_topology),
+                        alpha_mode,
+                        depth_only: true,
+                    };
+                    let pipeline = match pipelines.specialize(
+                        &mut pipeline_cache,
+                        &custom_pipeline,
+                        key,
+                        &mesh.layout,
+                    ) {
+                        Ok(pipeline) => pipeline,
+                        Err(err) => {
+                            error!("{}", err);
+                            continue;
+                        }
+                    };
+                    shadow_phase.add(Shadow {
+                        draw_function: draw_shadow,
+                        pipeline,
+                        entity,
+                        distance: 0.0,
+                    });
+                }
+            }
+        }
+    }
+}
 
 pub struct CustomPipeline {
     shader: Handle<Shader>,
+    //Imported by path from grass.wgsl, kept loaded so the pipeline cache can resolve them
+    _shader_imports: Vec<Handle<Shader>>,
     mesh_pipeline: MeshPipeline,
+    shadow_view_layout: BindGroupLayout,
     grass_chunk_bind_group_layout: BindGroupLayout,
     growth_bind_group_layout: BindGroupLayout,
     grid_config_bind_group_layout: BindGroupLayout,
@@ -487,6 +1303,7 @@ impl FromWorld for CustomPipeline {
         let growth_bind_group_layout =
             render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
                 entries: &[
+                    //Growth is loaded and blended in the shader like sample_growth, there is no sampler
                     BindGroupLayoutEntry {
                         binding: 0,
                         visibility: ShaderStages::VERTEX_FRAGMENT,
@@ -497,10 +1314,25 @@ impl FromWorld for CustomPipeline {
                         },
                         count: None,
                     },
+                    //Terrain heights, R32Float is not filterable so the shader interpolates itself
                     BindGroupLayoutEntry {
                         binding: 1,
-                        visibility: ShaderStages::VERTEX_FRAGMENT,
-                        ty: BindingType::Sampler(SamplerBindingType::Filtering),
+                        visibility: ShaderStages::VERTEX,
+                        ty: BindingType::Texture {
+                            multisampled: false,
+                            view_dimension: TextureViewDimension::D2,
+                            sample_type: TextureSampleType::Float { filterable: false },
+                        },
+                        count: None,
+                    },
+                    BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: ShaderStages::VERTEX,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
                         count: None,
                     },
                 ],
@@ -529,12 +1361,19 @@ impl FromWorld for CustomPipeline {
         let asset_server = world.resource::<AssetServer>();
         asset_server.watch_for_changes().unwrap();
         let shader = asset_server.load("shaders/grass.wgsl");
+        let shader_imports = vec![
+            asset_server.load("shaders/forest_rng.wgsl"),
+            asset_server.load("shaders/grass_placement.wgsl"),
+        ];
 
         let mesh_pipeline = world.resource::<MeshPipeline>();
+        let shadow_pipeline = world.resource::<ShadowPipeline>();
 
         CustomPipeline {
             shader,
+            _shader_imports: shader_imports,
             mesh_pipeline: mesh_pipeline.clone(),
+            shadow_view_layout: shadow_pipeline.view_layout.clone(),
             grass_chunk_bind_group_layout,
             growth_bind_group_layout,
             grid_config_bind_group_layout,
@@ -542,15 +1381,23 @@ impl FromWorld for CustomPipeline {
     }
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ChunkGrassPipelineKey {
+    pub mesh_key: MeshPipelineKey,
+    pub alpha_mode: AlphaModeKey,
+    /// Vertex stage only, for the shadow passes
+    pub depth_only: bool,
+}
+
 impl SpecializedMeshPipeline for CustomPipeline {
-    type Key = MeshPipelineKey;
+    type Key = ChunkGrassPipelineKey;
 
     fn specialize(
         &self,
         key: Self::Key,
         layout: &MeshVertexBufferLayout,
     ) -> Result<RenderPipelineDescriptor, SpecializedMeshPipelineError> {
-        let mut descriptor = self.mesh_pipeline.specialize(key, layout)?;
+        let mut descriptor = self.mesh_pipeline.specialize(key.mesh_key, layout)?;
         descriptor.primitive.cull_mode = None; //For grass
         descriptor.vertex.shader = self.shader.clone();
         descriptor.fragment.as_mut().unwrap().shader = self.shader.clone();
@@ -561,6 +1408,13 @@ impl SpecializedMeshPipeline for CustomPipeline {
             self.growth_bind_group_layout.clone(),
             self.grid_config_bind_group_layout.clone(),
         ]);
+        if key.alpha_mode == AlphaModeKey::Mask {
+            descriptor.fragment.as_mut().unwrap().shader_defs.push(String::from("ALPHA_MASK"));
+        }
+        if key.depth_only {
+            let depth_fragment = (key.alpha_mode == AlphaModeKey::Mask).then_some("fragment_depth");
+            make_depth_only(&mut descriptor, &self.shadow_view_layout, depth_fragment);
+        }
 
         Ok(descriptor)
     }
@@ -590,18 +1444,30 @@ type DrawCustom = (
     DrawMeshInstanced,
 );
 
+type DrawCustomShadow = (
+    SetItemPipeline,
+    SetShadowViewBindGroup<0>,
+    SetMeshBindGroup<1>,
+    SetChunkGrassBindGroup<2>,
+    SetGrowthTexturesBindGroup<3>,
+    SetGridConfigBindGroup<4>,
+    DrawMeshInstanced,
+);
+
+/// Binds the chunk uniform with the fade of the view, shadow views use the fade of their camera
 pub struct SetChunkGrassBindGroup<const I: usize>;
 impl<const I: usize> EntityRenderCommand for SetChunkGrassBindGroup<I> {
-    type Param = SQuery<Read<ChunkGrassBindGroup>>;
+    type Param = (SQuery<Read<ChunkGrassBindGroup>>, SRes<ShadowViewOwners>);
     #[inline]
     fn render<'w>(
-        _view: Entity,
+        view: Entity,
         item: Entity,
-        grass_bind_group_query: SystemParamItem<'w, '_, Self::Param>,
+        (grass_bind_group_query, shadow_view_owners): SystemParamItem<'w, '_, Self::Param>,
         pass: &mut TrackedRenderPass<'w>,
     ) -> RenderCommandResult {
         let grass_chunk_bind_group = grass_bind_group_query.get_inner(item).unwrap();
-        pass.set_bind_group(I, &grass_chunk_bind_group.grass_chunk_bind_group, &[]);
+        let view = shadow_view_owners.camera_view(view);
+        pass.set_bind_group(I, grass_chunk_bind_group.bind_group(view), &[]);
         RenderCommandResult::Success
     }
 }
@@ -1,10 +1,14 @@
 use bevy::{
-    core_pipeline::core_3d::Transparent3d,
+    core_pipeline::core_3d::{AlphaMask3d, Opaque3d, Transparent3d},
     ecs::system::{lifetimeless::*, SystemParamItem, SystemState},
-    pbr::{MeshPipeline, MeshPipelineKey, MeshUniform, SetMeshBindGroup, SetMeshViewBindGroup},
+    pbr::{
+        LightEntity, Material, MaterialPipeline, MaterialPipelineKey, MeshPipeline, MeshPipelineKey,
+        MeshUniform, RenderMaterials, SetMeshBindGroup, SetMeshViewBindGroup, SetShadowViewBindGroup,
+        Shadow, ShadowPipeline, StandardMaterialKey, ViewLightEntities,
+    },
     prelude::*,
     render::{
-        mesh::{GpuBufferInfo, MeshVertexBufferLayout},
+        mesh::{GpuBufferInfo, GpuMesh, MeshVertexBufferLayout},
         primitives::Aabb,
         render_asset::RenderAssets,
         render_phase::{
@@ -12,34 +16,52 @@ use bevy::{
             SetItemPipeline, TrackedRenderPass,
         },
         render_resource::*,
-        renderer::RenderDevice,
+        renderer::{RenderDevice, RenderQueue},
         view::{ComputedVisibility, ExtractedView, Msaa},
         Extract, RenderApp, RenderStage,
     },
+    utils::{HashMap, HashSet},
 };
 use bevy_inspector_egui::{Inspectable, RegisterInspectable};
 use rand::Rng;
-use super::{DistanceCulling};
+use super::{
+    impostor::ImpostorLod,
+    rng,
+    scatter::{Heightfield, ScatterRule},
+    shadow::{
+        extract_shadow_casters, make_depth_only, LightVisibleEntities, ShadowCaster,
+        ShadowViewOwners,
+    },
+    alpha_cutoff, AlphaModeKey, ChunkInRange, DistanceCulling,
+};
 pub struct ChunkInstancingPlugin;
 
 impl Plugin for ChunkInstancingPlugin {
     fn build(&self, app: &mut App) {
-        app.add_system(chunk_distance_culling)
-        .register_inspectable::<Instance>()
-        .register_inspectable::<ChunkInstancing>();
+        app.register_inspectable::<Instance>()
+        .register_inspectable::<InstanceFormat>()
+        .register_inspectable::<ChunkInstancing>()
+        .add_event::<InstanceChanged>()
+        .add_system_to_stage(CoreStage::PostUpdate, send_instance_events);
 
         app.sub_app_mut(RenderApp)
+            .add_render_command::<Opaque3d, DrawCustom>()
+            .add_render_command::<AlphaMask3d, DrawCustom>()
             .add_render_command::<Transparent3d, DrawCustom>()
+            .add_render_command::<Shadow, DrawCustomShadow>()
             .init_resource::<CustomPipeline>()
             .init_resource::<SpecializedMeshPipelines<CustomPipeline>>()
+            .init_resource::<ChunkInstancingCache>()
             .add_system_to_stage(RenderStage::Extract, extract_chunk_instancies)
+            .add_system_to_stage(RenderStage::Extract, extract_shadow_casters::<ChunkInstancing>)
             .add_system_to_stage(
                 RenderStage::Prepare,
                 prepare_chunk_instancing_instance_buffers,
             )
             .add_system_to_stage(RenderStage::Prepare, prepare_textures_bind_group)
             .add_system_to_stage(RenderStage::Prepare, prepare_grass_chunk_bind_group)
-            .add_system_to_stage(RenderStage::Queue, queue_custom);
+            .add_system_to_stage(RenderStage::Queue, queue_custom)
+            .add_system_to_stage(RenderStage::Queue, queue_custom_shadows);
     }
 }
 
@@ -61,60 +83,487 @@ pub struct ChunkInstancingBundle {
     pub distance_culling: DistanceCulling,
 }
 
-fn chunk_distance_culling(
-    mut query: Query<(&GlobalTransform, &mut Visibility, &DistanceCulling)>,
-    query_camera: Query<&GlobalTransform, With<Camera>>,
-) {
-    if let Ok(camera_global_transform) = query_camera.get_single() {
-        let camera_trans = camera_global_transform.compute_transform();
-        for (global_transform, mut visability, distance_culling) in query.iter_mut() {
-            let transform = global_transform.compute_transform();
-            if camera_trans.translation.distance(transform.translation) > distance_culling.distance {
-                visability.is_visible = false;
-            } else {
-                visability.is_visible = true;
+#[derive(Clone, Inspectable, Debug)]
+pub struct Instance {
+    pub pos_xyz: [f32; 4], //[x,y,z, scale]
+    /// Only used by [`InstanceFormat::Full`]
+    pub rotation: Quat,
+    /// Per axis scale on top of the uniform scale in `pos_xyz`, only used by [`InstanceFormat::Full`]
+    pub scale: Vec3,
+    /// Multiplied with the base color, only used when `ChunkInstancing::instance_variation` is set
+    pub tint: Color,
+    /// Layer of the base color texture array, only used when `ChunkInstancing::instance_variation` is set
+    pub texture_layer: u32,
+}
+
+impl Default for Instance {
+    fn default() -> Self {
+        Self {
+            pos_xyz: [0.0, 0.0, 0.0, 1.0],
+            rotation: Quat::IDENTITY,
+            scale: Vec3::ONE,
+            tint: Color::WHITE,
+            texture_layer: 0,
+        }
+    }
+}
+
+impl Instance {
+    pub fn new(translation: Vec3, rotation: Quat, scale: Vec3) -> Self {
+        Self {
+            pos_xyz: [translation.x, translation.y, translation.z, 1.0],
+            rotation,
+            scale,
+            ..default()
+        }
+    }
+
+    pub fn with_variation(mut self, tint: Color, texture_layer: u32) -> Self {
+        self.tint = tint;
+        self.texture_layer = texture_layer;
+        self
+    }
+
+    pub fn translation(&self) -> Vec3 {
+        Vec3::new(self.pos_xyz[0], self.pos_xyz[1], self.pos_xyz[2])
+    }
+
+    /// Chunk local transform of the instance as drawn by the shader (without the layers model_transform)
+    pub fn transform(&self, format: InstanceFormat) -> Transform {
+        match format {
+            InstanceFormat::Compact => {
+                let (yaw, jitter) = compact_yaw_jitter(self.pos_xyz[0], self.pos_xyz[2]);
+                Transform {
+                    translation: self.translation(),
+                    rotation: Quat::from_rotation_y(yaw),
+                    scale: Vec3::splat(self.pos_xyz[3] * jitter),
+                }
+            }
+            InstanceFormat::Full => Transform {
+                translation: self.translation(),
+                rotation: self.rotation,
+                scale: self.scale * self.pos_xyz[3],
+            },
+        }
+    }
+
+    /// Inverse of [`Instance::transform`]. The compact format only keeps the position and the average scale,
+    /// its yaw always comes from the position
+    pub fn from_transform(transform: Transform, format: InstanceFormat) -> Self {
+        let [x, y, z] = transform.translation.to_array();
+        match format {
+            InstanceFormat::Compact => {
+                let (_, jitter) = compact_yaw_jitter(x, z);
+                let scale = (transform.scale.x + transform.scale.y + transform.scale.z) / 3.0;
+                Self {
+                    pos_xyz: [x, y, z, scale / jitter],
+                    ..default()
+                }
             }
+            InstanceFormat::Full => Self {
+                pos_xyz: [x, y, z, 1.0],
+                rotation: transform.rotation,
+                scale: transform.scale,
+                ..default()
+            },
         }
     }
 }
 
-#[derive(Clone, Inspectable, Debug, Default)]
-pub struct Instance {
-    pub pos_xyz: [f32; 4],
+//Same random yaw and scale jitter as chunk_instancing.wgsl
+fn compact_yaw_jitter(x: f32, z: f32) -> (f32, f32) {
+    let hash = rng::pcg_hash(x.to_bits() ^ rng::pcg_hash(z.to_bits()));
+    let yaw = rng::hash_to_unit(hash) * std::f32::consts::TAU;
+    let jitter = rng::hash_to_unit(rng::pcg_hash(hash)) * 0.2 + 0.9;
+    (yaw, jitter)
+}
+
+/// How instances are sent to the gpu
+#[derive(Clone, Copy, Inspectable, Debug, PartialEq, Eq, Hash)]
+pub enum InstanceFormat {
+    /// 16 bytes per instance: position and uniform scale, the yaw is randomized in the shader
+    Compact,
+    /// 48 bytes per instance: position, rotation and per axis scale
+    Full,
+}
+
+impl Default for InstanceFormat {
+    fn default() -> Self {
+        InstanceFormat::Compact
+    }
 }
 
 #[derive(Component, Inspectable, Clone, Debug, Default)]
 pub struct ChunkInstancing {
-    pub instances: Vec<Instance>, //[x,y,z, scale] Lower performance if using full Transforms
+    /// Read with [`ChunkInstancing::instances`], edited through the instance id api or `instances_mut` so the edit log
+    /// always knows what has to be uploaded
+    instances: Vec<Instance>, //Lower performance if using full Transforms, see InstanceFormat
+    /// 2d texture or 2d array texture (see `Image::reinterpret_stacked_2d_as_array`) indexed by `Instance::texture_layer`
     pub base_color_texture: Handle<Image>,
     pub model_transform: Transform,
+    pub instance_format: InstanceFormat,
+    /// Sends `Instance::tint` and `Instance::texture_layer` to the gpu, costs 16 bytes extra per instance
+    pub instance_variation: bool,
+    /// Lower detail meshes used instead of the entity's `Handle<Mesh>` when the camera is far enough from the chunk
+    #[inspectable(ignore)]
+    pub lods: Vec<MeshLod>,
+    /// Camera facing billboards drawn instead of the meshes beyond `ImpostorLod::distance`, see [`crate::impostor::ImpostorBaker`]
+    #[inspectable(ignore)]
+    pub impostor: Option<ImpostorLod>,
+    /// Opaque layers go to the Opaque3d phase, Mask to AlphaMask3d (cut out below the cutoff) and Blend to Transparent3d.
+    /// Ignored when `material` is set, the alpha mode of the material is used then like for a `PbrBundle`
+    #[inspectable(ignore)]
+    pub alpha_mode: AlphaMode,
+    /// Renders the instances with every texture and parameter of the material like a `PbrBundle` would,
+    /// `base_color_texture` and `Instance::texture_layer` are ignored when set
+    #[inspectable(ignore)]
+    pub material: Option<Handle<StandardMaterial>>,
+    #[inspectable(ignore)]
+    edit_log: InstanceEditLog,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct MeshLod {
+    pub mesh: Handle<Mesh>,
+    /// Distance from the camera to the chunk center from which this mesh is used
+    pub distance: f32,
+}
+
+/// Index into `lods` of the mesh to draw at `distance`, None means the full detail mesh.
+/// The lods don't need to be sorted, the one with the largest distance not beyond `distance` wins
+pub fn select_lod(lods: &[MeshLod], distance: f32) -> Option<usize> {
+    lods.iter()
+        .enumerate()
+        .filter(|(_, lod)| distance >= lod.distance)
+        .max_by(|(_, a), (_, b)| a.distance.total_cmp(&b.distance))
+        .map(|(i, _)| i)
 }
 
 impl ChunkInstancing {
+    /// Random placement that differs on every launch, see [`ChunkInstancing::new_seeded`] for reproducible worlds
     pub fn new(
         nr_instances: u32,
         base_color_texture: Handle<Image>,
         model_transform: Transform,
         chunk_size: f32,
     ) -> Self {
-        let mut rng = rand::thread_rng();
-        let mut instances = Vec::new();
-        for _ in 0..nr_instances {
-            let x = rng.gen::<f32>() * chunk_size;
-            let z = rng.gen::<f32>() * chunk_size;
-            let scale = rng.gen::<f32>() * 0.5 + 0.5;
-
-            instances.push(Instance {
-                pos_xyz: [x, 0.0, z, scale],
-            });
-        }
+        let seed = rand::thread_rng().gen::<u64>();
+        Self::new_seeded(nr_instances, base_color_texture, model_transform, chunk_size, seed)
+    }
+
+    /// Same seed gives bit identical instances on every run and platform, use [`rng::chunk_seed`] to derive one per chunk and layer
+    pub fn new_seeded(
+        nr_instances: u32,
+        base_color_texture: Handle<Image>,
+        model_transform: Transform,
+        chunk_size: f32,
+        seed: u64,
+    ) -> Self {
+        let instances = Self::scatter(nr_instances, chunk_size, &mut rng::ForestRng::new(seed));
 
         Self {
             instances,
             base_color_texture,
             model_transform,
+            instance_format: InstanceFormat::Compact,
+            instance_variation: false,
+            alpha_mode: AlphaMode::Opaque,
+            lods: Vec::new(),
+            impostor: None,
+            material: None,
+            edit_log: InstanceEditLog::default(),
+        }
+    }
+
+    /// Uniform placement over [0, chunk_size) in x and z with random scale and yaw
+    pub fn scatter(nr_instances: u32, chunk_size: f32, rng: &mut rng::ForestRng) -> Vec<Instance> {
+        (0..nr_instances)
+            .map(|_| {
+                let x = rng.next_f32() * chunk_size;
+                let z = rng.next_f32() * chunk_size;
+                Self::random_instance(x, z, rng)
+            })
+            .collect()
+    }
+
+    /// Instance at `x`, `z` with random scale and yaw
+    pub(crate) fn random_instance(x: f32, z: f32, rng: &mut rng::ForestRng) -> Instance {
+        let scale = rng.next_f32() * 0.5 + 0.5;
+        let [qx, qy, qz, qw] = rng.yaw_rotation();
+
+        Instance {
+            pos_xyz: [x, 0.0, z, scale],
+            rotation: Quat::from_xyzw(qx, qy, qz, qw),
+            scale: Vec3::ONE,
+            ..default()
+        }
+    }
+
+    /// For instances placed elsewhere, e.g. by [`crate::scatter::PoissonScatter`]
+    pub fn from_instances(
+        instances: Vec<Instance>,
+        base_color_texture: Handle<Image>,
+        model_transform: Transform,
+    ) -> Self {
+        Self {
+            instances,
+            ..Self::new_seeded(0, base_color_texture, model_transform, 0.0, 0)
         }
     }
+
+    /// Thins out the instances, see [`ScatterRule::retain`]
+    pub fn with_scatter_rule(
+        mut self,
+        rule: &ScatterRule,
+        chunk_origin: Vec2,
+        heightfield: &impl Heightfield,
+    ) -> Self {
+        rule.retain(&mut self.instances, chunk_origin, heightfield);
+        self
+    }
+
+    /// Moves the instances onto the ground, `chunk_origin` is the world position of the chunk corner.
+    /// Aligning to the ground normal needs the rotations so it switches to [`InstanceFormat::Full`].
+    pub fn with_terrain(
+        mut self,
+        terrain: &impl Heightfield,
+        chunk_origin: Vec2,
+        align_to_normal: bool,
+    ) -> Self {
+        for instance in self.instances.iter_mut() {
+            let x = chunk_origin.x + instance.pos_xyz[0];
+            let z = chunk_origin.y + instance.pos_xyz[2];
+            instance.pos_xyz[1] = terrain.height_at(x, z);
+            if align_to_normal {
+                instance.rotation =
+                    Quat::from_rotation_arc(Vec3::Y, terrain.normal_at(x, z)) * instance.rotation;
+            }
+        }
+        if align_to_normal {
+            self.instance_format = InstanceFormat::Full;
+        }
+        self
+    }
+
+    pub fn with_instance_format(mut self, instance_format: InstanceFormat) -> Self {
+        self.instance_format = instance_format;
+        self
+    }
+
+    pub fn with_instance_variation(mut self) -> Self {
+        self.instance_variation = true;
+        self
+    }
+
+    pub fn with_lod(mut self, mesh: Handle<Mesh>, distance: f32) -> Self {
+        self.lods.push(MeshLod { mesh, distance });
+        self
+    }
+
+    pub fn with_impostor(mut self, impostor: ImpostorLod) -> Self {
+        self.impostor = Some(impostor);
+        self
+    }
+
+    pub fn with_alpha_mode(mut self, alpha_mode: AlphaMode) -> Self {
+        self.alpha_mode = alpha_mode;
+        self
+    }
+
+    pub fn with_material(mut self, material: Handle<StandardMaterial>) -> Self {
+        self.material = Some(material);
+        self
+    }
+
+    pub fn instances(&self) -> &[Instance] {
+        &self.instances
+    }
+
+    /// For bulk edits, the next upload writes every instance. Prefer [`ChunkInstancing::push_instance`], `remove_instance`
+    /// and `update_instance` for single edits, they only upload the instances that changed. Resizing the Vec gives every
+    /// instance a new id, use [`ChunkInstancing::set_instances`] to replace them all
+    pub fn instances_mut(&mut self) -> &mut Vec<Instance> {
+        self.edit_log.invalidate();
+        &mut self.instances
+    }
+
+    /// Adds an instance, only that instance is written to the gpu
+    pub fn push_instance(&mut self, instance: Instance) -> InstanceId {
+        self.ensure_ids();
+        let id = InstanceId(self.edit_log.next_id);
+        self.edit_log.next_id += 1;
+        let index = self.instances.len();
+        self.instances.push(instance);
+        self.edit_log.ids.push(id);
+        self.edit_log.indices.insert(id, index);
+        self.log_edit(index, id, InstanceChange::Added);
+        id
+    }
+
+    /// Swap removes the instance, the last instance moves into its slot and keeps its id
+    pub fn remove_instance(&mut self, id: InstanceId) -> Option<Instance> {
+        self.ensure_ids();
+        let index = self.edit_log.indices.remove(&id)?;
+        let instance = self.instances.swap_remove(index);
+        self.edit_log.ids.swap_remove(index);
+        if let Some(moved) = self.edit_log.ids.get(index) {
+            self.edit_log.indices.insert(*moved, index);
+        }
+        self.log_edit(index, id, InstanceChange::Removed);
+        Some(instance)
+    }
+
+    /// Modifies the instance in place, false if the id is unknown
+    pub fn update_instance(&mut self, id: InstanceId, update: impl FnOnce(&mut Instance)) -> bool {
+        self.ensure_ids();
+        let index = match self.edit_log.indices.get(&id) {
+            Some(index) => *index,
+            None => return false,
+        };
+        update(&mut self.instances[index]);
+        self.log_edit(index, id, InstanceChange::Updated);
+        true
+    }
+
+    pub fn instance(&self, id: InstanceId) -> Option<&Instance> {
+        self.index_of(id).map(|index| &self.instances[index])
+    }
+
+    /// Id of the instance at `index` of `instances`
+    pub fn instance_id(&self, index: usize) -> Option<InstanceId> {
+        if index >= self.instances.len() {
+            return None;
+        }
+        match self.edit_log.ids.len() == self.instances.len() {
+            true => Some(self.edit_log.ids[index]),
+            false if self.edit_log.next_id == 0 => Some(InstanceId(index as u32)),
+            false => None,
+        }
+    }
+
+    /// Replaces every instance and gives them new ids, the whole buffer is uploaded again
+    pub fn set_instances(&mut self, instances: Vec<Instance>) {
+        self.ensure_ids(); //So the new ids don't reuse the old ones
+        self.instances = instances;
+        self.edit_log.ids.clear();
+        self.ensure_ids();
+        self.edit_log.invalidate();
+    }
+
+    //Instances that were never edited use their index as id, they get real ids on the first edit
+    //or when `instances_mut` resized them
+    fn ensure_ids(&mut self) {
+        if self.edit_log.ids.len() == self.instances.len() {
+            return;
+        }
+        if self.has_stale_ids() {
+            warn!(
+                "ChunkInstancing::instances_mut went from {} to {} instances, every instance gets a new id. Use set_instances to replace them",
+                self.edit_log.ids.len(),
+                self.instances.len()
+            );
+        }
+        let log = &mut self.edit_log;
+        let first = log.next_id;
+        log.ids = (0..self.instances.len() as u32)
+            .map(|i| InstanceId(first + i))
+            .collect();
+        log.next_id = first + self.instances.len() as u32;
+        log.indices = log.ids.iter().enumerate().map(|(i, id)| (*id, i)).collect();
+    }
+
+    /// Ids were handed out but `instances_mut` resized the instances since, the old ids no longer match
+    pub(crate) fn has_stale_ids(&self) -> bool {
+        self.edit_log.next_id != 0 && self.edit_log.ids.len() != self.instances.len()
+    }
+
+    fn index_of(&self, id: InstanceId) -> Option<usize> {
+        let index = match self.edit_log.ids.len() == self.instances.len() {
+            true => *self.edit_log.indices.get(&id)?,
+            false if self.edit_log.next_id == 0 => id.0 as usize,
+            false => return None,
+        };
+        (index < self.instances.len()).then_some(index)
+    }
+
+    fn log_edit(&mut self, index: usize, id: InstanceId, change: InstanceChange) {
+        let log = &mut self.edit_log;
+        log.revision += 1;
+        log.dirty.push(index);
+        log.events.push((id, change));
+        //Past this many edits a full upload is as cheap, older revisions fall back to it
+        let max_entries = self.instances.len().max(64);
+        if log.dirty.len() > max_entries {
+            let trimmed = log.dirty.len() / 2;
+            log.dirty.drain(..trimmed);
+            log.first_revision += trimmed as u64;
+        }
+    }
+}
+
+/// Stable handle to an instance of a [`ChunkInstancing`], stays valid while other instances are added and removed
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct InstanceId(pub u32);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InstanceChange {
+    Added,
+    Removed,
+    Updated,
+}
+
+/// Sent for every `push_instance`, `remove_instance` and `update_instance`, not for `set_instances`
+#[derive(Clone, Copy, Debug)]
+pub struct InstanceChanged {
+    pub chunk: Entity,
+    pub id: InstanceId,
+    pub change: InstanceChange,
+}
+
+/// Ids of the instances and the indices written per revision, the render world only writes
+/// the indices edited since the revision it uploaded last
+#[derive(Clone, Debug, Default)]
+struct InstanceEditLog {
+    ids: Vec<InstanceId>,
+    indices: HashMap<InstanceId, usize>,
+    next_id: u32,
+    revision: u64,
+    /// Revision before `dirty[0]` was written, older revisions need a full upload
+    first_revision: u64,
+    dirty: Vec<usize>,
+    events: Vec<(InstanceId, InstanceChange)>,
+}
+
+impl InstanceEditLog {
+    /// Every revision before this one needs a full upload, for changes that didn't go through the log
+    fn invalidate(&mut self) {
+        self.revision += 1;
+        self.first_revision = self.revision;
+        self.dirty.clear();
+    }
+
+    fn dirty_since(&self, revision: u64) -> Option<&[usize]> {
+        let offset = revision.checked_sub(self.first_revision)?;
+        self.dirty.get(offset as usize..)
+    }
+}
+
+fn send_instance_events(
+    mut chunks: Query<(Entity, &mut ChunkInstancing), Changed<ChunkInstancing>>,
+    mut events: EventWriter<InstanceChanged>,
+) {
+    for (chunk, mut chunk_instancing) in chunks.iter_mut() {
+        if chunk_instancing.edit_log.events.is_empty() {
+            continue;
+        }
+        events.send_batch(
+            chunk_instancing
+                .edit_log
+                .events
+                .drain(..)
+                .map(|(id, change)| InstanceChanged { chunk, id, change }),
+        );
+    }
 }
 
 
@@ -133,28 +582,138 @@ impl ChunkInstancing {
 // ██████████████████████████████████████████████████████████████████████████████████████████████████████████████████
 
 //Make custom extract func in order to not clone instance data twice when using convinient abstract types for world side components
+//Instance data is only cloned when the main world component changed (or the chunk comes back into view after changing),
+//everything else lives in the ChunkInstancingCache between frames
 fn extract_chunk_instancies(
     mut commands: Commands,
     mut previous_len: Local<usize>,
-    mut query: Extract<Query<(Entity, &ComputedVisibility, &ChunkInstancing)>>,
+    mut frame: Local<u32>,
+    mut cache: ResMut<ChunkInstancingCache>,
+    query: Extract<
+        Query<(
+            Entity,
+            &ComputedVisibility,
+            &GlobalTransform,
+            Option<&Aabb>,
+            &ChunkInstancing,
+            ChangeTrackers<ChunkInstancing>,
+        )>,
+    >,
+    mut image_events: Extract<EventReader<AssetEvent<Image>>>,
+    materials: Extract<Res<Assets<StandardMaterial>>>,
+    mut material_events: Extract<EventReader<AssetEvent<StandardMaterial>>>,
 ) {
-    if !query.is_empty() {
-        let mut values = Vec::with_capacity(*previous_len);
-        for (entity, computed_visibility, query_item) in query.iter_mut() {
-            if computed_visibility.is_visible() {
-                values.push((
-                    entity,
-                    (
-                        query_item.to_raw_instances(),
-                        query_item.to_raw_chunk_bind_group(),
-                        query_item.base_color_texture.clone(),
-                    ),
-                ));
+    *frame = frame.wrapping_add(1);
+
+    //Reloaded textures need new bind groups since the gpu image is recreated
+    let modified_images: HashSet<Handle<Image>> = image_events
+        .iter()
+        .filter_map(|event| match event {
+            AssetEvent::Modified { handle } | AssetEvent::Removed { handle } => {
+                Some(handle.clone_weak())
+            }
+            AssetEvent::Created { .. } => None,
+        })
+        .collect();
+    //The alpha mode of materials decides the pipeline and the cutoff of the chunks using them
+    let modified_materials: HashSet<Handle<StandardMaterial>> = material_events
+        .iter()
+        .filter_map(|event| match event {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle } => {
+                Some(handle.clone_weak())
+            }
+            AssetEvent::Removed { .. } => None,
+        })
+        .collect();
+
+    let mut values = Vec::with_capacity(*previous_len);
+    for (
+        entity,
+        computed_visibility,
+        global_transform,
+        aabb,
+        chunk_instancing,
+        change_tracker,
+    ) in query.iter()
+    {
+        let cached = cache.chunks.entry(entity).or_default();
+        cached.last_seen_frame = *frame;
+        if change_tracker.is_changed() {
+            cached.stale = true;
+        }
+        if let Some(material) = &chunk_instancing.material {
+            if modified_materials.contains(material) {
+                cached.stale = true;
+            }
+        }
+        if modified_images.contains(&chunk_instancing.base_color_texture) {
+            cached.texture_bind_group = None;
+        }
+        if let Some(impostor) = &chunk_instancing.impostor {
+            if modified_images.contains(&impostor.atlas) {
+                cached.impostor_bind_group = None;
+            }
+        }
+
+        if !computed_visibility.is_visible() {
+            continue;
+        }
+
+        if cached.stale {
+            //Edits through the instance id api since the last upload are written sparsely, anything else
+            //(new chunk, other layout, trimmed edit log, instances_mut, the inspector) uploads everything
+            let log = &chunk_instancing.edit_log;
+            let dirty = log.dirty_since(cached.revision).filter(|_| {
+                cached.revision != log.revision
+                    && cached.instance_buffer.is_some()
+                    && cached.instance_format == chunk_instancing.instance_format
+                    && cached.instance_variation == chunk_instancing.instance_variation
+            });
+            cached.pending_instances = Some(match dirty {
+                Some(dirty) => chunk_instancing.to_raw_instance_writes(dirty),
+                None => PendingInstances::All(chunk_instancing.to_raw_instances()),
+            });
+            cached.revision = log.revision;
+            cached.instance_format = chunk_instancing.instance_format;
+            cached.instance_variation = chunk_instancing.instance_variation;
+            cached.lods = chunk_instancing.lods.clone();
+            if cached.impostor != chunk_instancing.impostor {
+                cached.impostor = chunk_instancing.impostor.clone();
+                cached.impostor_bind_group = None;
+            }
+            cached.material = chunk_instancing.material.clone();
+            let alpha_mode = chunk_instancing.material_alpha_mode(&materials);
+            cached.alpha_mode = alpha_mode.into();
+            if cached.texture != chunk_instancing.base_color_texture {
+                cached.texture = chunk_instancing.base_color_texture.clone();
+                cached.texture_bind_group = None;
             }
+            cached.pending_chunk_data = Some(chunk_instancing.to_raw_chunk_bind_group(alpha_mode));
+            cached.stale = false;
         }
-        *previous_len = values.len();
-        commands.insert_or_spawn_batch(values);
+        let center = aabb.map_or(Vec3::ZERO, |aabb| aabb.center.into());
+        values.push((
+            entity,
+            ExtractedChunkInstancing {
+                center: global_transform.compute_matrix().transform_point3(center),
+            },
+        ));
     }
+    //Drop gpu data of despawned chunks
+    let current_frame = *frame;
+    cache
+        .chunks
+        .retain(|_, cached| cached.last_seen_frame == current_frame);
+
+    *previous_len = values.len();
+    commands.insert_or_spawn_batch(values);
+}
+
+/// Render world marker for chunks that are visible this frame, the gpu data itself lives in [`ChunkInstancingCache`]
+#[derive(Component, Clone, Copy)]
+pub struct ExtractedChunkInstancing {
+    /// World space center of the chunk, used for lod selection
+    pub(crate) center: Vec3,
 }
 
 #[repr(C)]
@@ -163,27 +722,172 @@ struct GpuInstance {
     pub pos_xyz: [f32; 4],
 }
 
-#[derive(Component, Clone)]
-pub struct GpuInstances(Vec<GpuInstance>);
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct GpuInstanceFull {
+    pub pos_xyz: [f32; 4],
+    pub rotation: [f32; 4],
+    pub scale: [f32; 4], //w is padding
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct GpuInstanceVariation {
+    pub tint_layer: [f32; 4], //[r,g,b, texture layer]
+}
 
-#[derive(Component, Clone)]
+impl InstanceFormat {
+    fn size(&self) -> usize {
+        match self {
+            InstanceFormat::Compact => std::mem::size_of::<GpuInstance>(),
+            InstanceFormat::Full => std::mem::size_of::<GpuInstanceFull>(),
+        }
+    }
+}
+
+fn instance_stride(instance_format: InstanceFormat, instance_variation: bool) -> usize {
+    match instance_variation {
+        true => instance_format.size() + std::mem::size_of::<GpuInstanceVariation>(),
+        false => instance_format.size(),
+    }
+}
+
+/// Raw instance bytes, laid out according to the InstanceFormat followed by the optional GpuInstanceVariation
+#[derive(Clone)]
+pub struct GpuInstances(Vec<u8>);
+
+/// Instance data waiting for `prepare_chunk_instancing_instance_buffers`
+enum PendingInstances {
+    All(GpuInstances),
+    /// Instance count after the edits and the byte runs to write starting at an instance index
+    Sparse {
+        length: usize,
+        writes: Vec<(usize, Vec<u8>)>,
+    },
+}
+
+/// Per instance vertex buffer, shared with the impostor pipeline.
+/// Shader locations 0-7 are reserved for the mesh attributes (Position, Normal, UV, Tangent, Color, Joints)
+pub(crate) fn instance_vertex_buffer_layout(
+    instance_format: InstanceFormat,
+    instance_variation: bool,
+    shader_defs: &mut Vec<String>,
+) -> VertexBufferLayout {
+    let mut attributes = vec![VertexAttribute {
+        format: VertexFormat::Float32x4,
+        offset: 0,
+        shader_location: 8,
+    }];
+    if instance_format == InstanceFormat::Full {
+        attributes.push(VertexAttribute {
+            format: VertexFormat::Float32x4,
+            offset: 16,
+            shader_location: 9,
+        });
+        attributes.push(VertexAttribute {
+            format: VertexFormat::Float32x4,
+            offset: 32,
+            shader_location: 10,
+        });
+        shader_defs.push("INSTANCE_FULL_TRANSFORM".to_string());
+    }
+    if instance_variation {
+        attributes.push(VertexAttribute {
+            format: VertexFormat::Float32x4,
+            offset: instance_format.size() as u64,
+            shader_location: 11,
+        });
+        shader_defs.push("INSTANCE_VARIATION".to_string());
+    }
+    VertexBufferLayout {
+        array_stride: instance_stride(instance_format, instance_variation) as u64,
+        step_mode: VertexStepMode::Instance,
+        attributes,
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
 struct GpuChunkBindGroupData {
     model_transform: [[f32; 4]; 4],
+    normal_transform: [[f32; 4]; 4], //Inverse transpose of model_transform
+    alpha_cutoff: [f32; 4],
+    fade: [f32; 4],
 }
 
 impl ChunkInstancing {
     fn to_raw_instances(&self) -> GpuInstances {
-        GpuInstances(
-            self.instances.iter()
-                .map(|v| GpuInstance {
-                    pos_xyz: v.pos_xyz,
-                })
-                .collect(),
-        )
-    }
-    fn to_raw_chunk_bind_group(&self) -> GpuChunkBindGroupData {
+        let stride = instance_stride(self.instance_format, self.instance_variation);
+        let mut data = Vec::with_capacity(self.instances.len() * stride);
+        for v in self.instances.iter() {
+            self.write_raw_instance(v, &mut data);
+        }
+        GpuInstances(data)
+    }
+
+    /// Only the instances at the `dirty` indices, neighbouring indices are merged into one write
+    fn to_raw_instance_writes(&self, dirty: &[usize]) -> PendingInstances {
+        let stride = instance_stride(self.instance_format, self.instance_variation);
+        let mut indices: Vec<usize> = dirty
+            .iter()
+            .copied()
+            .filter(|index| *index < self.instances.len()) //Removed from the end
+            .collect();
+        indices.sort_unstable();
+        indices.dedup();
+
+        let mut writes: Vec<(usize, Vec<u8>)> = Vec::new();
+        for index in indices {
+            match writes.last_mut() {
+                Some((start, data)) if *start + data.len() / stride == index => {
+                    self.write_raw_instance(&self.instances[index], data);
+                }
+                _ => {
+                    let mut data = Vec::with_capacity(stride);
+                    self.write_raw_instance(&self.instances[index], &mut data);
+                    writes.push((index, data));
+                }
+            }
+        }
+        PendingInstances::Sparse {
+            length: self.instances.len(),
+            writes,
+        }
+    }
+
+    fn write_raw_instance(&self, v: &Instance, data: &mut Vec<u8>) {
+        match self.instance_format {
+            InstanceFormat::Compact => data.extend_from_slice(bytemuck::bytes_of(&GpuInstance {
+                pos_xyz: v.pos_xyz,
+            })),
+            InstanceFormat::Full => data.extend_from_slice(bytemuck::bytes_of(&GpuInstanceFull {
+                pos_xyz: v.pos_xyz,
+                rotation: v.rotation.normalize().to_array(),
+                scale: v.scale.extend(0.0).to_array(),
+            })),
+        }
+        if self.instance_variation {
+            let [r, g, b, _] = v.tint.as_linear_rgba_f32();
+            data.extend_from_slice(bytemuck::bytes_of(&GpuInstanceVariation {
+                tint_layer: [r, g, b, v.texture_layer as f32],
+            }));
+        }
+    }
+    /// The alpha mode of the material if there is one, `alpha_mode` otherwise
+    fn material_alpha_mode(&self, materials: &Assets<StandardMaterial>) -> AlphaMode {
+        self.material
+            .as_ref()
+            .and_then(|material| materials.get(material))
+            .map_or(self.alpha_mode, |material| material.alpha_mode)
+    }
+    /// Fully faded in, views inside the fade band get a copy with their own fade
+    fn to_raw_chunk_bind_group(&self, alpha_mode: AlphaMode) -> GpuChunkBindGroupData {
+        let model_transform = self.model_transform.compute_matrix();
         GpuChunkBindGroupData {
-            model_transform: self.model_transform.compute_matrix().to_cols_array_2d(),
+            model_transform: model_transform.to_cols_array_2d(),
+            normal_transform: model_transform.inverse().transpose().to_cols_array_2d(),
+            alpha_cutoff: [alpha_cutoff(alpha_mode), 0.0, 0.0, 0.0],
+            fade: [1.0, 0.0, 0.0, 0.0],
         }
     }
 }
@@ -202,81 +906,340 @@ impl ChunkInstancing {
 // █░░░░░░█████████░░░░░░██░░░░░░░░░░█░░░░░░░░░░░░░░█░░░░░░█████████░░░░░░██░░░░░░█░░░░░░██░░░░░░░░░░█░░░░░░░░░░░░░░█
 // ██████████████████████████████████████████████████████████████████████████████████████████████████████████████████
 
-#[derive(Component)]
-pub struct ChunkInstancingInstanceBuffer {
+/// Gpu side data of every ChunkInstancing entity, kept between frames and keyed by the main world entity.
+/// Buffers and bind groups are only recreated when the size grows or the texture changes.
+#[derive(Default)]
+pub struct ChunkInstancingCache {
+    pub(crate) chunks: HashMap<Entity, CachedChunkInstancing>,
+}
+
+pub(crate) struct CachedChunkInstancing {
+    stale: bool,
+    last_seen_frame: u32,
+    pending_instances: Option<PendingInstances>,
+    /// `ChunkInstancing` edit log revision of the uploaded instances
+    revision: u64,
+    pending_chunk_data: Option<GpuChunkBindGroupData>,
+    chunk_data: GpuChunkBindGroupData,
+    texture: Handle<Image>,
+    pub(crate) instance_format: InstanceFormat,
+    pub(crate) instance_variation: bool,
+    lods: Vec<MeshLod>,
+    pub(crate) impostor: Option<ImpostorLod>,
+    pub(crate) impostor_bind_group: Option<BindGroup>,
+    material: Option<Handle<StandardMaterial>>,
+    alpha_mode: AlphaModeKey,
+
+    pub(crate) instance_buffer: Option<Buffer>,
+    capacity: usize,
+    pub(crate) length: usize,
+    uploaded_instances: Vec<u8>,
+    chunk_buffer: Option<Buffer>,
+    chunk_bind_group: Option<BindGroup>,
+    /// Chunk uniforms of the views that see the chunk inside its fade band
+    faded_bind_groups: HashMap<Entity, FadedBindGroup>,
+    texture_view: Option<TextureView>,
+    texture_bind_group: Option<BindGroup>,
+}
+
+struct FadedBindGroup {
+    fade: f32,
     buffer: Buffer,
-    length: usize,
+    bind_group: BindGroup,
+}
+
+impl Default for CachedChunkInstancing {
+    fn default() -> Self {
+        Self {
+            stale: true, //New chunks always need an upload
+            last_seen_frame: 0,
+            pending_instances: None,
+            revision: 0,
+            pending_chunk_data: None,
+            chunk_data: bytemuck::Zeroable::zeroed(),
+            texture: Handle::default(),
+            instance_format: InstanceFormat::Compact,
+            instance_variation: false,
+            lods: Vec::new(),
+            impostor: None,
+            impostor_bind_group: None,
+            material: None,
+            alpha_mode: AlphaModeKey::Opaque,
+            instance_buffer: None,
+            capacity: 0,
+            length: 0,
+            uploaded_instances: Vec::new(),
+            chunk_buffer: None,
+            chunk_bind_group: None,
+            faded_bind_groups: HashMap::default(),
+            texture_view: None,
+            texture_bind_group: None,
+        }
+    }
+}
+
+impl CachedChunkInstancing {
+    pub(crate) fn stride(&self) -> usize {
+        instance_stride(self.instance_format, self.instance_variation)
+    }
+
+    /// True when the impostor pipeline draws this chunk instead of the meshes
+    pub(crate) fn uses_impostor(&self, distance: f32) -> bool {
+        match &self.impostor {
+            Some(impostor) => distance >= impostor.distance && self.impostor_bind_group.is_some(),
+            None => false,
+        }
+    }
+
+    fn lod_mesh<'a>(&'a self, mesh: &'a Handle<Mesh>, distance: f32) -> &'a Handle<Mesh> {
+        match select_lod(&self.lods, distance) {
+            Some(lod) => &self.lods[lod].mesh,
+            None => mesh,
+        }
+    }
+
+    /// Chunk uniform with the fade of the camera `view`
+    fn chunk_bind_group(&self, view: Entity) -> &BindGroup {
+        match self.faded_bind_groups.get(&view) {
+            Some(faded) => &faded.bind_group,
+            None => self.chunk_bind_group.as_ref().unwrap(),
+        }
+    }
+
+    //Materials are prepared by bevy, queue_custom checks RenderMaterials for those
+    fn is_ready(&self) -> bool {
+        self.length > 0
+            && self.instance_buffer.is_some()
+            && self.chunk_bind_group.is_some()
+            && (self.material.is_some() || self.texture_bind_group.is_some())
+    }
+}
+
+impl ChunkInstancingCache {
+    pub(crate) fn get_ready(&self, entity: Entity) -> Option<&CachedChunkInstancing> {
+        self.chunks.get(&entity).filter(|cached| cached.is_ready())
+    }
+}
+
+/// Range of instances that differs between what is on the gpu and the new data, None if nothing changed
+fn changed_range(old: &[u8], new: &[u8], stride: usize) -> Option<std::ops::Range<usize>> {
+    let first = old.chunks_exact(stride).zip(new.chunks_exact(stride)).position(|(a, b)| a != b)?;
+    let last = old.chunks_exact(stride).zip(new.chunks_exact(stride)).rposition(|(a, b)| a != b)?;
+    Some(first..last + 1)
 }
 
 fn prepare_chunk_instancing_instance_buffers(
-    mut commands: Commands,
-    query: Query<(Entity, &GpuInstances)>,
+    mut cache: ResMut<ChunkInstancingCache>,
     render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
 ) {
-    for (entity, gpu_instances) in &query {
-        let buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
-            label: Some("instance data buffer"),
-            contents: bytemuck::cast_slice(gpu_instances.0.as_slice()),
-            usage: BufferUsages::VERTEX | BufferUsages::COPY_DST,
-        });
-        commands
-            .entity(entity)
-            .insert(ChunkInstancingInstanceBuffer {
-                buffer,
-                length: gpu_instances.0.len(),
-            });
+    for cached in cache.chunks.values_mut() {
+        let gpu_instances = match cached.pending_instances.take() {
+            Some(PendingInstances::All(gpu_instances)) => gpu_instances.0,
+            Some(PendingInstances::Sparse { length, writes }) => {
+                write_sparse_instances(cached, length, writes, &render_device, &render_queue);
+                continue;
+            }
+            None => continue,
+        };
+
+        match &cached.instance_buffer {
+            //Same amount of instances, only write what changed
+            Some(buffer) if gpu_instances.len() == cached.uploaded_instances.len() => {
+                let stride = cached.stride();
+                if let Some(range) = changed_range(&cached.uploaded_instances, &gpu_instances, stride) {
+                    let bytes = range.start * stride..range.end * stride;
+                    render_queue.write_buffer(buffer, bytes.start as u64, &gpu_instances[bytes]);
+                }
+            }
+            //Still fits, reuse the buffer
+            Some(buffer) if gpu_instances.len() <= cached.capacity => {
+                render_queue.write_buffer(buffer, 0, &gpu_instances);
+            }
+            _ => {
+                cached.instance_buffer = create_instance_buffer(&render_device, &gpu_instances);
+                cached.capacity = gpu_instances.len();
+            }
+        }
+        cached.length = gpu_instances.len() / cached.stride();
+        cached.uploaded_instances = gpu_instances;
     }
 }
 
-#[derive(Component)]
-pub struct ChunkInstancingBindGroup(BindGroup);
+fn create_instance_buffer(render_device: &RenderDevice, gpu_instances: &[u8]) -> Option<Buffer> {
+    if gpu_instances.is_empty() {
+        return None;
+    }
+    Some(render_device.create_buffer_with_data(&BufferInitDescriptor {
+        label: Some("instance data buffer"),
+        contents: gpu_instances,
+        usage: BufferUsages::VERTEX | BufferUsages::COPY_DST,
+    }))
+}
+
+/// Applies the edits to the cpu copy of the buffer and writes only the edited instances,
+/// the buffer is recreated from the copy when it grows past its capacity
+fn write_sparse_instances(
+    cached: &mut CachedChunkInstancing,
+    length: usize,
+    writes: Vec<(usize, Vec<u8>)>,
+    render_device: &RenderDevice,
+    render_queue: &RenderQueue,
+) {
+    let stride = cached.stride();
+    cached.uploaded_instances.resize(length * stride, 0);
+    for (index, data) in writes.iter() {
+        let start = index * stride;
+        cached.uploaded_instances[start..start + data.len()].copy_from_slice(data);
+    }
+
+    match &cached.instance_buffer {
+        Some(buffer) if cached.uploaded_instances.len() <= cached.capacity => {
+            for (index, data) in writes.iter() {
+                render_queue.write_buffer(buffer, (index * stride) as u64, data);
+            }
+        }
+        //Instances are usually pushed one by one, leave room for the next ones
+        _ if !cached.uploaded_instances.is_empty() => {
+            let capacity = cached.uploaded_instances.len() * 3 / 2;
+            let buffer = render_device.create_buffer(&BufferDescriptor {
+                label: Some("instance data buffer"),
+                size: capacity as u64,
+                usage: BufferUsages::VERTEX | BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            });
+            render_queue.write_buffer(&buffer, 0, &cached.uploaded_instances);
+            cached.instance_buffer = Some(buffer);
+            cached.capacity = capacity;
+        }
+        _ => {
+            cached.instance_buffer = None;
+            cached.capacity = 0;
+        }
+    }
+    cached.length = length;
+}
 
 fn prepare_grass_chunk_bind_group(
-    mut commands: Commands,
-    query: Query<(Entity, &GpuChunkBindGroupData)>,
+    mut cache: ResMut<ChunkInstancingCache>,
+    chunks_in_range: Query<&ChunkInRange, With<ExtractedChunkInstancing>>,
     render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
     custom_pipeline: Res<CustomPipeline>,
 ) {
-    for (entity, gpu_chunk) in &query {
-        let chunk_buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
-            label: Some("Chunk_instancing_buffer"),
-            contents: bytemuck::cast_slice(&[gpu_chunk.model_transform]),
-            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
-        });
+    for (entity, cached) in cache.chunks.iter_mut() {
+        let written = match cached.pending_chunk_data.take() {
+            Some(gpu_chunk) => {
+                cached.chunk_data = gpu_chunk;
+                match &cached.chunk_buffer {
+                    Some(chunk_buffer) => {
+                        render_queue.write_buffer(chunk_buffer, 0, bytemuck::cast_slice(&[gpu_chunk]))
+                    }
+                    None => {
+                        let (chunk_buffer, chunk_bind_group) =
+                            create_chunk_bind_group(&render_device, &custom_pipeline, gpu_chunk);
+                        cached.chunk_buffer = Some(chunk_buffer);
+                        cached.chunk_bind_group = Some(chunk_bind_group);
+                    }
+                }
+                true
+            }
+            None => false,
+        };
 
-        let chunk_instancing_bind_group = render_device.create_bind_group(&BindGroupDescriptor {
-            label: Some("Chunk_instancing_bindgroup"),
-            layout: &custom_pipeline.chunk_instancing_bind_group_layout,
-            entries: &[BindGroupEntry {
-                binding: 0,
-                resource: chunk_buffer.as_entire_binding(),
-            }],
-        });
-        commands
-            .entity(entity)
-            .insert(ChunkInstancingBindGroup(chunk_instancing_bind_group));
+        //The fade changes while a camera moves through the fade band, only those uniforms are rewritten then
+        let faded_views: Vec<(Entity, f32)> = chunks_in_range
+            .get(*entity)
+            .map(|in_range| {
+                in_range
+                    .cameras
+                    .iter()
+                    .copied()
+                    .filter(|(_, fade)| *fade < 1.0)
+                    .collect()
+            })
+            .unwrap_or_default();
+        cached
+            .faded_bind_groups
+            .retain(|view, _| faded_views.iter().any(|(camera, _)| camera == view));
+        for (view, fade) in faded_views {
+            let gpu_chunk = GpuChunkBindGroupData {
+                fade: [fade, 0.0, 0.0, 0.0],
+                ..cached.chunk_data
+            };
+            match cached.faded_bind_groups.get_mut(&view) {
+                Some(faded) if faded.fade == fade && !written => {}
+                Some(faded) => {
+                    render_queue.write_buffer(&faded.buffer, 0, bytemuck::cast_slice(&[gpu_chunk]));
+                    faded.fade = fade;
+                }
+                None => {
+                    let (buffer, bind_group) =
+                        create_chunk_bind_group(&render_device, &custom_pipeline, gpu_chunk);
+                    cached.faded_bind_groups.insert(
+                        view,
+                        FadedBindGroup {
+                            fade,
+                            buffer,
+                            bind_group,
+                        },
+                    );
+                }
+            }
+        }
     }
 }
 
-#[derive(Component)]
-pub struct TextureBindGroup(BindGroup);
+fn create_chunk_bind_group(
+    render_device: &RenderDevice,
+    custom_pipeline: &CustomPipeline,
+    gpu_chunk: GpuChunkBindGroupData,
+) -> (Buffer, BindGroup) {
+    let chunk_buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
+        label: Some("Chunk_instancing_buffer"),
+        contents: bytemuck::cast_slice(&[gpu_chunk]),
+        usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+    });
+
+    let chunk_instancing_bind_group = render_device.create_bind_group(&BindGroupDescriptor {
+        label: Some("Chunk_instancing_bindgroup"),
+        layout: &custom_pipeline.chunk_instancing_bind_group_layout,
+        entries: &[BindGroupEntry {
+            binding: 0,
+            resource: chunk_buffer.as_entire_binding(),
+        }],
+    });
+    (chunk_buffer, chunk_instancing_bind_group)
+}
 
 pub fn prepare_textures_bind_group(
     render_device: Res<RenderDevice>,
-    mut commands: Commands,
+    mut cache: ResMut<ChunkInstancingCache>,
     custom_pipeline: Res<CustomPipeline>,
-    image_query: Query<(Entity, &Handle<Image>), With<GpuInstances>>,
     gpu_images: Res<RenderAssets<Image>>,
 ) {
-    for (e, texture_handle) in image_query.iter() {
-        let gpu_image = gpu_images.get(texture_handle).unwrap();
+    for cached in cache.chunks.values_mut() {
+        if cached.texture_bind_group.is_some() || cached.material.is_some() {
+            continue;
+        }
+        //Texture might still be loading, try again next frame
+        let gpu_image = match gpu_images.get(&cached.texture) {
+            Some(gpu_image) => gpu_image,
+            None => continue,
+        };
 
+        //Always view the texture as an array so single textures and texture arrays share one pipeline
+        let texture_view = gpu_image.texture.create_view(&TextureViewDescriptor {
+            label: Some("chunk_instancing_texture_array_view"),
+            dimension: Some(TextureViewDimension::D2Array),
+            ..default()
+        });
         let texture_bind_group = render_device.create_bind_group(&BindGroupDescriptor {
             layout: &custom_pipeline.texture_bind_group_layout,
             entries: &[
                 BindGroupEntry {
                     binding: 0,
-                    resource: BindingResource::TextureView(&gpu_image.texture_view),
+                    resource: BindingResource::TextureView(&texture_view),
                 },
                 BindGroupEntry {
                     binding: 1,
@@ -285,9 +1248,8 @@ pub fn prepare_textures_bind_group(
             ],
             label: Some("growth_texture_bind_group"),
         });
-        commands
-            .entity(e)
-            .insert(TextureBindGroup(texture_bind_group));
+        cached.texture_view = Some(texture_view);
+        cached.texture_bind_group = Some(texture_bind_group);
     }
 }
 
@@ -307,42 +1269,107 @@ pub fn prepare_textures_bind_group(
 
 #[allow(clippy::too_many_arguments)]
 fn queue_custom(
+    opaque_3d_draw_functions: Res<DrawFunctions<Opaque3d>>,
+    alpha_mask_3d_draw_functions: Res<DrawFunctions<AlphaMask3d>>,
     transparent_3d_draw_functions: Res<DrawFunctions<Transparent3d>>,
     custom_pipeline: Res<CustomPipeline>,
     msaa: Res<Msaa>,
     mut pipelines: ResMut<SpecializedMeshPipelines<CustomPipeline>>,
     mut pipeline_cache: ResMut<PipelineCache>,
     meshes: Res<RenderAssets<Mesh>>,
-    material_meshes: Query<
-        (Entity, &MeshUniform, &Handle<Mesh>, &Handle<Image>),
-        With<GpuInstances>,
-    >,
-    mut views: Query<(&ExtractedView, &mut RenderPhase<Transparent3d>)>,
-    gpu_images: Res<RenderAssets<Image>>,
+    render_materials: Res<RenderMaterials<StandardMaterial>>,
+    material_meshes: Query<(
+        Entity,
+        &MeshUniform,
+        &Handle<Mesh>,
+        &ExtractedChunkInstancing,
+        Option<&ChunkInRange>,
+    )>,
+    mut views: Query<(
+        Entity,
+        &ExtractedView,
+        &mut RenderPhase<Opaque3d>,
+        &mut RenderPhase<AlphaMask3d>,
+        &mut RenderPhase<Transparent3d>,
+    )>,
+    cache: Res<ChunkInstancingCache>,
 ) {
-    let draw_custom = transparent_3d_draw_functions
+    let draw_opaque = opaque_3d_draw_functions.read().get_id::<DrawCustom>().unwrap();
+    let draw_alpha_mask = alpha_mask_3d_draw_functions
+        .read()
+        .get_id::<DrawCustom>()
+        .unwrap();
+    let draw_transparent = transparent_3d_draw_functions
         .read()
         .get_id::<DrawCustom>()
         .unwrap();
 
     let msaa_key = MeshPipelineKey::from_msaa_samples(msaa.samples);
 
-    for (view, mut transparent_phase) in &mut views {
+    for (view_entity, view, mut opaque_phase, mut alpha_mask_phase, mut transparent_phase) in
+        &mut views
+    {
         let rangefinder = view.rangefinder3d();
-        for (entity, mesh_uniform, mesh_handle, image_handle) in &material_meshes {
-            if let (Some(mesh), Some(_)) = (meshes.get(mesh_handle), gpu_images.get(image_handle))
-            {
-                let key =
+        let view_position = view.transform.translation();
+        for (entity, mesh_uniform, mesh_handle, extracted, in_range) in &material_meshes {
+            if !ChunkInRange::is_in_range(in_range, view_entity) {
+                continue;
+            }
+            let cached = match cache.get_ready(entity) {
+                Some(cached) => cached,
+                None => continue,
+            };
+            let distance = view_position.distance(extracted.center);
+            if cached.uses_impostor(distance) {
+                continue;
+            }
+            let material_key = match &cached.material {
+                Some(material) => match render_materials.get(material) {
+                    Some(prepared) => Some(prepared.key.clone()),
+                    None => continue, //Material textures still loading
+                },
+                None => None,
+            };
+            let mesh_handle = cached.lod_mesh(mesh_handle, distance);
+            if let Some(mesh) = meshes.get(mesh_handle) {
+                let mut mesh_key =
                     msaa_key | MeshPipelineKey::from_primitive_topology(mesh.primitive_topology);
+                if cached.alpha_mode == AlphaModeKey::Blend {
+                    mesh_key |= MeshPipelineKey::TRANSPARENT_MAIN_PASS;
+                }
+                let key = ChunkInstancingPipelineKey {
+                    mesh_key,
+                    instance_format: cached.instance_format,
+                    instance_variation: cached.instance_variation,
+                    material_key,
+                    alpha_mode: cached.alpha_mode,
+                    depth_only: false,
+                    dither_depth: false,
+                };
                 let pipeline = pipelines
                     .specialize(&mut pipeline_cache, &custom_pipeline, key, &mesh.layout)
                     .unwrap();
-                transparent_phase.add(Transparent3d {
-                    entity,
-                    pipeline,
-                    draw_function: draw_custom,
-                    distance: rangefinder.distance(&mesh_uniform.transform),
-                });
+                let distance = rangefinder.distance(&mesh_uniform.transform);
+                match cached.alpha_mode {
+                    AlphaModeKey::Opaque => opaque_phase.add(Opaque3d {
+                        entity,
+                        pipeline,
+                        draw_function: draw_opaque,
+                        distance,
+                    }),
+                    AlphaModeKey::Mask => alpha_mask_phase.add(AlphaMask3d {
+                        entity,
+                        pipeline,
+                        draw_function: draw_alpha_mask,
+                        distance,
+                    }),
+                    AlphaModeKey::Blend => transparent_phase.add(Transparent3d {
+                        entity,
+                        pipeline,
+                        draw_function: draw_transparent,
+                        distance,
+                    }),
+                }
             }
         }
     }
@@ -362,10 +1389,104 @@ fn queue_custom(
 // █░░░░░░█████████░░░░░░░░░░█░░░░░░█████████░░░░░░░░░░░░░░█░░░░░░░░░░░░░░█░░░░░░░░░░█░░░░░░██████████░░░░░░█░░░░░░░░░░░░░░█
 // █████████████████████████████████████████████████████████████████████████████████████████████████████████████████████████
 
+#[allow(clippy::too_many_arguments)]
+fn queue_custom_shadows(
+    shadow_draw_functions: Res<DrawFunctions<Shadow>>,
+    custom_pipeline: Res<CustomPipeline>,
+    mut pipelines: ResMut<SpecializedMeshPipelines<CustomPipeline>>,
+    mut pipeline_cache: ResMut<PipelineCache>,
+    meshes: Res<RenderAssets<Mesh>>,
+    render_materials: Res<RenderMaterials<StandardMaterial>>,
+    casting_meshes: Query<
+        (&Handle<Mesh>, &ExtractedChunkInstancing, Option<&ChunkInRange>),
+        With<ShadowCaster>,
+    >,
+    view_lights: Query<(Entity, &ExtractedView, &ViewLightEntities)>,
+    mut view_light_shadow_phases: Query<(&LightEntity, &mut RenderPhase<Shadow>)>,
+    light_visible_entities: LightVisibleEntities,
+    cache: Res<ChunkInstancingCache>,
+) {
+    let draw_shadow = shadow_draw_functions
+        .read()
+        .get_id::<DrawCustomShadow>()
+        .unwrap();
+
+    //Shadow views belong to a camera view, chunks culled for that camera cast no shadows in it
+    //and the others use the lod that camera sees
+    for (view_entity, view, view_lights) in &view_lights {
+        let view_position = view.transform.translation();
+        for view_light_entity in view_lights.lights.iter().copied() {
+            let (light_entity, mut shadow_phase) =
+                view_light_shadow_phases.get_mut(view_light_entity).unwrap();
+            //Lights with shadows disabled have no visible entities
+            let visible_entities = match light_visible_entities.get(light_entity) {
+                Some(visible_entities) => visible_entities,
+                None => continue,
+            };
+            for entity in visible_entities.iter().copied() {
+                let (mesh_handle, extracted, fade, cached) =
+                    match (casting_meshes.get(entity), cache.get_ready(entity)) {
+                        (Ok((mesh_handle, extracted, in_range)), Some(cached)) => {
+                            match ChunkInRange::fade(in_range, view_entity) {
+                                Some(fade) => (mesh_handle, extracted, fade, cached),
+                                None => continue,
+                            }
+                        }
+                        _ => continue,
+                    };
+                //Alpha mask layers cut their shadows out like the main pass, everything else casts solid shadows
+                let (alpha_mode, material_key) = match (cached.alpha_mode, &cached.material) {
+                    (AlphaModeKey::Mask, Some(material)) => match render_materials.get(material) {
+                        Some(prepared) => (AlphaModeKey::Mask, Some(prepared.key.clone())),
+                        None => continue, //Material textures still loading
+                    },
+                    (AlphaModeKey::Mask, None) => (AlphaModeKey::Mask, None),
+                    _ => (AlphaModeKey::Opaque, None),
+                };
+                let mesh_handle = cached.lod_mesh(mesh_handle, view_position.distance(extracted.center));
+                if let Some(mesh) = meshes.get(mesh_handle) {
+                    let key = ChunkInstancingPipelineKey {
+                        mesh_key: MeshPipelineKey::from_primitive_topology(mesh.primitive_topology),
+                        instance_format: cached.instance_format,
+                        instance_variation: cached.instance_variation,
+                        material_key,
+                        alpha_mode,
+                        depth_only: true,
+                        //Shadows dither out with the chunk, fully visible chunks skip the fragment stage
+                        dither_depth: fade < 1.0,
+                    };
+                    let pipeline = match pipelines.specialize(
+                        &mut pipeline_cache,
+                        &custom_pipeline,
+                        key,
+                        &mesh.layout,
+                    ) {
+                        Ok(pipeline) => pipeline,
+                        Err(err) => {
+                            error!("{}", err);
+                            continue;
+                        }
+                    };
+                    shadow_phase.add(Shadow {
+                        draw_function: draw_shadow,
+                        pipeline,
+                        entity,
+                        distance: 0.0,
+                    });
+                }
+            }
+        }
+    }
+}
+
 pub struct CustomPipeline {
     shader: Handle<Shader>,
+    //Imported by path from chunk_instancing.wgsl, kept loaded so the pipeline cache can resolve it
+    _rng_shader: Handle<Shader>,
     mesh_pipeline: MeshPipeline,
-    chunk_instancing_bind_group_layout: BindGroupLayout,
+    material_pipeline: MaterialPipeline<StandardMaterial>,
+    shadow_view_layout: BindGroupLayout,
+    pub(crate) chunk_instancing_bind_group_layout: BindGroupLayout,
     texture_bind_group_layout: BindGroupLayout,
 }
 
@@ -379,7 +1500,7 @@ impl FromWorld for CustomPipeline {
             render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
                 entries: &[BindGroupLayoutEntry {
                     binding: 0,
-                    visibility: ShaderStages::VERTEX,
+                    visibility: ShaderStages::VERTEX_FRAGMENT,
                     ty: BindingType::Buffer {
                         ty: BufferBindingType::Uniform,
                         has_dynamic_offset: false, //size will not change
@@ -399,7 +1520,7 @@ impl FromWorld for CustomPipeline {
                         visibility: ShaderStages::FRAGMENT,
                         ty: BindingType::Texture {
                             multisampled: false,
-                            view_dimension: TextureViewDimension::D2,
+                            view_dimension: TextureViewDimension::D2Array,
                             sample_type: TextureSampleType::Float { filterable: true },
                         },
                         count: None,
@@ -417,44 +1538,113 @@ impl FromWorld for CustomPipeline {
         let asset_server = world.resource::<AssetServer>();
         asset_server.watch_for_changes().unwrap();
         let shader = asset_server.load("shaders/chunk_instancing.wgsl");
+        let rng_shader = asset_server.load("shaders/forest_rng.wgsl");
 
         let mesh_pipeline = world.resource::<MeshPipeline>();
+        let material_pipeline = world.resource::<MaterialPipeline<StandardMaterial>>();
+        let shadow_pipeline = world.resource::<ShadowPipeline>();
 
         CustomPipeline {
             shader,
+            _rng_shader: rng_shader,
             mesh_pipeline: mesh_pipeline.clone(),
+            material_pipeline: material_pipeline.clone(),
+            shadow_view_layout: shadow_pipeline.view_layout.clone(),
             chunk_instancing_bind_group_layout,
             texture_bind_group_layout,
         }
     }
 }
 
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct ChunkInstancingPipelineKey {
+    pub mesh_key: MeshPipelineKey,
+    pub instance_format: InstanceFormat,
+    pub instance_variation: bool,
+    /// Some when drawn with a StandardMaterial instead of the base color texture
+    pub material_key: Option<StandardMaterialKey>,
+    pub alpha_mode: AlphaModeKey,
+    /// Vertex stage only, for the shadow passes
+    pub depth_only: bool,
+    /// Depth only pass of a chunk inside its fade band, discards the same pixels as the main pass
+    pub dither_depth: bool,
+}
+
 impl SpecializedMeshPipeline for CustomPipeline {
-    type Key = MeshPipelineKey;
+    type Key = ChunkInstancingPipelineKey;
 
     fn specialize(
         &self,
         key: Self::Key,
         layout: &MeshVertexBufferLayout,
     ) -> Result<RenderPipelineDescriptor, SpecializedMeshPipelineError> {
-        let mut descriptor = self.mesh_pipeline.specialize(key, layout)?;
+        let mut descriptor = self.mesh_pipeline.specialize(key.mesh_key, layout)?;
         descriptor.primitive.cull_mode = None; //For grass
         descriptor.vertex.shader = self.shader.clone();
-        descriptor.vertex.buffers.push(VertexBufferLayout {
-            array_stride: std::mem::size_of::<GpuInstance>() as u64,
-            step_mode: VertexStepMode::Instance,
-            attributes: vec![VertexAttribute {
-                format: VertexFormat::Float32x4,
-                offset: 0,
-                shader_location: 3, // shader locations 0-2 are taken up by Position, Normal and UV attributes
-            }],
-        });
+
+        let instance_layout = instance_vertex_buffer_layout(
+            key.instance_format,
+            key.instance_variation,
+            &mut descriptor.vertex.shader_defs,
+        );
+        descriptor.vertex.buffers.push(instance_layout);
         descriptor.fragment.as_mut().unwrap().shader = self.shader.clone();
+
+        if key.depth_only {
+            let mut bind_group_layouts = vec![
+                self.mesh_pipeline.view_layout.clone(),
+                self.mesh_pipeline.mesh_layout.clone(),
+                self.chunk_instancing_bind_group_layout.clone(),
+            ];
+            //Alpha mask layers sample their texture in the shadow pass to cut the shadow out
+            if key.alpha_mode == AlphaModeKey::Mask {
+                let fragment = descriptor.fragment.as_mut().unwrap();
+                fragment.shader_defs.push(String::from("ALPHA_MASK"));
+                bind_group_layouts.push(match key.material_key {
+                    Some(_) => {
+                        descriptor.vertex.shader_defs.push(String::from("STANDARD_MATERIAL"));
+                        fragment.shader_defs.push(String::from("STANDARD_MATERIAL"));
+                        self.material_pipeline.material_layout.clone()
+                    }
+                    None => self.texture_bind_group_layout.clone(),
+                });
+            }
+            let depth_fragment = (key.alpha_mode == AlphaModeKey::Mask || key.dither_depth)
+                .then_some("fragment_depth");
+            descriptor.layout = Some(bind_group_layouts);
+            make_depth_only(&mut descriptor, &self.shadow_view_layout, depth_fragment);
+            return Ok(descriptor);
+        }
+
+        match key.alpha_mode {
+            AlphaModeKey::Opaque => {}
+            AlphaModeKey::Mask => descriptor.fragment.as_mut().unwrap().shader_defs.push(String::from("ALPHA_MASK")),
+            AlphaModeKey::Blend => descriptor.fragment.as_mut().unwrap().shader_defs.push(String::from("ALPHA_BLEND")),
+        }
+
+        let texture_layout = match key.material_key {
+            Some(material_key) => {
+                descriptor.vertex.shader_defs.push(String::from("STANDARD_MATERIAL"));
+                descriptor.fragment.as_mut().unwrap().shader_defs.push(String::from("STANDARD_MATERIAL"));
+                //Normal map defines and cull mode, same as a PbrBundle with this material
+                StandardMaterial::specialize(
+                    &self.material_pipeline,
+                    &mut descriptor,
+                    layout,
+                    MaterialPipelineKey {
+                        mesh_key: key.mesh_key,
+                        bind_group_data: material_key,
+                    },
+                )?;
+                self.material_pipeline.material_layout.clone()
+            }
+            None => self.texture_bind_group_layout.clone(),
+        };
         descriptor.layout = Some(vec![
             self.mesh_pipeline.view_layout.clone(),
             self.mesh_pipeline.mesh_layout.clone(),
             self.chunk_instancing_bind_group_layout.clone(),
-            self.texture_bind_group_layout.clone(),
+            texture_layout,
         ]);
 
         Ok(descriptor)
@@ -484,38 +1674,91 @@ type DrawCustom = (
     DrawMeshInstanced,
 );
 
+type DrawCustomShadow = (
+    SetItemPipeline,
+    SetShadowViewBindGroup<0>,
+    SetMeshBindGroup<1>,
+    SetChunkInstancingBindGroup<2>,
+    SetShadowTextureBindGroup<3>,
+    DrawMeshInstancedShadow,
+);
+
+/// Binds the StandardMaterial of the chunk if it has one, otherwise the base color texture
 pub struct SetTextureBindGroup<const I: usize>;
 impl<const I: usize> EntityRenderCommand for SetTextureBindGroup<I> {
-    type Param = SQuery<Read<TextureBindGroup>>;
+    type Param = (
+        SRes<ChunkInstancingCache>,
+        SRes<RenderMaterials<StandardMaterial>>,
+    );
     #[inline]
     fn render<'w>(
         _view: Entity,
         item: Entity,
-        bind_group_query: SystemParamItem<'w, '_, Self::Param>,
+        (cache, render_materials): SystemParamItem<'w, '_, Self::Param>,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        let cached = match cache.into_inner().get_ready(item) {
+            Some(cached) => cached,
+            None => return RenderCommandResult::Failure,
+        };
+        let bind_group = match &cached.material {
+            Some(material) => render_materials
+                .into_inner()
+                .get(material)
+                .map(|prepared| &prepared.bind_group),
+            None => cached.texture_bind_group.as_ref(),
+        };
+        match bind_group {
+            Some(bind_group) => {
+                pass.set_bind_group(I, bind_group, &[]);
+                RenderCommandResult::Success
+            }
+            None => RenderCommandResult::Failure,
+        }
+    }
+}
+
+/// Only alpha mask layers sample their texture in the shadow passes, the other shadow pipelines have no group for it
+pub struct SetShadowTextureBindGroup<const I: usize>;
+impl<const I: usize> EntityRenderCommand for SetShadowTextureBindGroup<I> {
+    type Param = <SetTextureBindGroup<I> as EntityRenderCommand>::Param;
+    #[inline]
+    fn render<'w>(
+        view: Entity,
+        item: Entity,
+        param: SystemParamItem<'w, '_, Self::Param>,
         pass: &mut TrackedRenderPass<'w>,
     ) -> RenderCommandResult {
-        if let Ok(bind_group) = bind_group_query.get_inner(item) {
-            pass.set_bind_group(I, &bind_group.0, &[]);
+        let alpha_mask = param
+            .0
+            .get_ready(item)
+            .map_or(false, |cached| cached.alpha_mode == AlphaModeKey::Mask);
+        match alpha_mask {
+            true => SetTextureBindGroup::<I>::render(view, item, param, pass),
+            false => RenderCommandResult::Success,
         }
-        RenderCommandResult::Success
     }
 }
 
+/// Binds the chunk uniform with the fade of the view, shadow views use the fade of their camera
 pub struct SetChunkInstancingBindGroup<const I: usize>;
 impl<const I: usize> EntityRenderCommand for SetChunkInstancingBindGroup<I> {
-    type Param = SQuery<Read<ChunkInstancingBindGroup>>;
+    type Param = (SRes<ChunkInstancingCache>, SRes<ShadowViewOwners>);
     #[inline]
     fn render<'w>(
-        _view: Entity,
+        view: Entity,
         item: Entity,
-        bind_group_query: SystemParamItem<'w, '_, Self::Param>,
+        (cache, shadow_view_owners): SystemParamItem<'w, '_, Self::Param>,
         pass: &mut TrackedRenderPass<'w>,
     ) -> RenderCommandResult {
-        if let Ok(bind_group) = bind_group_query.get_inner(item) {
-            pass.set_bind_group(I, &bind_group.0, &[]);
+        match cache.into_inner().get_ready(item) {
+            Some(cached) => {
+                let view = shadow_view_owners.camera_view(view);
+                pass.set_bind_group(I, cached.chunk_bind_group(view), &[]);
+                RenderCommandResult::Success
+            }
+            None => RenderCommandResult::Failure,
         }
-
-        RenderCommandResult::Success
     }
 }
 
@@ -524,46 +1767,91 @@ pub struct DrawMeshInstanced;
 impl EntityRenderCommand for DrawMeshInstanced {
     type Param = (
         SRes<RenderAssets<Mesh>>,
-        SQuery<Read<Handle<Mesh>>>,
-        SQuery<Read<ChunkInstancingInstanceBuffer>>,
+        SQuery<(Read<Handle<Mesh>>, Read<ExtractedChunkInstancing>)>,
+        SQuery<Read<ExtractedView>>,
+        SRes<ChunkInstancingCache>,
     );
     #[inline]
     fn render<'w>(
-        _view: Entity,
+        view: Entity,
         item: Entity,
-        (meshes, mesh_query, chunk_instancing_instance_buffer_query): SystemParamItem<
-            'w,
-            '_,
-            Self::Param,
-        >,
+        (meshes, mesh_query, view_query, cache): SystemParamItem<'w, '_, Self::Param>,
         pass: &mut TrackedRenderPass<'w>,
     ) -> RenderCommandResult {
-        let mesh_handle = mesh_query.get(item).unwrap();
-        let instance_buffer = chunk_instancing_instance_buffer_query
-            .get_inner(item)
-            .unwrap();
+        let (mesh_handle, extracted) = mesh_query.get(item).unwrap();
+        let cached = match cache.into_inner().get_ready(item) {
+            Some(cached) => cached,
+            None => return RenderCommandResult::Failure,
+        };
+        //Same lod as picked in queue_custom
+        let view_position = view_query.get(view).unwrap().transform.translation();
+        let mesh_handle = cached.lod_mesh(mesh_handle, view_position.distance(extracted.center));
 
-        let gpu_mesh = match meshes.into_inner().get(mesh_handle) {
-            Some(gpu_mesh) => gpu_mesh,
+        match meshes.into_inner().get(mesh_handle) {
+            Some(gpu_mesh) => draw_instanced(pass, gpu_mesh, cached),
+            None => RenderCommandResult::Failure,
+        }
+    }
+}
+
+pub struct DrawMeshInstancedShadow;
+
+impl EntityRenderCommand for DrawMeshInstancedShadow {
+    type Param = (
+        SRes<RenderAssets<Mesh>>,
+        SQuery<(Read<Handle<Mesh>>, Read<ExtractedChunkInstancing>)>,
+        SQuery<Read<ExtractedView>>,
+        SRes<ShadowViewOwners>,
+        SRes<ChunkInstancingCache>,
+    );
+    #[inline]
+    fn render<'w>(
+        view: Entity,
+        item: Entity,
+        (meshes, mesh_query, view_query, shadow_view_owners, cache): SystemParamItem<'w, '_, Self::Param>,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        let (mesh_handle, extracted) = mesh_query.get(item).unwrap();
+        let cached = match cache.into_inner().get_ready(item) {
+            Some(cached) => cached,
             None => return RenderCommandResult::Failure,
         };
+        //Same lod as picked in queue_custom_shadows, from the camera the shadow view belongs to
+        let camera = shadow_view_owners.camera_view(view);
+        let view_position = view_query.get(camera).unwrap().transform.translation();
+        let mesh_handle = cached.lod_mesh(mesh_handle, view_position.distance(extracted.center));
 
-        pass.set_vertex_buffer(0, gpu_mesh.vertex_buffer.slice(..));
-        pass.set_vertex_buffer(1, instance_buffer.buffer.slice(..));
-
-        match &gpu_mesh.buffer_info {
-            GpuBufferInfo::Indexed {
-                buffer,
-                index_format,
-                count,
-            } => {
-                pass.set_index_buffer(buffer.slice(..), 0, *index_format);
-                pass.draw_indexed(0..*count, 0, 0..instance_buffer.length as u32);
-            }
-            GpuBufferInfo::NonIndexed { vertex_count } => {
-                pass.draw(0..*vertex_count, 0..instance_buffer.length as u32);
-            }
+        match meshes.into_inner().get(mesh_handle) {
+            Some(gpu_mesh) => draw_instanced(pass, gpu_mesh, cached),
+            None => RenderCommandResult::Failure,
+        }
+    }
+}
+
+fn draw_instanced<'w>(
+    pass: &mut TrackedRenderPass<'w>,
+    gpu_mesh: &'w GpuMesh,
+    cached: &'w CachedChunkInstancing,
+) -> RenderCommandResult {
+    pass.set_vertex_buffer(0, gpu_mesh.vertex_buffer.slice(..));
+    let instance_bytes = (cached.length * cached.stride()) as u64;
+    pass.set_vertex_buffer(
+        1,
+        cached.instance_buffer.as_ref().unwrap().slice(..instance_bytes),
+    );
+
+    match &gpu_mesh.buffer_info {
+        GpuBufferInfo::Indexed {
+            buffer,
+            index_format,
+            count,
+        } => {
+            pass.set_index_buffer(buffer.slice(..), 0, *index_format);
+            pass.draw_indexed(0..*count, 0, 0..cached.length as u32);
+        }
+        GpuBufferInfo::NonIndexed { vertex_count } => {
+            pass.draw(0..*vertex_count, 0..cached.length as u32);
         }
-        RenderCommandResult::Success
     }
+    RenderCommandResult::Success
 }
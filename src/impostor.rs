@@ -0,0 +1,676 @@
+use bevy::{
+    core_pipeline::core_3d::AlphaMask3d,
+    ecs::system::{lifetimeless::*, SystemParamItem, SystemState},
+    pbr::{MeshPipeline, MeshUniform, SetMeshBindGroup, SetMeshViewBindGroup},
+    prelude::*,
+    render::{
+        mesh::{Indices, VertexAttributeValues},
+        render_asset::RenderAssets,
+        render_phase::{
+            AddRenderCommand, DrawFunctions, EntityRenderCommand, RenderCommandResult, RenderPhase,
+            SetItemPipeline, TrackedRenderPass,
+        },
+        render_resource::*,
+        renderer::RenderDevice,
+        texture::BevyDefault,
+        view::{ExtractedView, Msaa},
+        RenderApp, RenderStage,
+    },
+};
+use bytemuck::{Pod, Zeroable};
+
+use super::{
+    chunk_instancing::{
+        instance_vertex_buffer_layout, ChunkInstancingCache, CustomPipeline,
+        ExtractedChunkInstancing, InstanceFormat, SetChunkInstancingBindGroup,
+    },
+    ChunkInRange,
+};
+
+/// Draws the far ring of ChunkInstancing layers that have an [`ImpostorLod`] as camera facing billboards.
+/// Add it after the ChunkInstancingPlugin, the impostors bind its chunk uniforms for the distance fade
+pub struct ImpostorPlugin;
+
+impl Plugin for ImpostorPlugin {
+    fn build(&self, app: &mut App) {
+        app.sub_app_mut(RenderApp)
+            .add_render_command::<AlphaMask3d, DrawImpostor>()
+            .init_resource::<ImpostorPipeline>()
+            .init_resource::<SpecializedRenderPipelines<ImpostorPipeline>>()
+            .add_system_to_stage(RenderStage::Prepare, prepare_impostor_bind_groups)
+            .add_system_to_stage(RenderStage::Queue, queue_impostors);
+    }
+}
+
+/// Impostor settings of a ChunkInstancing layer, usually made with [`ImpostorAtlas::into_lod`]
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImpostorLod {
+    pub atlas: Handle<Image>,
+    pub frames: u32,
+    pub columns: u32,
+    /// Bounds of the baked mesh (model_transform applied) in model space
+    pub half_width: f32,
+    pub min_y: f32,
+    pub max_y: f32,
+    /// Distance from the camera to the chunk center from which impostors are drawn instead of meshes
+    pub distance: f32,
+}
+
+// ████ Baking ████
+
+/// Output of [`ImpostorBaker::bake`], `frames` views around the y axis laid out in a grid of `columns`.
+/// The image has the format of the baked texture, texels are copied without converting them
+pub struct ImpostorAtlas {
+    pub image: Image,
+    pub frames: u32,
+    pub columns: u32,
+    pub half_width: f32,
+    pub min_y: f32,
+    pub max_y: f32,
+}
+
+impl ImpostorAtlas {
+    pub fn into_lod(self, images: &mut Assets<Image>, distance: f32) -> ImpostorLod {
+        ImpostorLod {
+            frames: self.frames,
+            columns: self.columns,
+            half_width: self.half_width,
+            min_y: self.min_y,
+            max_y: self.max_y,
+            atlas: images.add(self.image),
+            distance,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ImpostorBakeError {
+    MissingAttribute(&'static str),
+    UnsupportedTopology(PrimitiveTopology),
+    UnsupportedTextureFormat(TextureFormat),
+    EmptyMesh,
+    EmptyTexture,
+    NoFrames,
+    EmptyTile,
+}
+
+impl std::fmt::Display for ImpostorBakeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImpostorBakeError::MissingAttribute(name) => write!(f, "mesh is missing {name}"),
+            ImpostorBakeError::UnsupportedTopology(topology) => {
+                write!(f, "only triangle lists can be baked, got {topology:?}")
+            }
+            ImpostorBakeError::UnsupportedTextureFormat(format) => {
+                write!(f, "only rgba8 textures can be baked, got {format:?}")
+            }
+            ImpostorBakeError::EmptyMesh => write!(f, "mesh has no triangles"),
+            ImpostorBakeError::EmptyTexture => write!(f, "texture has no texels"),
+            ImpostorBakeError::NoFrames => write!(f, "at least one frame is needed"),
+            ImpostorBakeError::EmptyTile => write!(f, "tile size must be at least 1"),
+        }
+    }
+}
+
+impl std::error::Error for ImpostorBakeError {}
+
+/// Renders a mesh and its base color texture from `frames` angles around the y axis into an atlas.
+/// Runs on the cpu with a software rasterizer so it also works headless, e.g. in an asset pipeline.
+pub struct ImpostorBaker {
+    pub frames: u32,
+    pub tile_size: u32,
+    /// Same as `ChunkInstancing::model_transform` of the layer using the impostor
+    pub model_transform: Transform,
+}
+
+impl Default for ImpostorBaker {
+    fn default() -> Self {
+        Self {
+            frames: 8,
+            tile_size: 128,
+            model_transform: Transform::identity(),
+        }
+    }
+}
+
+impl ImpostorBaker {
+    pub fn bake(&self, mesh: &Mesh, base_color_texture: &Image) -> Result<ImpostorAtlas, ImpostorBakeError> {
+        if self.frames == 0 {
+            return Err(ImpostorBakeError::NoFrames);
+        }
+        if self.tile_size == 0 {
+            return Err(ImpostorBakeError::EmptyTile);
+        }
+        if mesh.primitive_topology() != PrimitiveTopology::TriangleList {
+            return Err(ImpostorBakeError::UnsupportedTopology(mesh.primitive_topology()));
+        }
+        let positions = match mesh.attribute(Mesh::ATTRIBUTE_POSITION) {
+            Some(VertexAttributeValues::Float32x3(positions)) => positions,
+            _ => return Err(ImpostorBakeError::MissingAttribute("positions")),
+        };
+        let uvs = match mesh.attribute(Mesh::ATTRIBUTE_UV_0) {
+            Some(VertexAttributeValues::Float32x2(uvs)) => uvs,
+            _ => return Err(ImpostorBakeError::MissingAttribute("uvs")),
+        };
+        let indices: Vec<u32> = match mesh.indices() {
+            Some(Indices::U16(indices)) => indices.iter().map(|i| *i as u32).collect(),
+            Some(Indices::U32(indices)) => indices.clone(),
+            None => (0..positions.len() as u32).collect(),
+        };
+        if indices.len() < 3 {
+            return Err(ImpostorBakeError::EmptyMesh);
+        }
+
+        let format = base_color_texture.texture_descriptor.format;
+        if !matches!(format, TextureFormat::Rgba8UnormSrgb | TextureFormat::Rgba8Unorm) {
+            return Err(ImpostorBakeError::UnsupportedTextureFormat(format));
+        }
+        let size = base_color_texture.texture_descriptor.size;
+        if size.width == 0 || size.height == 0 {
+            return Err(ImpostorBakeError::EmptyTexture);
+        }
+        let texture = BakeTexture {
+            data: &base_color_texture.data,
+            width: size.width,
+            height: size.height,
+        };
+
+        let model = self.model_transform.compute_matrix();
+        let positions: Vec<Vec3> = positions
+            .iter()
+            .map(|p| model.transform_point3(Vec3::from(*p)))
+            .collect();
+        let uvs: Vec<Vec2> = uvs.iter().map(|uv| Vec2::from(*uv)).collect();
+
+        let baked = bake_frames(&positions, &uvs, &indices, &texture, self.frames, self.tile_size);
+        let columns = atlas_columns(self.frames);
+        let rows = (self.frames + columns - 1) / columns;
+
+        Ok(ImpostorAtlas {
+            image: Image::new(
+                Extent3d {
+                    width: columns * self.tile_size,
+                    height: rows * self.tile_size,
+                    depth_or_array_layers: 1,
+                },
+                TextureDimension::D2,
+                baked.data,
+                format,
+            ),
+            frames: self.frames,
+            columns,
+            half_width: baked.half_width,
+            min_y: baked.min_y,
+            max_y: baked.max_y,
+        })
+    }
+}
+
+fn atlas_columns(frames: u32) -> u32 {
+    (frames as f32).sqrt().ceil().max(1.0) as u32
+}
+
+struct BakeTexture<'a> {
+    data: &'a [u8],
+    width: u32,
+    height: u32,
+}
+
+impl<'a> BakeTexture<'a> {
+    /// Nearest sample with repeat wrapping, transparent for empty textures
+    fn sample(&self, uv: Vec2) -> [u8; 4] {
+        if self.width == 0 || self.height == 0 {
+            return [0; 4];
+        }
+        let x = ((uv.x - uv.x.floor()) * self.width as f32) as u32;
+        let y = ((uv.y - uv.y.floor()) * self.height as f32) as u32;
+        let i = (y.min(self.height - 1) * self.width + x.min(self.width - 1)) as usize * 4;
+        [self.data[i], self.data[i + 1], self.data[i + 2], self.data[i + 3]]
+    }
+}
+
+struct BakedFrames {
+    data: Vec<u8>,
+    half_width: f32,
+    min_y: f32,
+    max_y: f32,
+}
+
+/// Orthographic views around the y axis, frame `i` is seen from the direction (sin(yaw), 0, cos(yaw)) with yaw = i/frames*TAU.
+/// impostor.wgsl picks frames with the same convention.
+fn bake_frames(
+    positions: &[Vec3],
+    uvs: &[Vec2],
+    indices: &[u32],
+    texture: &BakeTexture,
+    frames: u32,
+    tile_size: u32,
+) -> BakedFrames {
+    let half_width = positions
+        .iter()
+        .map(|p| Vec2::new(p.x, p.z).length())
+        .fold(f32::EPSILON, f32::max);
+    let min_y = positions.iter().map(|p| p.y).fold(f32::MAX, f32::min);
+    let max_y = positions.iter().map(|p| p.y).fold(f32::MIN, f32::max).max(min_y + f32::EPSILON);
+
+    let columns = atlas_columns(frames);
+    let rows = (frames + columns - 1) / columns;
+    let atlas_width = columns * tile_size;
+    let mut data = vec![0u8; (atlas_width * rows * tile_size * 4) as usize];
+    let mut depth = vec![f32::MIN; (tile_size * tile_size) as usize];
+
+    for frame in 0..frames {
+        let yaw = frame as f32 / frames as f32 * std::f32::consts::TAU;
+        let forward = Vec3::new(yaw.sin(), 0.0, yaw.cos()); //Towards the camera
+        let right = Vec3::Y.cross(forward);
+        let tile_x = (frame % columns) * tile_size;
+        let tile_y = (frame / columns) * tile_size;
+        depth.iter_mut().for_each(|d| *d = f32::MIN);
+
+        //Pixel coordinates and depth (larger is closer to the camera)
+        let project = |p: Vec3| {
+            Vec3::new(
+                (p.dot(right) / half_width * 0.5 + 0.5) * tile_size as f32,
+                (1.0 - (p.y - min_y) / (max_y - min_y)) * tile_size as f32,
+                p.dot(forward),
+            )
+        };
+
+        for triangle in indices.chunks_exact(3) {
+            let [a, b, c] = [triangle[0] as usize, triangle[1] as usize, triangle[2] as usize];
+            let (pa, pb, pc) = (project(positions[a]), project(positions[b]), project(positions[c]));
+            let area = edge(pa, pb, pc);
+            if area.abs() <= f32::EPSILON {
+                continue;
+            }
+
+            let min_x = pa.x.min(pb.x).min(pc.x).floor().max(0.0) as u32;
+            let max_x = (pa.x.max(pb.x).max(pc.x).ceil() as u32).min(tile_size);
+            let min_y = pa.y.min(pb.y).min(pc.y).floor().max(0.0) as u32;
+            let max_y = (pa.y.max(pb.y).max(pc.y).ceil() as u32).min(tile_size);
+
+            for y in min_y..max_y {
+                for x in min_x..max_x {
+                    let p = Vec3::new(x as f32 + 0.5, y as f32 + 0.5, 0.0);
+                    //Barycentric weights, the division by area makes them positive for both windings
+                    let wa = edge(pb, pc, p) / area;
+                    let wb = edge(pc, pa, p) / area;
+                    let wc = edge(pa, pb, p) / area;
+                    if wa < 0.0 || wb < 0.0 || wc < 0.0 {
+                        continue;
+                    }
+                    let z = wa * pa.z + wb * pb.z + wc * pc.z;
+                    let depth_index = (y * tile_size + x) as usize;
+                    if z <= depth[depth_index] {
+                        continue;
+                    }
+                    let color = texture.sample(wa * uvs[a] + wb * uvs[b] + wc * uvs[c]);
+                    if color[3] < 128 {
+                        continue; //Cut out leaves
+                    }
+                    depth[depth_index] = z;
+                    let i = (((tile_y + y) * atlas_width + tile_x + x) * 4) as usize;
+                    data[i..i + 4].copy_from_slice(&color);
+                }
+            }
+        }
+    }
+
+    dilate_colors(&mut data, atlas_width, rows * tile_size);
+
+    BakedFrames {
+        data,
+        half_width,
+        min_y,
+        max_y,
+    }
+}
+
+fn edge(a: Vec3, b: Vec3, p: Vec3) -> f32 {
+    (b.x - a.x) * (p.y - a.y) - (b.y - a.y) * (p.x - a.x)
+}
+
+/// Copies colors into the transparent border around the silhouettes, otherwise texture filtering
+/// pulls in black from the empty texels
+fn dilate_colors(data: &mut [u8], width: u32, height: u32) {
+    for _ in 0..2 {
+        let source = data.to_vec();
+        for y in 0..height as i32 {
+            for x in 0..width as i32 {
+                let i = ((y as u32 * width + x as u32) * 4) as usize;
+                if source[i + 3] != 0 {
+                    continue;
+                }
+                let neighbour = [(-1, 0), (1, 0), (0, -1), (0, 1)]
+                    .iter()
+                    .map(|(dx, dy)| (x + dx, y + dy))
+                    .filter(|(nx, ny)| *nx >= 0 && *ny >= 0 && *nx < width as i32 && *ny < height as i32)
+                    .map(|(nx, ny)| ((ny as u32 * width + nx as u32) * 4) as usize)
+                    .find(|n| source[n + 3] != 0 || source[*n..n + 3] != [0, 0, 0]);
+                if let Some(n) = neighbour {
+                    data[i..i + 3].copy_from_slice(&source[n..n + 3]);
+                }
+            }
+        }
+    }
+}
+
+// ████ Rendering ████
+
+#[repr(C)]
+#[derive(Clone, Copy, Zeroable, Pod)]
+struct GpuImpostor {
+    frames: u32,
+    columns: u32,
+    rows: u32,
+    _padding: u32,
+    half_width: f32,
+    min_y: f32,
+    max_y: f32,
+    _padding_2: f32,
+}
+
+impl ImpostorLod {
+    fn to_raw(&self) -> GpuImpostor {
+        let columns = self.columns.max(1);
+        GpuImpostor {
+            frames: self.frames.max(1),
+            columns,
+            rows: (self.frames.max(1) + columns - 1) / columns,
+            _padding: 0,
+            half_width: self.half_width,
+            min_y: self.min_y,
+            max_y: self.max_y,
+            _padding_2: 0.0,
+        }
+    }
+}
+
+fn prepare_impostor_bind_groups(
+    render_device: Res<RenderDevice>,
+    mut cache: ResMut<ChunkInstancingCache>,
+    impostor_pipeline: Res<ImpostorPipeline>,
+    gpu_images: Res<RenderAssets<Image>>,
+) {
+    for cached in cache.chunks.values_mut() {
+        if cached.impostor_bind_group.is_some() {
+            continue;
+        }
+        let impostor = match &cached.impostor {
+            Some(impostor) => impostor,
+            None => continue,
+        };
+        //Atlas might still be loading, meshes are drawn until it is ready
+        let gpu_image = match gpu_images.get(&impostor.atlas) {
+            Some(gpu_image) => gpu_image,
+            None => continue,
+        };
+
+        let impostor_buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
+            label: Some("impostor_buffer"),
+            contents: bytemuck::cast_slice(&[impostor.to_raw()]),
+            usage: BufferUsages::UNIFORM,
+        });
+        cached.impostor_bind_group = Some(render_device.create_bind_group(&BindGroupDescriptor {
+            label: Some("impostor_bind_group"),
+            layout: &impostor_pipeline.impostor_bind_group_layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: impostor_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: BindingResource::TextureView(&gpu_image.texture_view),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: BindingResource::Sampler(&gpu_image.sampler),
+                },
+            ],
+        }));
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn queue_impostors(
+    alpha_mask_3d_draw_functions: Res<DrawFunctions<AlphaMask3d>>,
+    impostor_pipeline: Res<ImpostorPipeline>,
+    msaa: Res<Msaa>,
+    mut pipelines: ResMut<SpecializedRenderPipelines<ImpostorPipeline>>,
+    mut pipeline_cache: ResMut<PipelineCache>,
+    chunks: Query<(Entity, &MeshUniform, &ExtractedChunkInstancing, Option<&ChunkInRange>)>,
+    mut views: Query<(Entity, &ExtractedView, &mut RenderPhase<AlphaMask3d>)>,
+    cache: Res<ChunkInstancingCache>,
+) {
+    let draw_impostor = alpha_mask_3d_draw_functions
+        .read()
+        .get_id::<DrawImpostor>()
+        .unwrap();
+
+    for (view_entity, view, mut alpha_mask_phase) in &mut views {
+        let rangefinder = view.rangefinder3d();
+        let view_position = view.transform.translation();
+        for (entity, mesh_uniform, extracted, in_range) in &chunks {
+            if !ChunkInRange::is_in_range(in_range, view_entity) {
+                continue;
+            }
+            let cached = match cache.get_ready(entity) {
+                Some(cached) => cached,
+                None => continue,
+            };
+            if !cached.uses_impostor(view_position.distance(extracted.center)) {
+                continue;
+            }
+            let key = ImpostorPipelineKey {
+                msaa_samples: msaa.samples,
+                instance_format: cached.instance_format,
+                instance_variation: cached.instance_variation,
+            };
+            let pipeline = pipelines.specialize(&mut pipeline_cache, &impostor_pipeline, key);
+            alpha_mask_phase.add(AlphaMask3d {
+                entity,
+                pipeline,
+                draw_function: draw_impostor,
+                distance: rangefinder.distance(&mesh_uniform.transform),
+            });
+        }
+    }
+}
+
+pub struct ImpostorPipeline {
+    shader: Handle<Shader>,
+    //Imported by path from impostor.wgsl, kept loaded so the pipeline cache can resolve it
+    _rng_shader: Handle<Shader>,
+    mesh_pipeline: MeshPipeline,
+    impostor_bind_group_layout: BindGroupLayout,
+    chunk_instancing_bind_group_layout: BindGroupLayout,
+}
+
+impl FromWorld for ImpostorPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let mut system_state: SystemState<Res<RenderDevice>> = SystemState::new(world);
+        let render_device = system_state.get_mut(world);
+
+        let impostor_bind_group_layout =
+            render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                entries: &[
+                    BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: ShaderStages::VERTEX_FRAGMENT,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: ShaderStages::FRAGMENT,
+                        ty: BindingType::Texture {
+                            multisampled: false,
+                            view_dimension: TextureViewDimension::D2,
+                            sample_type: TextureSampleType::Float { filterable: true },
+                        },
+                        count: None,
+                    },
+                    BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: ShaderStages::FRAGMENT,
+                        ty: BindingType::Sampler(SamplerBindingType::Filtering),
+                        count: None,
+                    },
+                ],
+                label: Some("impostor_bind_group_layout"),
+            });
+
+        let asset_server = world.resource::<AssetServer>();
+        let shader = asset_server.load("shaders/impostor.wgsl");
+        let rng_shader = asset_server.load("shaders/forest_rng.wgsl");
+
+        let mesh_pipeline = world.resource::<MeshPipeline>();
+        //Same chunk uniform as the meshes, it has the fade of every view
+        let custom_pipeline = world.resource::<CustomPipeline>();
+
+        ImpostorPipeline {
+            shader,
+            _rng_shader: rng_shader,
+            mesh_pipeline: mesh_pipeline.clone(),
+            impostor_bind_group_layout,
+            chunk_instancing_bind_group_layout: custom_pipeline
+                .chunk_instancing_bind_group_layout
+                .clone(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ImpostorPipelineKey {
+    pub msaa_samples: u32,
+    pub instance_format: InstanceFormat,
+    pub instance_variation: bool,
+}
+
+impl SpecializedRenderPipeline for ImpostorPipeline {
+    type Key = ImpostorPipelineKey;
+
+    fn specialize(&self, key: Self::Key) -> RenderPipelineDescriptor {
+        let mut shader_defs = Vec::new();
+        let instance_layout = instance_vertex_buffer_layout(
+            key.instance_format,
+            key.instance_variation,
+            &mut shader_defs,
+        );
+
+        RenderPipelineDescriptor {
+            vertex: VertexState {
+                shader: self.shader.clone(),
+                entry_point: "vertex".into(),
+                shader_defs: shader_defs.clone(),
+                buffers: vec![instance_layout], //Quad corners come from the vertex index
+            },
+            fragment: Some(FragmentState {
+                shader: self.shader.clone(),
+                shader_defs,
+                entry_point: "fragment".into(),
+                targets: vec![Some(ColorTargetState {
+                    format: TextureFormat::bevy_default(),
+                    blend: Some(BlendState::REPLACE), //Cut out in the fragment shader
+                    write_mask: ColorWrites::ALL,
+                })],
+            }),
+            layout: Some(vec![
+                self.mesh_pipeline.view_layout.clone(),
+                self.mesh_pipeline.mesh_layout.clone(),
+                self.impostor_bind_group_layout.clone(),
+                self.chunk_instancing_bind_group_layout.clone(),
+            ]),
+            primitive: PrimitiveState {
+                topology: PrimitiveTopology::TriangleList,
+                cull_mode: None,
+                ..default()
+            },
+            depth_stencil: Some(DepthStencilState {
+                format: TextureFormat::Depth32Float,
+                depth_write_enabled: true,
+                depth_compare: CompareFunction::Greater,
+                stencil: StencilState {
+                    front: StencilFaceState::IGNORE,
+                    back: StencilFaceState::IGNORE,
+                    read_mask: 0,
+                    write_mask: 0,
+                },
+                bias: DepthBiasState {
+                    constant: 0,
+                    slope_scale: 0.0,
+                    clamp: 0.0,
+                },
+            }),
+            multisample: MultisampleState {
+                count: key.msaa_samples,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+            label: Some("impostor_pipeline".into()),
+        }
+    }
+}
+
+type DrawImpostor = (
+    SetItemPipeline,
+    SetMeshViewBindGroup<0>,
+    SetMeshBindGroup<1>,
+    SetImpostorBindGroup<2>,
+    SetChunkInstancingBindGroup<3>,
+    DrawImpostorInstanced,
+);
+
+pub struct SetImpostorBindGroup<const I: usize>;
+impl<const I: usize> EntityRenderCommand for SetImpostorBindGroup<I> {
+    type Param = SRes<ChunkInstancingCache>;
+    #[inline]
+    fn render<'w>(
+        _view: Entity,
+        item: Entity,
+        cache: SystemParamItem<'w, '_, Self::Param>,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        match cache
+            .into_inner()
+            .get_ready(item)
+            .and_then(|cached| cached.impostor_bind_group.as_ref())
+        {
+            Some(bind_group) => {
+                pass.set_bind_group(I, bind_group, &[]);
+                RenderCommandResult::Success
+            }
+            None => RenderCommandResult::Failure,
+        }
+    }
+}
+
+pub struct DrawImpostorInstanced;
+impl EntityRenderCommand for DrawImpostorInstanced {
+    type Param = SRes<ChunkInstancingCache>;
+    #[inline]
+    fn render<'w>(
+        _view: Entity,
+        item: Entity,
+        cache: SystemParamItem<'w, '_, Self::Param>,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        let cached = match cache.into_inner().get_ready(item) {
+            Some(cached) => cached,
+            None => return RenderCommandResult::Failure,
+        };
+        let instance_bytes = (cached.length * cached.stride()) as u64;
+        pass.set_vertex_buffer(
+            0,
+            cached.instance_buffer.as_ref().unwrap().slice(..instance_bytes),
+        );
+        pass.draw(0..6, 0..cached.length as u32);
+        RenderCommandResult::Success
+    }
+}
+
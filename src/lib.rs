@@ -1,10 +1,31 @@
 #![allow(clippy::type_complexity)]
 
-use bevy::prelude::*;
+use bevy::{
+    core_pipeline::core_3d::{AlphaMask3d, Opaque3d, Transparent3d},
+    pbr::Shadow,
+    prelude::*,
+    render::{
+        primitives::Aabb,
+        render_phase::{EntityPhaseItem, RenderPhase},
+        Extract, RenderApp, RenderStage,
+    },
+    utils::HashMap,
+};
 use bevy_inspector_egui::{Inspectable, RegisterInspectable};
+use std::sync::{Arc, Mutex};
+
+use shadow::ShadowViewOwners;
 
 pub mod chunk_grass;
 pub mod chunk_instancing;
+pub mod impostor;
+pub mod navigation;
+pub mod promotion;
+pub mod rng;
+pub mod scatter;
+mod shadow;
+pub mod spatial;
+pub mod terrain;
 
 pub struct ForestRenderingPlugin;
 
@@ -13,17 +34,352 @@ impl Plugin for ForestRenderingPlugin {
         app
             .add_plugin(chunk_grass::ChunkGrassPlugin)
             .add_plugin(chunk_instancing::ChunkInstancingPlugin)
-            .register_inspectable::<DistanceCulling>();
+            .add_plugin(impostor::ImpostorPlugin)
+            .add_plugin(terrain::TerrainPlugin)
+            .add_plugin(spatial::SpatialQueryPlugin)
+            .add_plugin(navigation::NavigationPlugin)
+            .add_plugin(shadow::ShadowViewPlugin)
+            .add_plugin(DistanceCullingPlugin);
     }
 }
 
-#[derive(Component, Inspectable, Debug)]
+/// Decides per camera in the render world which `DistanceCulling` chunks are in range, so every view only queues its own chunks.
+/// [`ChunkEnteredRange`] and [`ChunkLeftRange`] are sent back to the main world and arrive one frame later
+pub struct DistanceCullingPlugin;
+
+impl Plugin for DistanceCullingPlugin {
+    fn build(&self, app: &mut App) {
+        let range_events = RangeEvents::default();
+        app.init_resource::<DistanceCullingSettings>()
+            .insert_resource(range_events.clone())
+            .add_event::<ChunkEnteredRange>()
+            .add_event::<ChunkLeftRange>()
+            .add_system_to_stage(CoreStage::First, send_range_events)
+            .register_inspectable::<DistanceCulling>()
+            .register_inspectable::<ViewDistanceCulling>();
+
+        app.sub_app_mut(RenderApp)
+            .insert_resource(range_events)
+            .init_resource::<ChunkRanges>()
+            .add_system_to_stage(RenderStage::Extract, extract_chunk_in_range)
+            .add_system_to_stage(RenderStage::PhaseSort, cull_phase_items::<Opaque3d>)
+            .add_system_to_stage(RenderStage::PhaseSort, cull_phase_items::<AlphaMask3d>)
+            .add_system_to_stage(RenderStage::PhaseSort, cull_phase_items::<Transparent3d>)
+            .add_system_to_stage(RenderStage::PhaseSort, cull_phase_items::<Shadow>);
+    }
+}
+
+/// Chunks further away from a camera than `distance` are not drawn by that camera.
+/// The distance is measured to the closest point of the chunk's `Aabb`
+#[derive(Component, Inspectable, Clone, Copy, Debug)]
 pub struct DistanceCulling {
     pub distance: f32,
+    /// Width of the band before `distance` in which the chunk dithers out, 0 pops it.
+    /// Chunks drawn by bevy's own pipelines (e.g. the terrain) can't dither and pop at the end of the band
+    pub fade_band: f32,
 }
 
 impl Default for DistanceCulling {
     fn default() -> Self {
-        Self { distance: 1000.0 }
+        Self {
+            distance: 1000.0,
+            fade_band: 20.0,
+        }
+    }
+}
+
+impl DistanceCulling {
+    /// 1 inside the range, going to 0 over the fade band
+    pub fn fade(&self, distance: f32, range: f32) -> f32 {
+        if self.fade_band <= 0.0 {
+            return if distance <= range { 1.0 } else { 0.0 };
+        }
+        ((range - distance) / self.fade_band).clamp(0.0, 1.0)
+    }
+}
+
+/// Optional on a camera, scales the `DistanceCulling` of every chunk for that camera, e.g. below 1 for a minimap
+#[derive(Component, Inspectable, Clone, Copy, Debug)]
+pub struct ViewDistanceCulling {
+    pub scale: f32,
+}
+
+impl Default for ViewDistanceCulling {
+    fn default() -> Self {
+        Self { scale: 1.0 }
+    }
+}
+
+pub struct DistanceCullingSettings {
+    /// Chunks enter at `distance` and leave at `distance + hysteresis`, so they don't flicker at the border.
+    /// Chunks with a fade band are only drawn while their fade is above 0, the band itself keeps them from flickering
+    pub hysteresis: f32,
+    /// Cameras moving less than this are treated as standing still, only changed chunks are checked then
+    pub min_camera_movement: f32,
+}
+
+impl Default for DistanceCullingSettings {
+    fn default() -> Self {
+        Self {
+            hysteresis: 5.0,
+            min_camera_movement: 0.1,
+        }
     }
-}
\ No newline at end of file
+}
+
+/// Render world component of every visible `DistanceCulling` chunk: the cameras that have it in range,
+/// each with the chunk's fade for that camera (1 is fully visible)
+#[derive(Component, Clone, Debug, Default)]
+pub struct ChunkInRange {
+    pub cameras: Vec<(Entity, f32)>,
+}
+
+impl ChunkInRange {
+    /// Fade of the chunk in `view`, None when `view` doesn't draw it.
+    /// Chunks without culling state are drawn unfaded by every view
+    pub(crate) fn fade(in_range: Option<&ChunkInRange>, view: Entity) -> Option<f32> {
+        match in_range {
+            Some(in_range) => in_range
+                .cameras
+                .iter()
+                .find(|(camera, _)| *camera == view)
+                .map(|(_, fade)| *fade)
+                .filter(|fade| *fade > 0.0),
+            None => Some(1.0),
+        }
+    }
+
+    pub(crate) fn is_in_range(in_range: Option<&ChunkInRange>, view: Entity) -> bool {
+        Self::fade(in_range, view).is_some()
+    }
+}
+
+pub struct ChunkEnteredRange {
+    pub chunk: Entity,
+    pub camera: Entity,
+}
+
+pub struct ChunkLeftRange {
+    pub chunk: Entity,
+    pub camera: Entity,
+}
+
+enum RangeEvent {
+    Entered(ChunkEnteredRange),
+    Left(ChunkLeftRange),
+}
+
+/// Shared by both worlds, the render world pushes the events and `send_range_events` sends them in the main world
+#[derive(Clone, Default)]
+struct RangeEvents(Arc<Mutex<Vec<RangeEvent>>>);
+
+fn send_range_events(
+    range_events: Res<RangeEvents>,
+    mut entered_events: EventWriter<ChunkEnteredRange>,
+    mut left_events: EventWriter<ChunkLeftRange>,
+) {
+    for event in range_events.0.lock().unwrap().drain(..) {
+        match event {
+            RangeEvent::Entered(event) => entered_events.send(event),
+            RangeEvent::Left(event) => left_events.send(event),
+        }
+    }
+}
+
+/// Culling state kept in the render world between frames
+#[derive(Default)]
+struct ChunkRanges {
+    /// Position and distance scale of every active camera when the chunks were last checked
+    cameras: HashMap<Entity, (Vec3, f32)>,
+    chunks: HashMap<Entity, TrackedChunk>,
+    frame: u32,
+}
+
+#[derive(Default)]
+struct TrackedChunk {
+    in_range: ChunkInRange,
+    last_seen_frame: u32,
+}
+
+/// Distance from `point` to the closest point of the world space bounds of `aabb`
+fn distance_to_aabb(point: Vec3, transform: &GlobalTransform, aabb: Option<&Aabb>) -> f32 {
+    let matrix = transform.compute_matrix();
+    let (center, half_extents) = match aabb {
+        Some(aabb) => (Vec3::from(aabb.center), Vec3::from(aabb.half_extents)),
+        None => (Vec3::ZERO, Vec3::ZERO),
+    };
+    let world_center = matrix.transform_point3(center);
+    let world_half_extents = Mat3::from_cols(
+        matrix.x_axis.truncate().abs(),
+        matrix.y_axis.truncate().abs(),
+        matrix.z_axis.truncate().abs(),
+    ) * half_extents;
+    ((point - world_center).abs() - world_half_extents)
+        .max(Vec3::ZERO)
+        .length()
+}
+
+fn extract_chunk_in_range(
+    mut commands: Commands,
+    mut previous_len: Local<usize>,
+    mut ranges: ResMut<ChunkRanges>,
+    range_events: Res<RangeEvents>,
+    settings: Extract<Res<DistanceCullingSettings>>,
+    cameras: Extract<Query<(Entity, &Camera, &GlobalTransform, Option<&ViewDistanceCulling>)>>,
+    chunks: Extract<
+        Query<(
+            Entity,
+            &ComputedVisibility,
+            &GlobalTransform,
+            Option<&Aabb>,
+            &DistanceCulling,
+            ChangeTrackers<GlobalTransform>,
+            ChangeTrackers<DistanceCulling>,
+        )>,
+    >,
+) {
+    let ranges = ranges.as_mut();
+    ranges.frame = ranges.frame.wrapping_add(1);
+    let cameras: Vec<(Entity, Vec3, f32)> = cameras
+        .iter()
+        .filter(|(_, camera, _, _)| camera.is_active)
+        .map(|(entity, _, transform, view_culling)| {
+            let scale = view_culling.map_or(1.0, |view_culling| view_culling.scale);
+            (entity, transform.translation(), scale)
+        })
+        .collect();
+
+    //Any camera that moved, changed its scale or was (de)activated and new settings check every chunk again
+    let cameras_changed = settings.is_changed()
+        || cameras.len() != ranges.cameras.len()
+        || cameras.iter().any(|(entity, position, scale)| {
+            ranges.cameras.get(entity).map_or(true, |(last_position, last_scale)| {
+                last_position.distance(*position) >= settings.min_camera_movement
+                    || last_scale != scale
+            })
+        });
+    if cameras_changed {
+        ranges.cameras = cameras
+            .iter()
+            .map(|(entity, position, scale)| (*entity, (*position, *scale)))
+            .collect();
+    }
+
+    let mut events = range_events.0.lock().unwrap();
+    let mut values = Vec::with_capacity(*previous_len);
+    for (
+        chunk,
+        computed_visibility,
+        transform,
+        aabb,
+        distance_culling,
+        transform_tracker,
+        culling_tracker,
+    ) in chunks.iter()
+    {
+        let is_new = !ranges.chunks.contains_key(&chunk);
+        let tracked = ranges.chunks.entry(chunk).or_default();
+        tracked.last_seen_frame = ranges.frame;
+        let in_range = &mut tracked.in_range;
+
+        if cameras_changed
+            || is_new
+            || transform_tracker.is_changed()
+            || culling_tracker.is_changed()
+        {
+            //Cameras that were despawned or deactivated
+            in_range.cameras.retain(|(camera, _)| {
+                let keep = cameras.iter().any(|(entity, _, _)| entity == camera);
+                if !keep {
+                    events.push(RangeEvent::Left(ChunkLeftRange {
+                        chunk,
+                        camera: *camera,
+                    }));
+                }
+                keep
+            });
+
+            for (camera, position, scale) in cameras.iter() {
+                let distance = distance_to_aabb(*position, transform, aabb);
+                let range = distance_culling.distance * scale;
+                //Without a fade band the chunk stays fully visible until it leaves at the end of the hysteresis
+                let fade = match distance_culling.fade_band > 0.0 {
+                    true => distance_culling.fade(distance, range),
+                    false => 1.0,
+                };
+                match in_range.cameras.iter().position(|(entity, _)| entity == camera) {
+                    None if distance <= range => {
+                        in_range.cameras.push((*camera, fade));
+                        events.push(RangeEvent::Entered(ChunkEnteredRange {
+                            chunk,
+                            camera: *camera,
+                        }));
+                    }
+                    Some(index) if distance > range + settings.hysteresis => {
+                        in_range.cameras.swap_remove(index);
+                        events.push(RangeEvent::Left(ChunkLeftRange {
+                            chunk,
+                            camera: *camera,
+                        }));
+                    }
+                    Some(index) => in_range.cameras[index].1 = fade,
+                    None => {}
+                }
+            }
+        }
+
+        if computed_visibility.is_visible() {
+            values.push((chunk, in_range.clone()));
+        }
+    }
+    //Forget despawned chunks
+    let frame = ranges.frame;
+    ranges
+        .chunks
+        .retain(|_, tracked| tracked.last_seen_frame == frame);
+
+    *previous_len = values.len();
+    commands.insert_or_spawn_batch(values);
+}
+
+/// Chunks drawn by bevy's own pipelines (e.g. the terrain with its `StandardMaterial`) are queued for every view,
+/// this takes them out of the phases of the views that don't have them in range.
+/// Shadow views are culled like the camera they belong to
+fn cull_phase_items<P: EntityPhaseItem>(
+    mut views: Query<(Entity, &mut RenderPhase<P>)>,
+    chunks: Query<&ChunkInRange>,
+    shadow_view_owners: Res<ShadowViewOwners>,
+) {
+    for (view, mut phase) in &mut views {
+        let view = shadow_view_owners.camera_view(view);
+        phase
+            .items
+            .retain(|item| ChunkInRange::is_in_range(chunks.get(item.entity()).ok(), view));
+    }
+}
+
+/// Alpha mode as part of the pipeline keys, the `AlphaMode::Mask` cutoff is sent in the chunk uniforms
+/// so layers with different cutoffs share one pipeline
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AlphaModeKey {
+    Opaque,
+    Mask,
+    Blend,
+}
+
+impl From<AlphaMode> for AlphaModeKey {
+    fn from(alpha_mode: AlphaMode) -> Self {
+        match alpha_mode {
+            AlphaMode::Opaque => AlphaModeKey::Opaque,
+            AlphaMode::Mask(_) => AlphaModeKey::Mask,
+            AlphaMode::Blend => AlphaModeKey::Blend,
+        }
+    }
+}
+
+pub(crate) fn alpha_cutoff(alpha_mode: AlphaMode) -> f32 {
+    match alpha_mode {
+        AlphaMode::Mask(cutoff) => cutoff,
+        _ => 0.0,
+    }
+}
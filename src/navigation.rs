@@ -0,0 +1,350 @@
+//! Walkability of the forest for AI. Layers with a [`NavigationFootprint`] are rasterized into the
+//! [`NavigationGrid`] from the same instances that are rendered, [`NavigationGrid::find_path`] walks around them.
+
+use std::{cmp::Ordering, collections::BinaryHeap};
+
+use bevy::{prelude::*, transform::TransformSystem, utils::HashMap};
+use bevy_inspector_egui::{Inspectable, RegisterInspectable};
+
+use super::chunk_instancing::ChunkInstancing;
+
+pub struct NavigationPlugin;
+
+impl Plugin for NavigationPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<NavigationGrid>()
+            .add_system_to_stage(
+                CoreStage::PostUpdate,
+                update_navigation_grid.after(TransformSystem::TransformPropagate),
+            )
+            .register_inspectable::<NavigationFootprint>();
+    }
+}
+
+/// Added next to a `ChunkInstancing` to make its instances obstacles. The footprint is centered on the origin of the model
+/// and its radius is scaled like the rendered mesh: by `model_transform`, the instance and the chunk's transform
+#[derive(Component, Inspectable, Clone, Copy, Debug)]
+pub struct NavigationFootprint {
+    pub radius: f32,
+    /// Nothing can walk through, e.g. trees and rocks
+    pub blocking: bool,
+    /// When not blocking, walking through the footprint costs `1 + cost` times as much, e.g. 2 for bushes that slow down
+    pub cost: f32,
+}
+
+impl Default for NavigationFootprint {
+    fn default() -> Self {
+        Self {
+            radius: 0.5,
+            blocking: true,
+            cost: 0.0,
+        }
+    }
+}
+
+impl NavigationFootprint {
+    pub fn blocking(radius: f32) -> Self {
+        Self {
+            radius,
+            ..default()
+        }
+    }
+
+    pub fn cost(radius: f32, cost: f32) -> Self {
+        Self {
+            radius,
+            blocking: false,
+            cost,
+        }
+    }
+}
+
+/// Occupancy and cost of square cells over the x z plane, stored in chunks of `chunk_cells` x `chunk_cells` cells.
+/// Cells outside of every chunk are free. Insert one before adding the plugin to change the resolution
+pub struct NavigationGrid {
+    pub cell_size: f32,
+    pub chunk_cells: u32,
+    /// A* gives up after expanding this many cells, e.g. when the goal is walled in
+    pub max_search_nodes: usize,
+    chunks: HashMap<IVec2, NavigationChunk>,
+    /// Cells each layer entity added, removed again when its instances change
+    footprints: HashMap<Entity, LayerFootprint>,
+}
+
+impl Default for NavigationGrid {
+    fn default() -> Self {
+        Self::new(0.5, 64)
+    }
+}
+
+/// Row major cells of one chunk, see [`NavigationGrid::chunk_of`]
+#[derive(Clone, Debug)]
+pub struct NavigationChunk {
+    /// Number of blocking footprints per cell
+    pub obstacles: Vec<u16>,
+    /// Summed extra cost of the non blocking footprints per cell
+    pub cost: Vec<f32>,
+}
+
+struct LayerFootprint {
+    cells: Vec<IVec2>,
+    footprint: NavigationFootprint,
+}
+
+impl NavigationGrid {
+    pub fn new(cell_size: f32, chunk_cells: u32) -> Self {
+        Self {
+            cell_size,
+            chunk_cells,
+            max_search_nodes: 100_000,
+            chunks: HashMap::default(),
+            footprints: HashMap::default(),
+        }
+    }
+
+    pub fn cell_of(&self, position: Vec2) -> IVec2 {
+        (position / self.cell_size).floor().as_ivec2()
+    }
+
+    pub fn cell_center(&self, cell: IVec2) -> Vec2 {
+        (cell.as_vec2() + 0.5) * self.cell_size
+    }
+
+    /// Chunk coordinate of a cell, cells of chunk `c` go from `c * chunk_cells` to `(c + 1) * chunk_cells - 1`
+    pub fn chunk_of(&self, cell: IVec2) -> IVec2 {
+        let size = self.chunk_cells as i32;
+        IVec2::new(cell.x.div_euclid(size), cell.y.div_euclid(size))
+    }
+
+    pub fn chunk(&self, chunk: IVec2) -> Option<&NavigationChunk> {
+        self.chunks.get(&chunk)
+    }
+
+    fn local_index(&self, cell: IVec2) -> usize {
+        let size = self.chunk_cells as i32;
+        (cell.y.rem_euclid(size) * size + cell.x.rem_euclid(size)) as usize
+    }
+
+    pub fn is_blocked(&self, cell: IVec2) -> bool {
+        self.chunks
+            .get(&self.chunk_of(cell))
+            .map_or(false, |chunk| chunk.obstacles[self.local_index(cell)] > 0)
+    }
+
+    /// Cost multiplier for walking through the cell, None if it is blocked
+    pub fn cost(&self, cell: IVec2) -> Option<f32> {
+        match self.chunks.get(&self.chunk_of(cell)) {
+            Some(chunk) => {
+                let i = self.local_index(cell);
+                (chunk.obstacles[i] == 0).then(|| 1.0 + chunk.cost[i])
+            }
+            None => Some(1.0),
+        }
+    }
+
+    fn apply(&mut self, cells: &[IVec2], footprint: NavigationFootprint, sign: i32) {
+        let size = (self.chunk_cells * self.chunk_cells) as usize;
+        for cell in cells.iter() {
+            let i = self.local_index(*cell);
+            let key = self.chunk_of(*cell);
+            let chunk = self
+                .chunks
+                .entry(key)
+                .or_insert_with(|| NavigationChunk {
+                    obstacles: vec![0; size],
+                    cost: vec![0.0; size],
+                });
+            if footprint.blocking {
+                chunk.obstacles[i] = (chunk.obstacles[i] as i32 + sign).max(0) as u16;
+            } else {
+                chunk.cost[i] = (chunk.cost[i] + footprint.cost * sign as f32).max(0.0);
+            }
+        }
+    }
+
+    /// Replaces the cells of a layer entity, None removes them
+    fn set_layer(&mut self, layer: Entity, footprint: Option<LayerFootprint>) {
+        if let Some(old) = self.footprints.remove(&layer) {
+            self.apply(&old.cells, old.footprint, -1);
+        }
+        if let Some(new) = footprint {
+            self.apply(&new.cells, new.footprint, 1);
+            self.footprints.insert(layer, new);
+        }
+    }
+
+    /// Cells with their center inside a footprint, at least the cell under the instance
+    fn rasterize(&self, center: Vec2, radius: f32, cells: &mut Vec<IVec2>) {
+        let center_cell = self.cell_of(center);
+        let min = self.cell_of(center - radius);
+        let max = self.cell_of(center + radius);
+        for y in min.y..=max.y {
+            for x in min.x..=max.x {
+                let cell = IVec2::new(x, y);
+                if cell == center_cell || self.cell_center(cell).distance(center) <= radius {
+                    cells.push(cell);
+                }
+            }
+        }
+    }
+
+    /// Path between two x z positions around blocked cells, the points are the centers of the cells walked
+    /// with `start` and `goal` at the ends. Sample `ForestTerrain::height_at` for the heights
+    pub fn find_path(&self, start: Vec2, goal: Vec2) -> Option<Vec<Vec2>> {
+        let cells = self.find_path_cells(self.cell_of(start), self.cell_of(goal))?;
+        let mut path: Vec<Vec2> = cells.iter().map(|cell| self.cell_center(*cell)).collect();
+        path[0] = start;
+        *path.last_mut().unwrap() = goal;
+        Some(path)
+    }
+
+    /// A* over the 8 neighbours, diagonal steps can't cut the corner of a blocked cell.
+    /// Starting in a blocked cell is allowed so agents can leave a footprint they ended up in
+    pub fn find_path_cells(&self, start: IVec2, goal: IVec2) -> Option<Vec<IVec2>> {
+        if self.is_blocked(goal) {
+            return None;
+        }
+        let heuristic = |cell: IVec2| {
+            let d = (goal - cell).abs();
+            let (min, max) = (d.x.min(d.y) as f32, d.x.max(d.y) as f32);
+            max - min + min * std::f32::consts::SQRT_2
+        };
+
+        let mut open = BinaryHeap::new();
+        let mut came_from: HashMap<IVec2, (IVec2, f32)> = HashMap::default();
+        came_from.insert(start, (start, 0.0));
+        open.push(OpenCell {
+            estimate: heuristic(start),
+            cell: start,
+        });
+
+        let mut expanded = 0;
+        while let Some(OpenCell { estimate, cell }) = open.pop() {
+            if cell == goal {
+                let mut path = vec![goal];
+                let mut current = goal;
+                while current != start {
+                    current = came_from[&current].0;
+                    path.push(current);
+                }
+                path.reverse();
+                return Some(path);
+            }
+            let walked = came_from[&cell].1;
+            //Stale entry, the cell was reached cheaper after it was pushed
+            if estimate > walked + heuristic(cell) + 1e-4 {
+                continue;
+            }
+            expanded += 1;
+            if expanded > self.max_search_nodes {
+                return None;
+            }
+
+            for offset in NEIGHBOURS {
+                let next = cell + offset;
+                let step_cost = match self.cost(next) {
+                    Some(cost) => cost,
+                    None => continue,
+                };
+                let diagonal = offset.x != 0 && offset.y != 0;
+                if diagonal
+                    && (self.is_blocked(cell + IVec2::new(offset.x, 0))
+                        || self.is_blocked(cell + IVec2::new(0, offset.y)))
+                {
+                    continue;
+                }
+                let distance = if diagonal { std::f32::consts::SQRT_2 } else { 1.0 };
+                let next_walked = walked + distance * step_cost;
+                if came_from.get(&next).map_or(true, |(_, known)| next_walked < *known) {
+                    came_from.insert(next, (cell, next_walked));
+                    open.push(OpenCell {
+                        estimate: next_walked + heuristic(next),
+                        cell: next,
+                    });
+                }
+            }
+        }
+        None
+    }
+}
+
+const NEIGHBOURS: [IVec2; 8] = [
+    IVec2::new(1, 0),
+    IVec2::new(-1, 0),
+    IVec2::new(0, 1),
+    IVec2::new(0, -1),
+    IVec2::new(1, 1),
+    IVec2::new(1, -1),
+    IVec2::new(-1, 1),
+    IVec2::new(-1, -1),
+];
+
+//Min heap on the estimated total cost
+struct OpenCell {
+    estimate: f32,
+    cell: IVec2,
+}
+
+impl PartialEq for OpenCell {
+    fn eq(&self, other: &Self) -> bool {
+        self.estimate == other.estimate
+    }
+}
+
+impl Eq for OpenCell {}
+
+impl PartialOrd for OpenCell {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for OpenCell {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.estimate.total_cmp(&self.estimate)
+    }
+}
+
+fn update_navigation_grid(
+    mut grid: ResMut<NavigationGrid>,
+    layers: Query<
+        (Entity, &ChunkInstancing, &GlobalTransform, &NavigationFootprint),
+        Or<(
+            Changed<ChunkInstancing>,
+            Changed<GlobalTransform>,
+            Changed<NavigationFootprint>,
+        )>,
+    >,
+    removed_footprints: RemovedComponents<NavigationFootprint>,
+    removed_instancing: RemovedComponents<ChunkInstancing>,
+) {
+    for entity in removed_footprints.iter().chain(removed_instancing.iter()) {
+        grid.set_layer(entity, None);
+    }
+
+    for (entity, chunk_instancing, transform, footprint) in layers.iter() {
+        //Same matrix as the shader and InstanceSpatialIndex::new
+        let chunk_matrix = transform.compute_matrix();
+        let model_matrix = chunk_instancing.model_transform.compute_matrix();
+        let mut cells = Vec::new();
+        for instance in chunk_instancing.instances().iter() {
+            let matrix = chunk_matrix
+                * instance.transform(chunk_instancing.instance_format).compute_matrix()
+                * model_matrix;
+            let center = matrix.transform_point3(Vec3::ZERO);
+            let scale = matrix
+                .x_axis
+                .truncate()
+                .length()
+                .max(matrix.z_axis.truncate().length());
+            grid.rasterize(Vec2::new(center.x, center.z), footprint.radius * scale, &mut cells);
+        }
+        grid.set_layer(
+            entity,
+            Some(LayerFootprint {
+                cells,
+                footprint: *footprint,
+            }),
+        );
+    }
+}
@@ -0,0 +1,153 @@
+//! Turns single instances of a [`ChunkInstancing`] into regular entities and back, e.g. for a tree that falls over.
+//! The rest of the chunk stays instanced while the promoted entity is simulated on its own.
+
+use bevy::{ecs::system::Command, prelude::*};
+
+use super::chunk_instancing::{ChunkInstancing, Instance, InstanceId};
+
+/// On entities spawned by [`InstancePromotionCommands::promote_instance`], remembers where the instance came from
+#[derive(Component, Clone, Debug)]
+pub struct PromotedInstance {
+    pub chunk: Entity,
+    /// The instance as it was in the chunk, its tint and texture layer are kept when demoting
+    pub instance: Instance,
+}
+
+pub trait InstancePromotionCommands {
+    /// Removes the instance from the chunk and spawns a `PbrBundle` with the same mesh, material and world transform.
+    /// The entity is spawned without a parent, move it through its `Transform`.
+    /// Chunks using a texture array need `ChunkInstancing::material`, `StandardMaterial` can't sample those
+    fn promote_instance(&mut self, chunk: Entity, id: InstanceId) -> Entity;
+
+    /// Despawns the promoted entity and pushes it back into the chunk it came from at its current transform.
+    /// Compact chunks only keep the position and scale, see [`Instance::from_transform`]
+    fn demote_instance(&mut self, entity: Entity);
+
+    /// Same as [`InstancePromotionCommands::demote_instance`] into another chunk with the same mesh
+    fn demote_instance_into(&mut self, entity: Entity, chunk: Entity);
+}
+
+impl<'w, 's> InstancePromotionCommands for Commands<'w, 's> {
+    fn promote_instance(&mut self, chunk: Entity, id: InstanceId) -> Entity {
+        let entity = self.spawn().id();
+        self.add(PromoteInstance { chunk, id, entity });
+        entity
+    }
+
+    fn demote_instance(&mut self, entity: Entity) {
+        self.add(DemoteInstance { entity, chunk: None });
+    }
+
+    fn demote_instance_into(&mut self, entity: Entity, chunk: Entity) {
+        self.add(DemoteInstance {
+            entity,
+            chunk: Some(chunk),
+        });
+    }
+}
+
+struct PromoteInstance {
+    chunk: Entity,
+    id: InstanceId,
+    entity: Entity,
+}
+
+impl Command for PromoteInstance {
+    fn write(self, world: &mut World) {
+        let promoted = world.get_entity_mut(self.chunk).and_then(|mut chunk| {
+            let chunk_transform = *chunk.get::<GlobalTransform>()?;
+            let mesh = chunk.get::<Handle<Mesh>>()?.clone();
+            let mut chunk_instancing = chunk.get_mut::<ChunkInstancing>()?;
+            let instance = chunk_instancing.remove_instance(self.id)?;
+            let transform = Transform::from_matrix(
+                //Same order as the shader: chunk transform, instance transform, then the layers model_transform
+                chunk_transform.compute_matrix()
+                    * instance.transform(chunk_instancing.instance_format).compute_matrix()
+                    * chunk_instancing.model_transform.compute_matrix(),
+            );
+            let material = chunk_instancing.material.clone().ok_or_else(|| StandardMaterial {
+                base_color: match chunk_instancing.instance_variation {
+                    true => instance.tint,
+                    false => Color::WHITE,
+                },
+                base_color_texture: Some(chunk_instancing.base_color_texture.clone()),
+                alpha_mode: chunk_instancing.alpha_mode,
+                reflectance: 0.0, //Same as chunk_instancing.wgsl
+                ..default()
+            });
+            Some((mesh, transform, material, instance))
+        });
+        let (mesh, transform, material, instance) = match promoted {
+            Some(promoted) => promoted,
+            None => {
+                warn!(
+                    "Can't promote instance {:?} of {:?}, the chunk or instance doesn't exist",
+                    self.id, self.chunk
+                );
+                world.despawn(self.entity);
+                return;
+            }
+        };
+        //Chunks without a material get one per promoted instance, it is dropped with the entity
+        let material = material
+            .unwrap_or_else(|material| world.resource_mut::<Assets<StandardMaterial>>().add(material));
+
+        world
+            .entity_mut(self.entity)
+            .insert_bundle(PbrBundle {
+                mesh,
+                material,
+                transform,
+                global_transform: GlobalTransform::from(transform),
+                ..default()
+            })
+            .insert(PromotedInstance {
+                chunk: self.chunk,
+                instance,
+            });
+    }
+}
+
+struct DemoteInstance {
+    entity: Entity,
+    chunk: Option<Entity>,
+}
+
+impl Command for DemoteInstance {
+    fn write(self, world: &mut World) {
+        let demoted = world.get_entity(self.entity).and_then(|entity| {
+            Some((*entity.get::<Transform>()?, entity.get::<PromotedInstance>()?.clone()))
+        });
+        let (transform, promoted) = match demoted {
+            Some(demoted) => demoted,
+            None => {
+                warn!("Can't demote {:?}, it is not a promoted instance", self.entity);
+                return;
+            }
+        };
+        let chunk = self.chunk.unwrap_or(promoted.chunk);
+
+        let demoted = world.get_entity_mut(chunk).and_then(|mut chunk| {
+            let chunk_transform = *chunk.get::<GlobalTransform>()?;
+            let mut chunk_instancing = chunk.get_mut::<ChunkInstancing>()?;
+            let local = Transform::from_matrix(
+                chunk_transform.compute_matrix().inverse()
+                    * transform.compute_matrix()
+                    * chunk_instancing.model_transform.compute_matrix().inverse(),
+            );
+            let instance = Instance {
+                tint: promoted.instance.tint,
+                texture_layer: promoted.instance.texture_layer,
+                ..Instance::from_transform(local, chunk_instancing.instance_format)
+            };
+            chunk_instancing.push_instance(instance);
+            Some(())
+        });
+        if demoted.is_none() {
+            warn!("Can't demote {:?} into {:?}, the chunk doesn't exist", self.entity, chunk);
+            return;
+        }
+        world.entity_mut(self.entity).despawn_recursive();
+    }
+}
+
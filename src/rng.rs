@@ -0,0 +1,104 @@
+//! Integer hashing shared between the rust side and the shaders.
+//! Everything here only uses integer math and exact float conversions so the results are the same on every platform and gpu.
+
+/// PCG hash (Jarzynski & Olano 2020), same as `pcg_hash` in forest_rng.wgsl
+#[inline]
+pub fn pcg_hash(input: u32) -> u32 {
+    let state = input.wrapping_mul(747796405).wrapping_add(2891336453);
+    let word = ((state >> ((state >> 28) + 4)) ^ state).wrapping_mul(277803737);
+    (word >> 22) ^ word
+}
+
+/// Maps a hash to [0, 1) using the top 24 bits, exact in f32
+#[inline]
+pub fn hash_to_unit(hash: u32) -> f32 {
+    (hash >> 8) as f32 * (1.0 / 16777216.0)
+}
+
+/// Seed for one layer of the chunk at `chunk` in a world seeded with `world_seed`.
+/// Neighbouring chunks and layers get unrelated sequences.
+pub fn chunk_seed(world_seed: u64, chunk: [i32; 2], layer: u32) -> u64 {
+    let coords = ((chunk[0] as u32 as u64) << 32) | chunk[1] as u32 as u64;
+    splitmix64(world_seed ^ splitmix64(coords ^ splitmix64(layer as u64)))
+}
+
+/// SplitMix64 finalizer (Steele, Lea & Flood 2014)
+#[inline]
+pub fn splitmix64(input: u64) -> u64 {
+    let mut z = input.wrapping_add(0x9E3779B97F4A7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
+
+/// PCG32 generator (XSH RR variant, O'Neill 2014) used for all seeded scattering.
+///
+/// The output for a given seed is part of the crate's contract and does not depend on the `rand` version,
+/// the platform or the gpu, so every client builds the same world from the same seed.
+/// Changing anything here changes every seeded world.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ForestRng {
+    state: u64,
+    increment: u64,
+}
+
+impl ForestRng {
+    const MULTIPLIER: u64 = 6364136223846793005;
+    const DEFAULT_STREAM: u64 = 1442695040888963407;
+
+    pub fn new(seed: u64) -> Self {
+        Self::with_stream(seed, Self::DEFAULT_STREAM)
+    }
+
+    /// Generators with the same seed but different streams give unrelated sequences
+    pub fn with_stream(seed: u64, stream: u64) -> Self {
+        let mut rng = Self {
+            state: 0,
+            increment: (stream << 1) | 1,
+        };
+        rng.next_u32();
+        rng.state = rng.state.wrapping_add(seed);
+        rng.next_u32();
+        rng
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let old_state = self.state;
+        self.state = old_state
+            .wrapping_mul(Self::MULTIPLIER)
+            .wrapping_add(self.increment);
+        let xorshifted = (((old_state >> 18) ^ old_state) >> 27) as u32;
+        xorshifted.rotate_right((old_state >> 59) as u32)
+    }
+
+    /// [0, 1) with 24 bits of precision
+    pub fn next_f32(&mut self) -> f32 {
+        hash_to_unit(self.next_u32())
+    }
+
+    /// [min, max)
+    pub fn range(&mut self, min: f32, max: f32) -> f32 {
+        min + self.next_f32() * (max - min)
+    }
+
+    /// Uniform point in the unit disc by rejection sampling
+    pub fn unit_disc(&mut self) -> [f32; 2] {
+        loop {
+            let x = self.next_f32() * 2.0 - 1.0;
+            let y = self.next_f32() * 2.0 - 1.0;
+            let length_squared = x * x + y * y;
+            if length_squared > 1e-4 && length_squared <= 1.0 {
+                return [x, y];
+            }
+        }
+    }
+
+    /// Uniform random rotation around the y axis as a quaternion `[x, y, z, w]`.
+    /// Built from a normalized random disc point instead of sin/cos, which are not bit identical across platforms.
+    pub fn yaw_rotation(&mut self) -> [f32; 4] {
+        //(cos, sin) of half the yaw, q and -q are the same rotation so a full circle of half angles is still uniform
+        let [c, s] = self.unit_disc();
+        let length = (c * c + s * s).sqrt();
+        [0.0, s / length, 0.0, c / length]
+    }
+}
@@ -0,0 +1,481 @@
+//! Poisson-disk (blue noise) placement of several instance layers in one chunk,
+//! and [`ScatterRule`]s thinning them out by texture, noise, height and slope.
+//! Runs on the cpu and only depends on the seed, see [`crate::rng::ForestRng`].
+
+use bevy::{prelude::*, render::render_resource::TextureFormat, utils::HashMap};
+use noise::{NoiseFn, Perlin, Seedable};
+use std::collections::VecDeque;
+
+use super::{
+    chunk_instancing::{ChunkInstancing, Instance},
+    rng::{self, ForestRng},
+};
+
+/// Candidates tried around each active point before it is retired (Bridson 2007)
+const CANDIDATES_PER_POINT: u32 = 30;
+
+#[derive(Clone, Debug)]
+pub struct ScatterLayer {
+    /// Minimum distance between two instances of this layer
+    pub min_distance: f32,
+    pub max_instances: Option<u32>,
+    pub exclusions: Vec<Exclusion>,
+}
+
+/// Keeps instances of a layer at least `radius` away from the instances of an earlier `layer`
+#[derive(Clone, Copy, Debug)]
+pub struct Exclusion {
+    pub layer: usize,
+    pub radius: f32,
+}
+
+impl ScatterLayer {
+    pub fn new(min_distance: f32) -> Self {
+        Self {
+            min_distance,
+            max_instances: None,
+            exclusions: Vec::new(),
+        }
+    }
+
+    pub fn with_max_instances(mut self, max_instances: u32) -> Self {
+        self.max_instances = Some(max_instances);
+        self
+    }
+
+    /// `layer` has to be added to the [`PoissonScatter`] before this one
+    pub fn with_exclusion(mut self, layer: usize, radius: f32) -> Self {
+        self.exclusions.push(Exclusion { layer, radius });
+        self
+    }
+}
+
+/// Scatters the layers of a chunk in order, so every layer can keep away from the ones before it.
+///
+/// Instances stay `margin` away from the chunk border, half of the largest distance involving the layer.
+/// Two chunks scattered next to each other therefore never break the spacing across the border.
+#[derive(Clone, Debug)]
+pub struct PoissonScatter {
+    pub chunk_size: f32,
+    pub layers: Vec<ScatterLayer>,
+}
+
+impl PoissonScatter {
+    pub fn new(chunk_size: f32) -> Self {
+        Self {
+            chunk_size,
+            layers: Vec::new(),
+        }
+    }
+
+    pub fn with_layer(mut self, layer: ScatterLayer) -> Self {
+        self.layers.push(layer);
+        self
+    }
+
+    /// Distance kept between the instances of `layer` and the chunk border
+    pub fn margin(&self, layer: usize) -> f32 {
+        let own = self.layers[layer]
+            .exclusions
+            .iter()
+            .map(|exclusion| exclusion.radius)
+            .fold(self.layers[layer].min_distance, f32::max);
+        let excluded_by_others = self
+            .layers
+            .iter()
+            .flat_map(|other| other.exclusions.iter())
+            .filter(|exclusion| exclusion.layer == layer)
+            .map(|exclusion| exclusion.radius)
+            .fold(0.0, f32::max);
+        own.max(excluded_by_others) * 0.5
+    }
+
+    /// Instances per layer, positions in [0, chunk_size) like [`ChunkInstancing::new_seeded`].
+    /// Every layer draws from its own stream so appending a layer does not move the existing ones.
+    pub fn scatter(&self, seed: u64) -> Vec<Vec<Instance>> {
+        let mut grids: Vec<PointGrid> = Vec::with_capacity(self.layers.len());
+        let mut result = Vec::with_capacity(self.layers.len());
+
+        for (index, layer) in self.layers.iter().enumerate() {
+            for exclusion in layer.exclusions.iter() {
+                assert!(
+                    exclusion.layer < index,
+                    "scatter layer {index} excludes layer {} which is not scattered before it",
+                    exclusion.layer
+                );
+            }
+
+            let mut rng = ForestRng::with_stream(seed, index as u64);
+            let margin = self.margin(index);
+            let points = self.scatter_layer(layer, margin, &grids, &mut rng);
+
+            let mut grid = PointGrid::new(layer.min_distance);
+            let mut instances = Vec::with_capacity(points.len());
+            for point in points {
+                grid.insert(point);
+                instances.push(ChunkInstancing::random_instance(point.x, point.y, &mut rng));
+            }
+            grids.push(grid);
+            result.push(instances);
+        }
+        result
+    }
+
+    fn scatter_layer(
+        &self,
+        layer: &ScatterLayer,
+        margin: f32,
+        previous: &[PointGrid],
+        rng: &mut ForestRng,
+    ) -> Vec<Vec2> {
+        let min = margin;
+        let max = self.chunk_size - margin;
+        if max <= min || layer.min_distance <= 0.0 {
+            return Vec::new();
+        }
+        let max_instances = layer.max_instances.unwrap_or(u32::MAX) as usize;
+
+        let mut grid = PointGrid::new(layer.min_distance);
+        let mut points = Vec::new();
+        let mut active = VecDeque::new();
+
+        let accept = |point: Vec2, grid: &PointGrid| {
+            point.x >= min
+                && point.x < max
+                && point.y >= min
+                && point.y < max
+                && !grid.any_within(point, layer.min_distance)
+                && layer
+                    .exclusions
+                    .iter()
+                    .all(|exclusion| !previous[exclusion.layer].any_within(point, exclusion.radius))
+        };
+
+        while points.len() < max_instances {
+            if active.is_empty() {
+                //Start a new front with random darts, covers areas cut off by exclusions too
+                let dart = (0..CANDIDATES_PER_POINT)
+                    .map(|_| Vec2::new(rng.range(min, max), rng.range(min, max)))
+                    .find(|point| accept(*point, &grid));
+                match dart {
+                    Some(point) => {
+                        grid.insert(point);
+                        points.push(point);
+                        active.push_back(point);
+                    }
+                    None => break,
+                }
+                continue;
+            }
+
+            //Oldest active point first, keeps the result independent of anything but the rng
+            let center = active[0];
+            let candidate = (0..CANDIDATES_PER_POINT)
+                .map(|_| center + annulus_offset(layer.min_distance, rng))
+                .find(|point| accept(*point, &grid));
+            match candidate {
+                Some(point) => {
+                    grid.insert(point);
+                    points.push(point);
+                    active.push_back(point);
+                }
+                None => {
+                    active.pop_front();
+                }
+            }
+        }
+        points
+    }
+}
+
+/// Uniform offset with a length in [radius, 2 * radius), no trigonometry so it is the same on every platform
+fn annulus_offset(radius: f32, rng: &mut ForestRng) -> Vec2 {
+    loop {
+        let [x, y] = rng.unit_disc();
+        let offset = Vec2::new(x, y) * (2.0 * radius);
+        if offset.length_squared() >= radius * radius {
+            return offset;
+        }
+    }
+}
+
+/// Spatial hash for distance checks, cells are as large as the spacing of the layer
+struct PointGrid {
+    cell_size: f32,
+    cells: HashMap<(i32, i32), Vec<Vec2>>,
+}
+
+impl PointGrid {
+    fn new(cell_size: f32) -> Self {
+        Self {
+            cell_size: cell_size.max(f32::EPSILON),
+            cells: HashMap::default(),
+        }
+    }
+
+    fn cell(&self, point: Vec2) -> (i32, i32) {
+        (
+            (point.x / self.cell_size).floor() as i32,
+            (point.y / self.cell_size).floor() as i32,
+        )
+    }
+
+    fn insert(&mut self, point: Vec2) {
+        let cell = self.cell(point);
+        self.cells.entry(cell).or_default().push(point);
+    }
+
+    /// True if a point is closer than `radius`
+    fn any_within(&self, point: Vec2, radius: f32) -> bool {
+        let (cx, cy) = self.cell(point);
+        let reach = (radius / self.cell_size).ceil() as i32;
+        let radius_squared = radius * radius;
+        (cx - reach..=cx + reach).any(|x| {
+            (cy - reach..=cy + reach).any(|y| {
+                self.cells.get(&(x, y)).map_or(false, |cell| {
+                    cell.iter()
+                        .any(|other| other.distance_squared(point) < radius_squared)
+                })
+            })
+        })
+    }
+}
+
+/// Ground height the height and slope rules are evaluated against
+pub trait Heightfield {
+    fn height_at(&self, x: f32, z: f32) -> f32;
+
+    /// Central differences by default
+    fn normal_at(&self, x: f32, z: f32) -> Vec3 {
+        const STEP: f32 = 0.25;
+        let dx = self.height_at(x + STEP, z) - self.height_at(x - STEP, z);
+        let dz = self.height_at(x, z + STEP) - self.height_at(x, z - STEP);
+        Vec3::new(-dx, 2.0 * STEP, -dz).normalize()
+    }
+}
+
+/// Ground at y = 0
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FlatGround;
+
+impl Heightfield for FlatGround {
+    fn height_at(&self, _x: f32, _z: f32) -> f32 {
+        0.0
+    }
+}
+
+impl<F: Fn(f32, f32) -> f32> Heightfield for F {
+    fn height_at(&self, x: f32, z: f32) -> f32 {
+        self(x, z)
+    }
+}
+
+/// Density in [0, 1] over the world, used to thin out scattered instances.
+///
+/// ```ignore
+/// //Trees only on gentle slopes below 300 m, thinned by noise
+/// let rule = ScatterRule::slope(0.0, 20.0)
+///     .multiply(ScatterRule::height(f32::MIN, 300.0))
+///     .multiply(ScatterRule::noise(3, 0.02));
+/// ```
+#[derive(Clone, Debug)]
+pub enum ScatterRule {
+    Constant(f32),
+    Texture(DensityMap),
+    /// Perlin noise remapped to [0, 1], position is multiplied by `frequency`
+    Noise { perlin: Perlin, frequency: f64 },
+    /// 1 inside [min, max], fading to 0 over `falloff` meters outside of it
+    Height { min: f32, max: f32, falloff: f32 },
+    /// Same as height with the angle between the ground normal and up, in degrees
+    Slope { min: f32, max: f32, falloff: f32 },
+    Multiply(Vec<ScatterRule>),
+    Min(Vec<ScatterRule>),
+    Max(Vec<ScatterRule>),
+}
+
+impl ScatterRule {
+    pub fn noise(seed: u32, frequency: f64) -> Self {
+        Self::Noise {
+            perlin: Perlin::new().set_seed(seed),
+            frequency,
+        }
+    }
+
+    pub fn height(min: f32, max: f32) -> Self {
+        Self::Height {
+            min,
+            max,
+            falloff: 0.0,
+        }
+    }
+
+    pub fn slope(min_degrees: f32, max_degrees: f32) -> Self {
+        Self::Slope {
+            min: min_degrees,
+            max: max_degrees,
+            falloff: 0.0,
+        }
+    }
+
+    /// Soft edges for height and slope rules, no effect on the others
+    pub fn with_falloff(mut self, falloff: f32) -> Self {
+        if let Self::Height { falloff: f, .. } | Self::Slope { falloff: f, .. } = &mut self {
+            *f = falloff;
+        }
+        self
+    }
+
+    pub fn multiply(self, other: ScatterRule) -> Self {
+        match self {
+            Self::Multiply(mut rules) => {
+                rules.push(other);
+                Self::Multiply(rules)
+            }
+            rule => Self::Multiply(vec![rule, other]),
+        }
+    }
+
+    pub fn min(self, other: ScatterRule) -> Self {
+        match self {
+            Self::Min(mut rules) => {
+                rules.push(other);
+                Self::Min(rules)
+            }
+            rule => Self::Min(vec![rule, other]),
+        }
+    }
+
+    pub fn max(self, other: ScatterRule) -> Self {
+        match self {
+            Self::Max(mut rules) => {
+                rules.push(other);
+                Self::Max(rules)
+            }
+            rule => Self::Max(vec![rule, other]),
+        }
+    }
+
+    /// Density at the world position `x`, `z`
+    pub fn density(&self, x: f32, z: f32, heightfield: &impl Heightfield) -> f32 {
+        let density = match self {
+            Self::Constant(value) => *value,
+            Self::Texture(map) => map.sample(x, z),
+            Self::Noise { perlin, frequency } => {
+                let noise = perlin.get([x as f64 * frequency, z as f64 * frequency]) as f32;
+                (noise + 1.0) / 2.0
+            }
+            Self::Height { min, max, falloff } => {
+                band(heightfield.height_at(x, z), *min, *max, *falloff)
+            }
+            Self::Slope { min, max, falloff } => {
+                let up = heightfield.normal_at(x, z).y.clamp(-1.0, 1.0);
+                band(up.acos().to_degrees(), *min, *max, *falloff)
+            }
+            Self::Multiply(rules) => rules
+                .iter()
+                .map(|rule| rule.density(x, z, heightfield))
+                .product(),
+            Self::Min(rules) => rules
+                .iter()
+                .map(|rule| rule.density(x, z, heightfield))
+                .fold(1.0, f32::min),
+            Self::Max(rules) => rules
+                .iter()
+                .map(|rule| rule.density(x, z, heightfield))
+                .fold(0.0, f32::max),
+        };
+        density.clamp(0.0, 1.0)
+    }
+
+    /// Keeps each instance with a probability equal to the density at its world position.
+    /// `chunk_origin` is the world position of the chunk corner the instances are relative to.
+    ///
+    /// The dice roll is a hash of the position, so the result only depends on the instances and the rule.
+    pub fn retain(
+        &self,
+        instances: &mut Vec<Instance>,
+        chunk_origin: Vec2,
+        heightfield: &impl Heightfield,
+    ) {
+        instances.retain(|instance| {
+            let x = chunk_origin.x + instance.pos_xyz[0];
+            let z = chunk_origin.y + instance.pos_xyz[2];
+            //Salted so the roll doesn't correlate with other hashes of the same position
+            let roll = rng::hash_to_unit(rng::pcg_hash(
+                x.to_bits() ^ rng::pcg_hash(z.to_bits()) ^ 0x9e3779b9,
+            ));
+            roll < self.density(x, z, heightfield)
+        });
+    }
+}
+
+/// 1 inside [min, max], linear fade to 0 within `falloff` outside
+fn band(value: f32, min: f32, max: f32, falloff: f32) -> f32 {
+    let outside = (min - value).max(value - max);
+    if outside <= 0.0 {
+        1.0
+    } else if falloff > 0.0 {
+        (1.0 - outside / falloff).max(0.0)
+    } else {
+        0.0
+    }
+}
+
+/// Cpu copy of the red channel of an image stretched over a world rectangle, sampled bilinearly
+#[derive(Clone, Debug)]
+pub struct DensityMap {
+    pub width: u32,
+    pub height: u32,
+    pub values: Vec<f32>,
+    /// World position of the first texel
+    pub world_min: Vec2,
+    pub world_size: Vec2,
+}
+
+impl DensityMap {
+    /// `None` for formats other than 8 bit unorm
+    pub fn from_image(image: &Image, world_min: Vec2, world_size: Vec2) -> Option<Self> {
+        //Bytes per texel and the byte of the red channel
+        let (bytes_per_texel, red) = match image.texture_descriptor.format {
+            TextureFormat::R8Unorm => (1, 0),
+            TextureFormat::Rg8Unorm => (2, 0),
+            TextureFormat::Rgba8Unorm | TextureFormat::Rgba8UnormSrgb => (4, 0),
+            TextureFormat::Bgra8Unorm | TextureFormat::Bgra8UnormSrgb => (4, 2),
+            _ => return None,
+        };
+        let size = image.texture_descriptor.size;
+        let values = image
+            .data
+            .chunks_exact(bytes_per_texel)
+            .take((size.width * size.height) as usize)
+            .map(|texel| texel[red] as f32 / 255.0)
+            .collect();
+
+        Some(Self {
+            width: size.width,
+            height: size.height,
+            values,
+            world_min,
+            world_size,
+        })
+    }
+
+    pub fn sample(&self, x: f32, z: f32) -> f32 {
+        if self.width == 0 || self.height == 0 {
+            return 0.0;
+        }
+        let uv = (Vec2::new(x, z) - self.world_min) / self.world_size;
+        let texel = uv * Vec2::new(self.width as f32, self.height as f32) - 0.5;
+        let base = texel.floor();
+        let t = texel - base;
+
+        let value = |dx: i32, dy: i32| {
+            let tx = (base.x as i32 + dx).clamp(0, self.width as i32 - 1) as usize;
+            let ty = (base.y as i32 + dy).clamp(0, self.height as i32 - 1) as usize;
+            self.values[ty * self.width as usize + tx]
+        };
+        let top = value(0, 0) * (1.0 - t.x) + value(1, 0) * t.x;
+        let bottom = value(0, 1) * (1.0 - t.x) + value(1, 1) * t.x;
+        top * (1.0 - t.y) + bottom * t.y
+    }
+}
@@ -0,0 +1,139 @@
+use bevy::{
+    ecs::system::SystemParam,
+    pbr::{
+        CubemapVisibleEntities, ExtractedDirectionalLight, ExtractedPointLight, LightEntity,
+        NotShadowCaster, RenderLightSystems, ViewLightEntities, SHADOW_FORMAT,
+    },
+    prelude::*,
+    render::{render_resource::*, view::VisibleEntities, Extract, RenderApp, RenderStage},
+    utils::HashMap,
+};
+
+pub(crate) struct ShadowViewPlugin;
+
+impl Plugin for ShadowViewPlugin {
+    fn build(&self, app: &mut App) {
+        app.sub_app_mut(RenderApp)
+            .init_resource::<ShadowViewOwners>()
+            .add_system_to_stage(
+                RenderStage::Prepare,
+                prepare_shadow_view_owners.after(RenderLightSystems::PrepareLights),
+            );
+    }
+}
+
+/// Camera view every shadow view is rendered for, so shadows use the culling, fade and lods of that camera
+#[derive(Default)]
+pub(crate) struct ShadowViewOwners(HashMap<Entity, Entity>);
+
+impl ShadowViewOwners {
+    /// The camera view of a shadow view, camera views are returned as they are
+    pub(crate) fn camera_view(&self, view: Entity) -> Entity {
+        self.0.get(&view).copied().unwrap_or(view)
+    }
+}
+
+//Bevy spawns the shadow views of every camera in prepare_lights
+fn prepare_shadow_view_owners(
+    mut owners: ResMut<ShadowViewOwners>,
+    views: Query<(Entity, &ViewLightEntities)>,
+) {
+    owners.0.clear();
+    for (view, view_lights) in &views {
+        for light_view in view_lights.lights.iter() {
+            owners.0.insert(*light_view, view);
+        }
+    }
+}
+
+/// Render world marker for visible chunks that are drawn into the shadow maps by this crate.
+/// Chunks always get bevy's `NotShadowCaster` as well, otherwise the stock shadow pass also draws their single unplaced mesh.
+#[derive(Component, Clone, Copy)]
+pub(crate) struct ShadowCaster;
+
+/// Add `NotShadowCaster` to a main world chunk to keep it out of the shadow maps, same as for a `PbrBundle`
+pub(crate) fn extract_shadow_casters<T: Component>(
+    mut commands: Commands,
+    mut previous_len: Local<usize>,
+    query: Extract<Query<(Entity, &ComputedVisibility, Option<&NotShadowCaster>), With<T>>>,
+) {
+    let mut casters = Vec::with_capacity(*previous_len);
+    let mut not_casters = Vec::new();
+    for (entity, computed_visibility, not_shadow_caster) in query.iter() {
+        if !computed_visibility.is_visible() {
+            continue;
+        }
+        match not_shadow_caster {
+            Some(_) => not_casters.push((entity, NotShadowCaster)),
+            None => casters.push((entity, (NotShadowCaster, ShadowCaster))),
+        }
+    }
+    *previous_len = casters.len();
+    commands.insert_or_spawn_batch(casters);
+    commands.insert_or_spawn_batch(not_casters);
+}
+
+/// Entities visible to a shadow casting light view, same lookup as bevy's queue_shadows
+#[derive(SystemParam)]
+pub(crate) struct LightVisibleEntities<'w, 's> {
+    point_lights: Query<'w, 's, &'static CubemapVisibleEntities, With<ExtractedPointLight>>,
+    directional_lights: Query<'w, 's, &'static VisibleEntities, With<ExtractedDirectionalLight>>,
+    spot_lights: Query<'w, 's, &'static VisibleEntities, With<ExtractedPointLight>>,
+}
+
+impl<'w, 's> LightVisibleEntities<'w, 's> {
+    pub(crate) fn get(&self, light_entity: &LightEntity) -> Option<&VisibleEntities> {
+        match light_entity {
+            LightEntity::Directional { light_entity } => self.directional_lights.get(*light_entity).ok(),
+            LightEntity::Point {
+                light_entity,
+                face_index,
+            } => self
+                .point_lights
+                .get(*light_entity)
+                .ok()
+                .map(|cubemap| cubemap.get(*face_index)),
+            LightEntity::Spot { light_entity } => self.spot_lights.get(*light_entity).ok(),
+        }
+    }
+}
+
+/// Turns a main pass descriptor into the depth only variant drawn into the shadow maps.
+/// The vertex stage is kept so the shadows move with the same placement and wind as the main pass.
+/// `depth_fragment` is the entry point that discards the cut out parts of alpha mask layers, without one there is no fragment stage
+pub(crate) fn make_depth_only(
+    descriptor: &mut RenderPipelineDescriptor,
+    shadow_view_layout: &BindGroupLayout,
+    depth_fragment: Option<&'static str>,
+) {
+    match depth_fragment {
+        Some(entry_point) => {
+            let fragment = descriptor.fragment.as_mut().unwrap();
+            fragment.entry_point = entry_point.into();
+            fragment.targets.clear();
+        }
+        None => descriptor.fragment = None,
+    }
+    descriptor.multisample = MultisampleState::default();
+    descriptor.primitive.cull_mode = None;
+    descriptor.depth_stencil = Some(DepthStencilState {
+        format: SHADOW_FORMAT,
+        depth_write_enabled: true,
+        depth_compare: CompareFunction::GreaterEqual,
+        stencil: StencilState {
+            front: StencilFaceState::IGNORE,
+            back: StencilFaceState::IGNORE,
+            read_mask: 0,
+            write_mask: 0,
+        },
+        bias: DepthBiasState {
+            constant: 0,
+            slope_scale: 0.0,
+            clamp: 0.0,
+        },
+    });
+    //Shadow views only bind the view uniform in group 0
+    if let Some(layout) = descriptor.layout.as_mut() {
+        layout[0] = shadow_view_layout.clone();
+    }
+}
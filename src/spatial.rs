@@ -0,0 +1,433 @@
+//! World space lookups of instances for gameplay code, e.g. the nearest tree, every bush around an animal
+//! or the instance under the cursor. Every `ChunkInstancing` gets an [`InstanceSpatialIndex`], [`ForestQuery`] searches them.
+
+use bevy::{
+    ecs::system::SystemParam, prelude::*, render::primitives::Aabb, transform::TransformSystem,
+    utils::HashMap,
+};
+
+use super::chunk_instancing::{ChunkInstancing, InstanceId};
+
+pub struct SpatialQueryPlugin;
+
+impl Plugin for SpatialQueryPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_to_stage(
+            CoreStage::PostUpdate,
+            update_instance_spatial_index.after(TransformSystem::TransformPropagate),
+        );
+    }
+}
+
+/// World space bounding spheres of the instances of one chunk, bucketed in a uniform grid over x and z.
+/// Rebuilt when the chunk's `ChunkInstancing` or `GlobalTransform` changes
+#[derive(Component, Clone, Debug, Default)]
+pub struct InstanceSpatialIndex {
+    /// Sorted by cell, `cell_starts[cell]..cell_starts[cell + 1]` are the spheres of a cell
+    spheres: Vec<InstanceSphere>,
+    cell_starts: Vec<u32>,
+    grid_min: Vec2,
+    cell_size: f32,
+    dims: UVec2,
+    bounds_min: Vec3,
+    bounds_max: Vec3,
+    max_radius: f32,
+    /// False while the mesh is loading, the spheres have no radius until it is
+    mesh_bounds: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct InstanceSphere {
+    id: InstanceId,
+    center: Vec3,
+    radius: f32,
+}
+
+impl InstanceSpatialIndex {
+    /// `mesh_aabb` is the local bounds of the chunk's mesh, the spheres enclose it for every instance
+    pub fn new(chunk_instancing: &ChunkInstancing, transform: &GlobalTransform, mesh_aabb: Option<&Aabb>) -> Self {
+        if chunk_instancing.has_stale_ids() {
+            warn!("ChunkInstancing::instances_mut resized the instances, its instances are left out of the spatial index until their ids are rebuilt by the next edit or set_instances");
+        }
+        let chunk_matrix = transform.compute_matrix();
+        let model_matrix = chunk_instancing.model_transform.compute_matrix();
+        let (center, half_extents) = mesh_aabb.map_or((Vec3::ZERO, Vec3::ZERO), |aabb| {
+            (Vec3::from(aabb.center), Vec3::from(aabb.half_extents))
+        });
+        let spheres: Vec<InstanceSphere> = chunk_instancing
+            .instances()
+            .iter()
+            .enumerate()
+            .filter_map(|(index, instance)| {
+                let matrix = chunk_matrix
+                    * instance.transform(chunk_instancing.instance_format).compute_matrix()
+                    * model_matrix;
+                let max_scale = matrix
+                    .x_axis
+                    .truncate()
+                    .length()
+                    .max(matrix.y_axis.truncate().length())
+                    .max(matrix.z_axis.truncate().length());
+                Some(InstanceSphere {
+                    id: chunk_instancing.instance_id(index)?,
+                    center: matrix.transform_point3(center),
+                    radius: half_extents.length() * max_scale,
+                })
+            })
+            .collect();
+        Self::from_spheres(spheres, mesh_aabb.is_some())
+    }
+
+    fn from_spheres(spheres: Vec<InstanceSphere>, mesh_bounds: bool) -> Self {
+        if spheres.is_empty() {
+            return Self {
+                mesh_bounds,
+                ..default()
+            };
+        }
+        let mut bounds_min = Vec3::splat(f32::MAX);
+        let mut bounds_max = Vec3::splat(f32::MIN);
+        let mut max_radius: f32 = 0.0;
+        for sphere in spheres.iter() {
+            bounds_min = bounds_min.min(sphere.center - sphere.radius);
+            bounds_max = bounds_max.max(sphere.center + sphere.radius);
+            max_radius = max_radius.max(sphere.radius);
+        }
+
+        //Cells hold the sphere centers, about two per cell
+        let grid_min = xz(bounds_min);
+        let extent = (xz(bounds_max) - grid_min).max(Vec2::splat(0.001));
+        let cell_size = (extent.x * extent.y / spheres.len() as f32 * 2.0).sqrt().max(0.01);
+        let dims = (extent / cell_size).as_uvec2() + UVec2::ONE;
+
+        let mut index = Self {
+            spheres: Vec::with_capacity(spheres.len()),
+            cell_starts: vec![0; (dims.x * dims.y) as usize + 1],
+            grid_min,
+            cell_size,
+            dims,
+            bounds_min,
+            bounds_max,
+            max_radius,
+            mesh_bounds,
+        };
+        //Counting sort by cell
+        let cells: Vec<usize> = spheres
+            .iter()
+            .map(|sphere| index.cell_index(index.cell_of(xz(sphere.center))))
+            .collect();
+        for cell in cells.iter() {
+            index.cell_starts[cell + 1] += 1;
+        }
+        for i in 1..index.cell_starts.len() {
+            index.cell_starts[i] += index.cell_starts[i - 1];
+        }
+        let mut next = index.cell_starts.clone();
+        let mut sorted = vec![None; spheres.len()];
+        for (sphere, cell) in spheres.into_iter().zip(cells) {
+            sorted[next[cell] as usize] = Some(sphere);
+            next[cell] += 1;
+        }
+        index.spheres = sorted.into_iter().flatten().collect();
+        index
+    }
+
+    pub fn len(&self) -> usize {
+        self.spheres.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.spheres.is_empty()
+    }
+
+    fn cell_of(&self, point: Vec2) -> IVec2 {
+        ((point - self.grid_min) / self.cell_size).floor().as_ivec2()
+    }
+
+    fn cell_index(&self, cell: IVec2) -> usize {
+        let cell = cell.clamp(IVec2::ZERO, self.dims.as_ivec2() - IVec2::ONE);
+        (cell.y as u32 * self.dims.x + cell.x as u32) as usize
+    }
+
+    fn cell(&self, cell: IVec2) -> &[InstanceSphere] {
+        let i = self.cell_index(cell);
+        &self.spheres[self.cell_starts[i] as usize..self.cell_starts[i + 1] as usize]
+    }
+
+    /// Cells whose centers can have spheres overlapping the x z rectangle
+    fn cells_in(&self, min: Vec2, max: Vec2) -> impl Iterator<Item = IVec2> {
+        let last = self.dims.as_ivec2() - IVec2::ONE;
+        let min = self.cell_of(min - self.max_radius).max(IVec2::ZERO);
+        let max = self.cell_of(max + self.max_radius).min(last);
+        (min.y..=max.y).flat_map(move |y| (min.x..=max.x).map(move |x| IVec2::new(x, y)))
+    }
+
+    /// Lower bound of the distance from `point` to the spheres of a cell
+    fn cell_distance(&self, cell: IVec2, point: Vec3) -> f32 {
+        let min = self.grid_min + cell.as_vec2() * self.cell_size;
+        let max = min + self.cell_size;
+        let point = xz(point);
+        let d = (min - point).max(point - max).max(Vec2::ZERO);
+        (d.length() - self.max_radius).max(0.0)
+    }
+
+    fn distance_to_bounds(&self, point: Vec3) -> f32 {
+        (self.bounds_min - point)
+            .max(point - self.bounds_max)
+            .max(Vec3::ZERO)
+            .length()
+    }
+
+    /// Distances along the ray at which it enters and leaves the bounds, None if it misses them.
+    /// Axes the ray is parallel to only check that the origin lies between the two planes, dividing by
+    /// the zero direction would give 0 * inf = NaN for origins on a plane
+    fn ray_bounds(&self, origin: Vec3, direction: Vec3, max_distance: f32) -> Option<(f32, f32)> {
+        let mut t_enter = 0.0f32;
+        let mut t_exit = max_distance;
+        for axis in 0..3 {
+            let (min, max) = (self.bounds_min[axis], self.bounds_max[axis]);
+            if direction[axis] == 0.0 {
+                if origin[axis] < min || origin[axis] > max {
+                    return None;
+                }
+                continue;
+            }
+            let t0 = (min - origin[axis]) / direction[axis];
+            let t1 = (max - origin[axis]) / direction[axis];
+            t_enter = t_enter.max(t0.min(t1));
+            t_exit = t_exit.min(t0.max(t1));
+        }
+        (t_enter <= t_exit).then_some((t_enter, t_exit))
+    }
+}
+
+fn xz(v: Vec3) -> Vec2 {
+    Vec2::new(v.x, v.z)
+}
+
+/// Distance from `point` to the surface of the sphere, 0 inside it
+fn sphere_distance(sphere: &InstanceSphere, point: Vec3) -> f32 {
+    (sphere.center.distance(point) - sphere.radius).max(0.0)
+}
+
+/// Distance along the normalized ray to the sphere, 0 when starting inside it
+fn ray_sphere(sphere: &InstanceSphere, origin: Vec3, direction: Vec3) -> Option<f32> {
+    let oc = origin - sphere.center;
+    let b = oc.dot(direction);
+    let c = oc.length_squared() - sphere.radius * sphere.radius;
+    let discriminant = b * b - c;
+    if discriminant < 0.0 {
+        return None;
+    }
+    let far = -b + discriminant.sqrt();
+    (far >= 0.0).then(|| (-b - discriminant.sqrt()).max(0.0))
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct InstanceHit<'a> {
+    pub chunk: Entity,
+    /// `Name` of the chunk entity, e.g. "Tree"
+    pub layer: Option<&'a str>,
+    pub id: InstanceId,
+    /// Center of the instance's bounding sphere
+    pub position: Vec3,
+    /// To the surface of the bounding sphere, or along the ray for [`ForestQuery::raycast`]
+    pub distance: f32,
+}
+
+/// Searches the instances of every chunk, `layer` filters by the `Name` of the chunk entities
+#[derive(SystemParam)]
+pub struct ForestQuery<'w, 's> {
+    chunks: Query<'w, 's, (Entity, &'static InstanceSpatialIndex, Option<&'static Name>)>,
+}
+
+impl<'w, 's> ForestQuery<'w, 's> {
+    fn layers(&self, layer: Option<&str>) -> Vec<(Entity, &InstanceSpatialIndex, Option<&str>)> {
+        self.chunks
+            .iter()
+            .map(|(entity, index, name)| (entity, index, name.map(|name| name.as_str())))
+            .filter(|(_, index, name)| {
+                !index.is_empty() && layer.map_or(true, |layer| *name == Some(layer))
+            })
+            .collect()
+    }
+
+    /// Instances whose bounding sphere is within `radius` of `point`, unordered
+    pub fn within_radius(&self, point: Vec3, radius: f32, layer: Option<&str>) -> Vec<InstanceHit<'_>> {
+        let mut hits = Vec::new();
+        for (chunk, index, name) in self.layers(layer) {
+            if index.distance_to_bounds(point) > radius {
+                continue;
+            }
+            for cell in index.cells_in(xz(point) - radius, xz(point) + radius) {
+                for sphere in index.cell(cell) {
+                    let distance = sphere_distance(sphere, point);
+                    if distance <= radius {
+                        hits.push(InstanceHit {
+                            chunk,
+                            layer: name,
+                            id: sphere.id,
+                            position: sphere.center,
+                            distance,
+                        });
+                    }
+                }
+            }
+        }
+        hits
+    }
+
+    /// The `k` instances closest to `point`, closest first
+    pub fn nearest(&self, point: Vec3, k: usize, layer: Option<&str>) -> Vec<InstanceHit<'_>> {
+        let mut chunks: Vec<_> = self
+            .layers(layer)
+            .into_iter()
+            .map(|chunk| (chunk.1.distance_to_bounds(point), chunk))
+            .collect();
+        chunks.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        let mut best: Vec<InstanceHit> = Vec::with_capacity(k + 1);
+        let worst = |best: &Vec<InstanceHit>| match best.len() < k {
+            true => f32::MAX,
+            false => best.last().map_or(f32::MAX, |hit| hit.distance),
+        };
+        for (bounds_distance, (chunk, index, name)) in chunks {
+            if k == 0 || bounds_distance > worst(&best) {
+                break;
+            }
+            for y in 0..index.dims.y as i32 {
+                for x in 0..index.dims.x as i32 {
+                    let cell = IVec2::new(x, y);
+                    if index.cell_distance(cell, point) > worst(&best) {
+                        continue;
+                    }
+                    for sphere in index.cell(cell) {
+                        let distance = sphere_distance(sphere, point);
+                        if distance >= worst(&best) {
+                            continue;
+                        }
+                        let at = best.partition_point(|hit| hit.distance <= distance);
+                        best.insert(
+                            at,
+                            InstanceHit {
+                                chunk,
+                                layer: name,
+                                id: sphere.id,
+                                position: sphere.center,
+                                distance,
+                            },
+                        );
+                        best.truncate(k);
+                    }
+                }
+            }
+        }
+        best
+    }
+
+    /// First instance whose bounding sphere the ray hits, e.g. from the camera through the cursor
+    pub fn raycast(
+        &self,
+        origin: Vec3,
+        direction: Vec3,
+        max_distance: f32,
+        layer: Option<&str>,
+    ) -> Option<InstanceHit<'_>> {
+        let direction = direction.normalize();
+        let mut chunks: Vec<_> = self
+            .layers(layer)
+            .into_iter()
+            .filter_map(|chunk| Some((chunk.1.ray_bounds(origin, direction, max_distance)?, chunk)))
+            .collect();
+        chunks.sort_by(|a, b| a.0 .0.total_cmp(&b.0 .0));
+
+        let mut best: Option<InstanceHit> = None;
+        for ((t_enter, t_exit), (chunk, index, name)) in chunks {
+            if best.map_or(false, |best| best.distance < t_enter) {
+                break;
+            }
+            //Steps of half a cell touch every cell under the ray, the neighbours cover spheres reaching into it
+            let reach = (index.max_radius / index.cell_size).ceil() as i32 + 1;
+            let step = index.cell_size * 0.5;
+            let mut visited = vec![false; (index.dims.x * index.dims.y) as usize];
+            let mut t = t_enter;
+            loop {
+                let center = index.cell_of(xz(origin + direction * t.min(t_exit)));
+                for y in center.y - reach..=center.y + reach {
+                    for x in center.x - reach..=center.x + reach {
+                        let cell = IVec2::new(x, y);
+                        if cell.cmplt(IVec2::ZERO).any() || cell.cmpge(index.dims.as_ivec2()).any() {
+                            continue;
+                        }
+                        let i = index.cell_index(cell);
+                        if std::mem::replace(&mut visited[i], true) {
+                            continue;
+                        }
+                        for sphere in index.cell(cell) {
+                            let distance = match ray_sphere(sphere, origin, direction) {
+                                Some(distance) if distance <= max_distance => distance,
+                                _ => continue,
+                            };
+                            if best.map_or(true, |best| distance < best.distance) {
+                                best = Some(InstanceHit {
+                                    chunk,
+                                    layer: name,
+                                    id: sphere.id,
+                                    position: sphere.center,
+                                    distance,
+                                });
+                            }
+                        }
+                    }
+                }
+                if t >= t_exit {
+                    break;
+                }
+                t += step;
+            }
+        }
+        best
+    }
+}
+
+fn update_instance_spatial_index(
+    mut commands: Commands,
+    meshes: Res<Assets<Mesh>>,
+    mut chunks: Query<(
+        Entity,
+        &ChunkInstancing,
+        &GlobalTransform,
+        &Handle<Mesh>,
+        Option<&mut InstanceSpatialIndex>,
+        ChangeTrackers<ChunkInstancing>,
+        ChangeTrackers<GlobalTransform>,
+    )>,
+) {
+    //Most chunks of a layer share the mesh
+    let mut mesh_aabbs: HashMap<Handle<Mesh>, Option<Aabb>> = HashMap::default();
+    for (entity, chunk_instancing, transform, mesh, index, instancing_tracker, transform_tracker) in
+        chunks.iter_mut()
+    {
+        let changed = instancing_tracker.is_changed() || transform_tracker.is_changed();
+        if !changed && index.as_ref().map_or(false, |index| index.mesh_bounds) {
+            continue;
+        }
+        let mesh_aabb = mesh_aabbs
+            .entry(mesh.clone_weak())
+            .or_insert_with(|| meshes.get(mesh).and_then(|mesh| mesh.compute_aabb()));
+        match index {
+            //Still waiting for the mesh
+            Some(_) if !changed && mesh_aabb.is_none() => {}
+            Some(mut index) => {
+                *index = InstanceSpatialIndex::new(chunk_instancing, transform, mesh_aabb.as_ref())
+            }
+            None => {
+                commands.entity(entity).insert(InstanceSpatialIndex::new(
+                    chunk_instancing,
+                    transform,
+                    mesh_aabb.as_ref(),
+                ));
+            }
+        }
+    }
+}
@@ -0,0 +1,641 @@
+//! Heightfield shared by the grass shader, instance placement, the ground mesh and gameplay code.
+//! Insert a [`ForestTerrain`] resource to leave the flat world, without one everything stays at y = 0.
+
+use bevy::{
+    prelude::*,
+    render::{
+        extract_resource::{ExtractResource, ExtractResourcePlugin},
+        mesh::Indices,
+        primitives::Aabb,
+        render_resource::{
+            AddressMode, Extent3d, FilterMode, PrimitiveTopology, SamplerDescriptor, ShaderType,
+            TextureDimension, TextureFormat,
+        },
+        texture::{CompressedImageFormats, ImageSampler, ImageType},
+    },
+    utils::HashMap,
+};
+use bytemuck::{Pod, Zeroable};
+use noise::{NoiseFn, Perlin, Seedable};
+
+use super::{
+    chunk_instancing::{select_lod, MeshLod},
+    scatter::Heightfield,
+    ChunkEnteredRange, ChunkLeftRange, DistanceCulling,
+};
+
+pub struct TerrainPlugin;
+
+impl Plugin for TerrainPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugin(ExtractResourcePlugin::<TerrainTexture>::default())
+            .init_resource::<TerrainTexture>()
+            .add_system(update_terrain_texture)
+            .add_system(update_terrain_lods);
+    }
+}
+
+/// Grid of heights in meters, sample `(x, z)` sits at `origin + (x, z) / (resolution - 1) * size`.
+/// Heights are world y for chunks placed at y = 0, outside of the grid the border heights continue.
+#[derive(Clone, Debug)]
+pub struct ForestTerrain {
+    pub resolution: UVec2,
+    /// Row major, `resolution.x * resolution.y` heights
+    pub heights: Vec<f32>,
+    /// World xz of the first sample
+    pub origin: Vec2,
+    /// World size covered by the grid
+    pub size: Vec2,
+}
+
+#[derive(Debug)]
+pub enum TerrainError {
+    Decode(String),
+    UnsupportedFormat(TextureFormat),
+    SizeMismatch { expected: usize, actual: usize },
+    TooSmall(UVec2),
+}
+
+impl std::fmt::Display for TerrainError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TerrainError::Decode(error) => write!(f, "could not decode heightmap: {error}"),
+            TerrainError::UnsupportedFormat(format) => {
+                write!(f, "heightmaps have to be 8 or 16 bit grayscale or r32float, got {format:?}")
+            }
+            TerrainError::SizeMismatch { expected, actual } => {
+                write!(f, "heightmap should have {expected} bytes, got {actual}")
+            }
+            TerrainError::TooSmall(resolution) => {
+                write!(f, "heightmaps need at least 2x2 samples, got {}x{}", resolution.x, resolution.y)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TerrainError {}
+
+impl ForestTerrain {
+    pub fn new(resolution: UVec2, heights: Vec<f32>, origin: Vec2, size: Vec2) -> Self {
+        assert_eq!(
+            heights.len(),
+            (resolution.x * resolution.y) as usize,
+            "terrain needs one height per sample"
+        );
+        assert!(resolution.x >= 2 && resolution.y >= 2, "terrain needs at least 2x2 samples");
+        Self {
+            resolution,
+            heights,
+            origin,
+            size,
+        }
+    }
+
+    /// Layered Perlin noise, `amplitude` is the largest height difference from 0
+    pub fn from_noise(
+        seed: u32,
+        resolution: UVec2,
+        origin: Vec2,
+        size: Vec2,
+        amplitude: f32,
+        frequency: f64,
+    ) -> Self {
+        const OCTAVES: u32 = 4;
+        let perlin = Perlin::new().set_seed(seed);
+        let spacing = size / (resolution - UVec2::ONE).as_vec2();
+
+        let mut heights = Vec::with_capacity((resolution.x * resolution.y) as usize);
+        for z in 0..resolution.y {
+            for x in 0..resolution.x {
+                let position = origin + UVec2::new(x, z).as_vec2() * spacing;
+                let mut height = 0.0;
+                let mut octave_amplitude = 0.5;
+                let mut octave_frequency = frequency;
+                for _ in 0..OCTAVES {
+                    height += perlin.get([
+                        position.x as f64 * octave_frequency,
+                        position.y as f64 * octave_frequency,
+                    ]) * octave_amplitude;
+                    octave_amplitude *= 0.5;
+                    octave_frequency *= 2.0;
+                }
+                heights.push(height as f32 * amplitude);
+            }
+        }
+        Self::new(resolution, heights, origin, size)
+    }
+
+    /// 8 and 16 bit grayscale images are mapped to [min_height, max_height], r32float is used as meters.
+    /// A 16 bit png loaded by the asset server is `R16Uint`.
+    pub fn from_image(
+        image: &Image,
+        origin: Vec2,
+        size: Vec2,
+        min_height: f32,
+        max_height: f32,
+    ) -> Result<Self, TerrainError> {
+        let extent = image.texture_descriptor.size;
+        let resolution = UVec2::new(extent.width, extent.height);
+        if resolution.x < 2 || resolution.y < 2 {
+            return Err(TerrainError::TooSmall(resolution));
+        }
+        let range = max_height - min_height;
+        let heights: Vec<f32> = match image.texture_descriptor.format {
+            TextureFormat::R8Unorm => image
+                .data
+                .iter()
+                .map(|value| min_height + *value as f32 / 255.0 * range)
+                .collect(),
+            TextureFormat::R16Uint | TextureFormat::R16Unorm => image
+                .data
+                .chunks_exact(2)
+                .map(|bytes| {
+                    let value = u16::from_ne_bytes([bytes[0], bytes[1]]);
+                    min_height + value as f32 / 65535.0 * range
+                })
+                .collect(),
+            TextureFormat::R32Float => image
+                .data
+                .chunks_exact(4)
+                .map(|bytes| f32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+                .collect(),
+            format => return Err(TerrainError::UnsupportedFormat(format)),
+        };
+
+        let expected = (resolution.x * resolution.y) as usize;
+        if heights.len() != expected {
+            return Err(TerrainError::SizeMismatch {
+                expected: expected * image.texture_descriptor.format.describe().block_size as usize,
+                actual: image.data.len(),
+            });
+        }
+        Ok(Self::new(resolution, heights, origin, size))
+    }
+
+    /// Decodes a 16 bit grayscale png, see [`Self::from_image`]
+    pub fn from_png(
+        bytes: &[u8],
+        origin: Vec2,
+        size: Vec2,
+        min_height: f32,
+        max_height: f32,
+    ) -> Result<Self, TerrainError> {
+        let image = Image::from_buffer(
+            bytes,
+            ImageType::Extension("png"),
+            CompressedImageFormats::NONE,
+            false,
+        )
+        .map_err(|error| TerrainError::Decode(error.to_string()))?;
+        Self::from_image(&image, origin, size, min_height, max_height)
+    }
+
+    /// Headerless little endian 16 bit heights as exported by most terrain tools
+    pub fn from_raw(
+        bytes: &[u8],
+        resolution: UVec2,
+        origin: Vec2,
+        size: Vec2,
+        min_height: f32,
+        max_height: f32,
+    ) -> Result<Self, TerrainError> {
+        if resolution.x < 2 || resolution.y < 2 {
+            return Err(TerrainError::TooSmall(resolution));
+        }
+        let expected = (resolution.x * resolution.y * 2) as usize;
+        if bytes.len() != expected {
+            return Err(TerrainError::SizeMismatch {
+                expected,
+                actual: bytes.len(),
+            });
+        }
+        let heights = bytes
+            .chunks_exact(2)
+            .map(|bytes| {
+                let value = u16::from_le_bytes([bytes[0], bytes[1]]);
+                min_height + value as f32 / 65535.0 * (max_height - min_height)
+            })
+            .collect();
+        Ok(Self::new(resolution, heights, origin, size))
+    }
+
+    /// World distance between two samples
+    pub fn spacing(&self) -> Vec2 {
+        self.size / (self.resolution - UVec2::ONE).as_vec2()
+    }
+
+    fn sample(&self, x: u32, z: u32) -> f32 {
+        self.heights[(z * self.resolution.x + x) as usize]
+    }
+
+    /// Bilinear height, same as `terrain_height` in grass.wgsl
+    pub fn height_at(&self, x: f32, z: f32) -> f32 {
+        let max = (self.resolution - UVec2::ONE).as_vec2();
+        let texel = ((Vec2::new(x, z) - self.origin) / self.spacing()).clamp(Vec2::ZERO, max);
+        let x0 = texel.x.floor() as u32;
+        let z0 = texel.y.floor() as u32;
+        let x1 = (x0 + 1).min(self.resolution.x - 1);
+        let z1 = (z0 + 1).min(self.resolution.y - 1);
+        let t = texel - Vec2::new(x0 as f32, z0 as f32);
+
+        let top = self.sample(x0, z0) * (1.0 - t.x) + self.sample(x1, z0) * t.x;
+        let bottom = self.sample(x0, z1) * (1.0 - t.x) + self.sample(x1, z1) * t.x;
+        top * (1.0 - t.y) + bottom * t.y
+    }
+
+    /// Central differences over one sample spacing
+    pub fn normal_at(&self, x: f32, z: f32) -> Vec3 {
+        let spacing = self.spacing();
+        let dx = self.height_at(x + spacing.x, z) - self.height_at(x - spacing.x, z);
+        let dz = self.height_at(x, z + spacing.y) - self.height_at(x, z - spacing.y);
+        Vec3::new(-dx * spacing.y, 2.0 * spacing.x * spacing.y, -dz * spacing.x).normalize()
+    }
+
+    /// Lowest and highest height inside the world rectangle, e.g. for chunk `Aabb`s
+    pub fn height_range(&self, min: Vec2, max: Vec2) -> (f32, f32) {
+        let last = (self.resolution - UVec2::ONE).as_vec2();
+        let first_texel = ((min - self.origin) / self.spacing()).floor().clamp(Vec2::ZERO, last);
+        let last_texel = ((max - self.origin) / self.spacing()).ceil().clamp(Vec2::ZERO, last);
+
+        let mut range = (f32::MAX, f32::MIN);
+        for z in first_texel.y as u32..=last_texel.y as u32 {
+            for x in first_texel.x as u32..=last_texel.x as u32 {
+                let height = self.sample(x, z);
+                range = (range.0.min(height), range.1.max(height));
+            }
+        }
+        range
+    }
+
+    /// Heights as an `R32Float` image, not filterable so the shader does the bilinear filtering itself
+    pub fn to_image(&self) -> Image {
+        Image::new(
+            Extent3d {
+                width: self.resolution.x,
+                height: self.resolution.y,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            bytemuck::cast_slice(&self.heights).to_vec(),
+            TextureFormat::R32Float,
+        )
+    }
+
+    /// Ground mesh with one vertex per sample, uvs go from 0 to 1 over the whole terrain
+    pub fn mesh(&self) -> Mesh {
+        let spacing = self.spacing();
+        let mut positions = Vec::with_capacity(self.heights.len());
+        let mut normals = Vec::with_capacity(self.heights.len());
+        let mut uvs = Vec::with_capacity(self.heights.len());
+        for z in 0..self.resolution.y {
+            for x in 0..self.resolution.x {
+                let position = self.origin + Vec2::new(x as f32, z as f32) * spacing;
+                positions.push([position.x, self.sample(x, z), position.y]);
+                normals.push(self.normal_at(position.x, position.y).to_array());
+                uvs.push([
+                    x as f32 / (self.resolution.x - 1) as f32,
+                    z as f32 / (self.resolution.y - 1) as f32,
+                ]);
+            }
+        }
+
+        let mut indices = Vec::new();
+        let width = self.resolution.x;
+        for z in 0..self.resolution.y - 1 {
+            for x in 0..width - 1 {
+                let i = z * width + x;
+                indices.extend_from_slice(&[i, i + width, i + 1, i + 1, i + width, i + width + 1]);
+            }
+        }
+
+        let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+        mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+        mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
+        mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
+        mesh.set_indices(Some(Indices::U32(indices)));
+        mesh
+    }
+}
+
+impl ForestTerrain {
+    /// Ground mesh of one forest chunk with `subdivisions` quads per side, positions relative to `chunk_origin`.
+    ///
+    /// Uvs are world position / `uv_tile_size` so textures tile seamlessly across chunks.
+    /// A skirt hangs `skirt_depth` down along the border to hide the cracks next to chunks with another lod.
+    pub fn chunk_mesh(
+        &self,
+        chunk_origin: Vec2,
+        chunk_size: f32,
+        subdivisions: u32,
+        uv_tile_size: f32,
+        skirt_depth: f32,
+    ) -> Mesh {
+        let n = subdivisions.max(1);
+        let width = n + 1;
+        let step = chunk_size / n as f32;
+
+        let mut positions = Vec::with_capacity((width * width + 4 * n) as usize);
+        let mut normals = Vec::with_capacity(positions.capacity());
+        let mut uvs = Vec::with_capacity(positions.capacity());
+        for z in 0..width {
+            for x in 0..width {
+                let local = Vec2::new(x as f32, z as f32) * step;
+                let world = chunk_origin + local;
+                positions.push([local.x, self.height_at(world.x, world.y), local.y]);
+                normals.push(self.normal_at(world.x, world.y).to_array());
+                uvs.push((world / uv_tile_size).to_array());
+            }
+        }
+
+        let mut indices = Vec::with_capacity((n * n * 6 + 4 * n * 6) as usize);
+        for z in 0..n {
+            for x in 0..n {
+                let i = z * width + x;
+                indices.extend_from_slice(&[i, i + width, i + 1, i + 1, i + width, i + width + 1]);
+            }
+        }
+
+        //Border walked so the skirt faces outwards: -z edge, -x edge, +z edge, +x edge
+        let border: Vec<u32> = (0..n)
+            .map(|x| n - x)
+            .chain((0..n).map(|z| z * width))
+            .chain((0..n).map(|x| n * width + x))
+            .chain((0..n).map(|z| (n - z) * width + n))
+            .collect();
+        let first_skirt = positions.len() as u32;
+        for top in border.iter() {
+            let [x, y, z] = positions[*top as usize];
+            positions.push([x, y - skirt_depth, z]);
+            normals.push(normals[*top as usize]);
+            uvs.push(uvs[*top as usize]);
+        }
+        for k in 0..border.len() {
+            let next = (k + 1) % border.len();
+            let (a, b) = (border[k], border[next]);
+            let (a_low, b_low) = (first_skirt + k as u32, first_skirt + next as u32);
+            indices.extend_from_slice(&[a, a_low, b, b, a_low, b_low]);
+        }
+
+        let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+        mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+        mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
+        mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
+        mesh.set_indices(Some(Indices::U32(indices)));
+        mesh
+    }
+
+    /// All lods of one chunk, see [`TerrainMeshSettings`]
+    pub fn terrain_chunk(
+        &self,
+        chunk_origin: Vec2,
+        chunk_size: f32,
+        settings: &TerrainMeshSettings,
+        meshes: &mut Assets<Mesh>,
+    ) -> TerrainChunk {
+        let lods = settings
+            .lods
+            .iter()
+            .map(|lod| MeshLod {
+                mesh: meshes.add(self.chunk_mesh(
+                    chunk_origin,
+                    chunk_size,
+                    lod.subdivisions,
+                    settings.uv_tile_size,
+                    settings.skirt_depth,
+                )),
+                distance: lod.distance,
+            })
+            .collect();
+        let (min_height, max_height) =
+            self.height_range(chunk_origin, chunk_origin + chunk_size);
+
+        TerrainChunk {
+            lods,
+            aabb: Aabb::from_min_max(
+                Vec3::new(0.0, min_height - settings.skirt_depth, 0.0),
+                Vec3::new(chunk_size, max_height, chunk_size),
+            ),
+        }
+    }
+}
+
+impl Heightfield for ForestTerrain {
+    fn height_at(&self, x: f32, z: f32) -> f32 {
+        ForestTerrain::height_at(self, x, z)
+    }
+
+    fn normal_at(&self, x: f32, z: f32) -> Vec3 {
+        ForestTerrain::normal_at(self, x, z)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct TerrainLodSettings {
+    /// Quads per chunk side
+    pub subdivisions: u32,
+    /// Distance from the camera to the chunk center from which this lod is used
+    pub distance: f32,
+}
+
+#[derive(Clone, Debug)]
+pub struct TerrainMeshSettings {
+    pub lods: Vec<TerrainLodSettings>,
+    /// World size of one repetition of the ground texture
+    pub uv_tile_size: f32,
+    pub skirt_depth: f32,
+}
+
+impl Default for TerrainMeshSettings {
+    fn default() -> Self {
+        Self {
+            lods: vec![
+                TerrainLodSettings {
+                    subdivisions: 32,
+                    distance: 0.0,
+                },
+                TerrainLodSettings {
+                    subdivisions: 16,
+                    distance: 100.0,
+                },
+                TerrainLodSettings {
+                    subdivisions: 4,
+                    distance: 300.0,
+                },
+            ],
+            uv_tile_size: 8.0,
+            skirt_depth: 1.0,
+        }
+    }
+}
+
+/// Ground of one forest chunk, the entity's `Handle<Mesh>` is swapped to the lod matching the camera distance
+#[derive(Component, Clone, Debug, Default)]
+pub struct TerrainChunk {
+    pub lods: Vec<MeshLod>,
+    /// Bounds of all lods including the skirt, relative to the chunk corner
+    pub aabb: Aabb,
+}
+
+/// Spawn as a child of the chunk entity next to the grass and instancing children
+#[derive(Bundle, Default)]
+pub struct TerrainChunkBundle {
+    pub mesh: Handle<Mesh>,
+    pub material: Handle<StandardMaterial>,
+    pub transform: Transform,
+    pub global_transform: GlobalTransform,
+    pub visibility: Visibility,
+    pub computed_visibility: ComputedVisibility,
+    pub aabb: Aabb,
+    pub terrain_chunk: TerrainChunk,
+    pub distance_culling: DistanceCulling,
+}
+
+impl TerrainChunkBundle {
+    pub fn new(terrain_chunk: TerrainChunk, material: Handle<StandardMaterial>) -> Self {
+        Self {
+            mesh: terrain_chunk
+                .lods
+                .first()
+                .map(|lod| lod.mesh.clone())
+                .unwrap_or_default(),
+            material,
+            aabb: terrain_chunk.aabb.clone(),
+            terrain_chunk,
+            //The pbr shader can't dither, the ground pops at the end of the hysteresis instead
+            distance_culling: DistanceCulling {
+                fade_band: 0.0,
+                ..default()
+            },
+            ..default()
+        }
+    }
+}
+
+/// Lets a texture repeat over the tiled uvs of the terrain chunks
+pub fn set_repeat_sampler(image: &mut Image) {
+    image.sampler_descriptor = ImageSampler::Descriptor(SamplerDescriptor {
+        address_mode_u: AddressMode::Repeat,
+        address_mode_v: AddressMode::Repeat,
+        mag_filter: FilterMode::Linear,
+        min_filter: FilterMode::Linear,
+        mipmap_filter: FilterMode::Linear,
+        ..default()
+    });
+}
+
+/// Only the cameras that draw a chunk pick its lod, inactive cameras and cameras that culled the chunk are ignored.
+/// Chunks with `DistanceCulling` follow the range events of the culling, the others every active camera
+fn update_terrain_lods(
+    mut cameras_in_range: Local<HashMap<Entity, Vec<Entity>>>,
+    mut entered: EventReader<ChunkEnteredRange>,
+    mut left: EventReader<ChunkLeftRange>,
+    cameras: Query<(Entity, &Camera, &GlobalTransform)>,
+    mut chunks: Query<(
+        Entity,
+        &GlobalTransform,
+        &TerrainChunk,
+        &mut Handle<Mesh>,
+        Option<&DistanceCulling>,
+    )>,
+) {
+    for event in left.iter() {
+        if let Some(in_range) = cameras_in_range.get_mut(&event.chunk) {
+            in_range.retain(|camera| *camera != event.camera);
+        }
+    }
+    for event in entered.iter() {
+        let in_range = cameras_in_range.entry(event.chunk).or_default();
+        if !in_range.contains(&event.camera) {
+            in_range.push(event.camera);
+        }
+    }
+    //Forget despawned chunks
+    cameras_in_range.retain(|chunk, _| chunks.contains(*chunk));
+
+    for (entity, transform, terrain_chunk, mut mesh, distance_culling) in chunks.iter_mut() {
+        let center = transform
+            .compute_matrix()
+            .transform_point3(terrain_chunk.aabb.center.into());
+        //Closest camera wins when there are several
+        let distance = cameras
+            .iter()
+            .filter(|(_, camera, _)| camera.is_active)
+            .filter(|(camera, _, _)| {
+                distance_culling.is_none()
+                    || cameras_in_range
+                        .get(&entity)
+                        .map_or(false, |in_range| in_range.contains(camera))
+            })
+            .map(|(_, _, camera_transform)| camera_transform.translation().distance(center))
+            .fold(f32::INFINITY, f32::min);
+        if distance == f32::INFINITY {
+            continue; //Not drawn by any camera, keep the lod it has
+        }
+        if let Some(lod) = select_lod(&terrain_chunk.lods, distance) {
+            if *mesh != terrain_chunk.lods[lod].mesh {
+                *mesh = terrain_chunk.lods[lod].mesh.clone();
+            }
+        }
+    }
+}
+
+/// Gpu side of the [`ForestTerrain`], a flat 2x2 grid until a terrain is inserted
+#[derive(Clone)]
+pub struct TerrainTexture {
+    pub image: Handle<Image>,
+    pub origin: Vec2,
+    pub size: Vec2,
+    pub resolution: UVec2,
+}
+
+impl FromWorld for TerrainTexture {
+    fn from_world(world: &mut World) -> Self {
+        let flat = ForestTerrain::new(UVec2::splat(2), vec![0.0; 4], Vec2::ZERO, Vec2::ONE);
+        let mut images = world.resource_mut::<Assets<Image>>();
+        Self {
+            image: images.add(flat.to_image()),
+            origin: flat.origin,
+            size: flat.size,
+            resolution: flat.resolution,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Zeroable, Pod, ShaderType)]
+pub(crate) struct GpuTerrain {
+    pub origin: [f32; 2],
+    pub size: [f32; 2],
+    pub resolution: [i32; 4],
+}
+
+impl TerrainTexture {
+    pub(crate) fn to_raw(&self) -> GpuTerrain {
+        GpuTerrain {
+            origin: self.origin.to_array(),
+            size: self.size.to_array(),
+            resolution: [self.resolution.x as i32, self.resolution.y as i32, 0, 0],
+        }
+    }
+}
+
+impl ExtractResource for TerrainTexture {
+    type Source = TerrainTexture;
+
+    fn extract_resource(res: &Self::Source) -> Self {
+        res.clone()
+    }
+}
+
+fn update_terrain_texture(
+    terrain: Option<Res<ForestTerrain>>,
+    mut terrain_texture: ResMut<TerrainTexture>,
+    mut images: ResMut<Assets<Image>>,
+) {
+    if let Some(terrain) = terrain.filter(|terrain| terrain.is_changed()) {
+        images.set_untracked(terrain_texture.image.clone(), terrain.to_image());
+        terrain_texture.origin = terrain.origin;
+        terrain_texture.size = terrain.size;
+        terrain_texture.resolution = terrain.resolution;
+    }
+}
@@ -0,0 +1,205 @@
+use bevy::{ecs::system::SystemState, prelude::*, render::primitives::Aabb};
+use bevy_efficient_forest_rendering::{
+    chunk_instancing::{ChunkInstancing, Instance, InstanceFormat, InstanceId},
+    rng::ForestRng,
+    spatial::{ForestQuery, InstanceHit, InstanceSpatialIndex},
+};
+
+/// Bounding sphere of an instance, computed the long way
+struct Sphere {
+    chunk: Entity,
+    layer: &'static str,
+    id: InstanceId,
+    center: Vec3,
+    radius: f32,
+}
+
+const MESH_CENTER: Vec3 = Vec3::new(0.0, 1.0, 0.0);
+const MESH_HALF_EXTENTS: Vec3 = Vec3::new(0.5, 1.0, 0.5);
+
+fn spawn_chunk(world: &mut World, spheres: &mut Vec<Sphere>, layer: &'static str, translation: Vec3, seed: u64) {
+    let mut rng = ForestRng::new(seed);
+    let instances: Vec<Instance> = (0..200)
+        .map(|_| {
+            let mut instance = Instance::new(
+                Vec3::new(rng.range(0.0, 30.0), rng.range(0.0, 2.0), rng.range(0.0, 30.0)),
+                Quat::IDENTITY,
+                Vec3::ONE,
+            );
+            instance.pos_xyz[3] = rng.range(0.5, 2.0);
+            instance
+        })
+        .collect();
+    let mut chunk_instancing = ChunkInstancing::default();
+    chunk_instancing.instance_format = InstanceFormat::Full;
+    chunk_instancing.set_instances(instances);
+
+    let transform = GlobalTransform::from_translation(translation);
+    let aabb = Aabb {
+        center: MESH_CENTER.into(),
+        half_extents: MESH_HALF_EXTENTS.into(),
+    };
+    let index = InstanceSpatialIndex::new(&chunk_instancing, &transform, Some(&aabb));
+    assert_eq!(index.len(), 200);
+    let chunk = world.spawn().insert(index).insert(Name::new(layer)).id();
+
+    for (i, instance) in chunk_instancing.instances().iter().enumerate() {
+        let scale = instance.pos_xyz[3];
+        spheres.push(Sphere {
+            chunk,
+            layer,
+            id: chunk_instancing.instance_id(i).unwrap(),
+            center: translation + instance.translation() + MESH_CENTER * scale,
+            radius: MESH_HALF_EXTENTS.length() * scale,
+        });
+    }
+}
+
+fn forest() -> (World, Vec<Sphere>) {
+    let mut world = World::new();
+    let mut spheres = Vec::new();
+    spawn_chunk(&mut world, &mut spheres, "Tree", Vec3::ZERO, 1);
+    spawn_chunk(&mut world, &mut spheres, "Tree", Vec3::new(30.0, 0.0, 0.0), 2);
+    spawn_chunk(&mut world, &mut spheres, "Bush", Vec3::new(0.0, 0.5, 30.0), 3);
+    (world, spheres)
+}
+
+fn sphere_distance(sphere: &Sphere, point: Vec3) -> f32 {
+    (sphere.center.distance(point) - sphere.radius).max(0.0)
+}
+
+fn ray_distance(sphere: &Sphere, origin: Vec3, direction: Vec3) -> Option<f32> {
+    let oc = origin - sphere.center;
+    let b = oc.dot(direction);
+    let discriminant = b * b - (oc.length_squared() - sphere.radius * sphere.radius);
+    if discriminant < 0.0 || -b + discriminant.sqrt() < 0.0 {
+        return None;
+    }
+    Some((-b - discriminant.sqrt()).max(0.0))
+}
+
+fn filtered<'a>(spheres: &'a [Sphere], layer: Option<&'a str>) -> impl Iterator<Item = &'a Sphere> {
+    spheres
+        .iter()
+        .filter(move |sphere| layer.map_or(true, |layer| sphere.layer == layer))
+}
+
+fn keys(hits: &[InstanceHit]) -> Vec<(Entity, InstanceId)> {
+    let mut keys: Vec<_> = hits.iter().map(|hit| (hit.chunk, hit.id)).collect();
+    keys.sort();
+    keys
+}
+
+fn query_points() -> Vec<Vec3> {
+    let mut rng = ForestRng::new(99);
+    let mut points = vec![Vec3::new(-20.0, 0.0, -20.0), Vec3::new(80.0, 10.0, 15.0)];
+    points.extend((0..20).map(|_| Vec3::new(rng.range(-5.0, 65.0), rng.range(-1.0, 4.0), rng.range(-5.0, 65.0))));
+    points
+}
+
+const LAYERS: [Option<&str>; 3] = [None, Some("Tree"), Some("Bush")];
+
+#[test]
+fn within_radius_matches_brute_force() {
+    let (mut world, spheres) = forest();
+    let mut state: SystemState<ForestQuery> = SystemState::new(&mut world);
+    let forest_query = state.get_mut(&mut world);
+    for point in query_points() {
+        for radius in [0.0, 1.0, 4.0, 15.0] {
+            for layer in LAYERS {
+                let hits = forest_query.within_radius(point, radius, layer);
+                let mut expected: Vec<_> = filtered(&spheres, layer)
+                    .filter(|sphere| sphere_distance(sphere, point) <= radius)
+                    .map(|sphere| (sphere.chunk, sphere.id))
+                    .collect();
+                expected.sort();
+                assert_eq!(keys(&hits), expected, "{point} {radius} {layer:?}");
+            }
+        }
+    }
+}
+
+#[test]
+fn nearest_matches_brute_force() {
+    let (mut world, spheres) = forest();
+    let mut state: SystemState<ForestQuery> = SystemState::new(&mut world);
+    let forest_query = state.get_mut(&mut world);
+    for point in query_points() {
+        for k in [0, 1, 5, 50, 1000] {
+            for layer in LAYERS {
+                let hits = forest_query.nearest(point, k, layer);
+                let mut expected: Vec<f32> = filtered(&spheres, layer)
+                    .map(|sphere| sphere_distance(sphere, point))
+                    .collect();
+                expected.sort_by(f32::total_cmp);
+                expected.truncate(k);
+                assert_eq!(hits.len(), expected.len());
+                for (hit, expected) in hits.iter().zip(expected) {
+                    assert!((hit.distance - expected).abs() < 1e-4, "{point} {k} {layer:?}");
+                }
+                //Closest first
+                assert!(hits.windows(2).all(|pair| pair[0].distance <= pair[1].distance));
+            }
+        }
+    }
+}
+
+fn assert_raycast(forest_query: &ForestQuery, spheres: &[Sphere], origin: Vec3, direction: Vec3) {
+    for max_distance in [10.0, 1000.0] {
+        for layer in LAYERS {
+            let hit = forest_query.raycast(origin, direction, max_distance, layer);
+            let expected = filtered(spheres, layer)
+                .filter_map(|sphere| Some((ray_distance(sphere, origin, direction)?, sphere)))
+                .filter(|(distance, _)| *distance <= max_distance)
+                .min_by(|a, b| a.0.total_cmp(&b.0));
+            match (hit, expected) {
+                (None, None) => {}
+                (Some(hit), Some((distance, sphere))) => {
+                    assert!((hit.distance - distance).abs() < 1e-4, "{origin} {direction}");
+                    assert_eq!((hit.chunk, hit.id), (sphere.chunk, sphere.id));
+                }
+                (hit, expected) => panic!(
+                    "{origin} {direction} {layer:?}: got {:?}, expected {:?}",
+                    hit.map(|hit| hit.distance),
+                    expected.map(|(distance, _)| distance)
+                ),
+            }
+        }
+    }
+}
+
+#[test]
+fn raycast_matches_brute_force() {
+    let (mut world, spheres) = forest();
+    let mut state: SystemState<ForestQuery> = SystemState::new(&mut world);
+    let forest_query = state.get_mut(&mut world);
+    let mut rng = ForestRng::new(7);
+    for _ in 0..100 {
+        let origin = Vec3::new(rng.range(-20.0, 80.0), rng.range(0.5, 30.0), rng.range(-20.0, 80.0));
+        let target = Vec3::new(rng.range(0.0, 60.0), rng.range(0.0, 3.0), rng.range(0.0, 60.0));
+        assert_raycast(&forest_query, &spheres, origin, (target - origin).normalize());
+    }
+
+    //Axis aligned rays, also from origins exactly on the planes of the chunk bounds
+    let mut xs: Vec<f32> = (0..4).map(|_| rng.range(0.0, 60.0)).collect();
+    let mut zs: Vec<f32> = (0..4).map(|_| rng.range(0.0, 60.0)).collect();
+    for chunk in spheres.iter().map(|sphere| sphere.chunk).collect::<std::collections::BTreeSet<_>>() {
+        let (min, max) = spheres
+            .iter()
+            .filter(|sphere| sphere.chunk == chunk)
+            .fold((Vec3::splat(f32::MAX), Vec3::splat(f32::MIN)), |(min, max), sphere| {
+                (min.min(sphere.center - sphere.radius), max.max(sphere.center + sphere.radius))
+            });
+        xs.extend([min.x, max.x]);
+        zs.extend([min.z, max.z]);
+    }
+    for &x in xs.iter() {
+        for &z in zs.iter() {
+            assert_raycast(&forest_query, &spheres, Vec3::new(x, 40.0, z), Vec3::NEG_Y);
+        }
+        assert_raycast(&forest_query, &spheres, Vec3::new(x, 1.0, -20.0), Vec3::Z);
+    }
+    for &z in zs.iter() {
+        assert_raycast(&forest_query, &spheres, Vec3::new(80.0, 1.0, z), Vec3::NEG_X);
+    }
+}
@@ -0,0 +1,488 @@
+use bevy::{
+    prelude::*,
+    render::render_resource::{Extent3d, TextureDimension, TextureFormat},
+};
+use bevy_efficient_forest_rendering::{
+    chunk_grass::{sample_growth, ChunkGrass, GridConfig},
+    rng::{hash_to_unit, pcg_hash},
+    scatter::FlatGround,
+};
+
+const RNG_WGSL: &str = include_str!("../assets/shaders/forest_rng.wgsl");
+const GRASS_PLACEMENT_WGSL: &str = include_str!("../assets/shaders/grass_placement.wgsl");
+
+fn chunk(seed: u32) -> ChunkGrass {
+    ChunkGrass {
+        seed,
+        chunk_half_extents: [15.0, 15.0],
+        nr_instances: 3,
+        scale: 1.6,
+        height_modifier: 0.6,
+        ..default()
+    }
+}
+
+fn growth_image(layers: &[[u8; 4]]) -> Image {
+    Image::new(
+        Extent3d {
+            width: 2,
+            height: 2,
+            depth_or_array_layers: layers.len() as u32,
+        },
+        TextureDimension::D2,
+        layers.concat(),
+        TextureFormat::R8Unorm,
+    )
+}
+
+/// forest_rng.wgsl and grass_placement.wgsl as one module, the import lines resolved by concatenating them
+/// in dependency order. grass.wgsl itself imports bevy_pbr and is only validated by bevy when the pipeline is built
+fn placement_module() -> String {
+    [RNG_WGSL, GRASS_PLACEMENT_WGSL]
+        .iter()
+        .flat_map(|source| source.lines())
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Runs the placement functions for every index and growth_at at every uv, layer `index % 3` of `growth`
+const PLACEMENT_COMPUTE_WGSL: &str = r#"
+struct Params {
+    seed: u32,
+    count: u32,
+    chunk_half_extents: vec2<f32>,
+};
+
+@group(0) @binding(0)
+var<uniform> params: Params;
+@group(0) @binding(1)
+var<storage, read> uvs: array<vec2<f32>>;
+@group(0) @binding(2)
+var<storage, read_write> results: array<u32>;
+
+@compute @workgroup_size(64)
+fn main(@builtin(global_invocation_id) id: vec3<u32>) {
+    let index = id.x;
+    if (index >= params.count) {
+        return;
+    }
+    let hash = blade_hash(params.seed, index);
+    let placement = blade_placement(hash, params.chunk_half_extents);
+    let first = index * 8u;
+    results[first] = pcg_hash(index);
+    results[first + 1u] = bitcast<u32>(hash_to_unit(pcg_hash(index)));
+    results[first + 2u] = hash;
+    results[first + 3u] = bitcast<u32>(placement.x);
+    results[first + 4u] = bitcast<u32>(placement.y);
+    results[first + 5u] = bitcast<u32>(placement.z);
+    results[first + 6u] = bitcast<u32>(blade_density_threshold(hash));
+    results[first + 7u] = bitcast<u32>(growth_at(uvs[index], i32(index % 3u)));
+}
+"#;
+
+/// Dispatches PLACEMENT_COMPUTE_WGSL, None when there is no adapter that can run compute shaders
+fn run_placement(
+    seed: u32,
+    chunk_half_extents: [f32; 2],
+    uvs: &[[f32; 2]],
+    growth: &Image,
+) -> Option<Vec<u32>> {
+    use wgpu::util::DeviceExt;
+
+    let instance = wgpu::Instance::new(wgpu::Backends::all());
+    let adapter =
+        pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions::default()))?;
+    if !adapter
+        .get_downlevel_capabilities()
+        .flags
+        .contains(wgpu::DownlevelFlags::COMPUTE_SHADERS)
+    {
+        return None;
+    }
+    let (device, queue) = pollster::block_on(adapter.request_device(
+        &wgpu::DeviceDescriptor {
+            label: None,
+            features: wgpu::Features::empty(),
+            limits: wgpu::Limits::downlevel_defaults(),
+        },
+        None,
+    ))
+    .ok()?;
+
+    let source = placement_module() + PLACEMENT_COMPUTE_WGSL;
+    let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: Some("grass_placement_test"),
+        source: wgpu::ShaderSource::Wgsl(source.into()),
+    });
+    let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+        label: None,
+        layout: None,
+        module: &module,
+        entry_point: "main",
+    });
+
+    let count = uvs.len() as u32;
+    let params = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: None,
+        contents: bytemuck::cast_slice(&[
+            seed,
+            count,
+            chunk_half_extents[0].to_bits(),
+            chunk_half_extents[1].to_bits(),
+        ]),
+        usage: wgpu::BufferUsages::UNIFORM,
+    });
+    let uv_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: None,
+        contents: bytemuck::cast_slice(uvs),
+        usage: wgpu::BufferUsages::STORAGE,
+    });
+    let size = count as u64 * 8 * 4;
+    let results = device.create_buffer(&wgpu::BufferDescriptor {
+        label: None,
+        size,
+        usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+        mapped_at_creation: false,
+    });
+    let readback = device.create_buffer(&wgpu::BufferDescriptor {
+        label: None,
+        size,
+        usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    });
+
+    let growth_size = growth.texture_descriptor.size;
+    let growth_texture = device.create_texture_with_data(
+        &queue,
+        &wgpu::TextureDescriptor {
+            label: None,
+            size: wgpu::Extent3d {
+                width: growth_size.width,
+                height: growth_size.height,
+                depth_or_array_layers: growth_size.depth_or_array_layers,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::R8Unorm,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        },
+        &growth.data,
+    );
+    let growth_view = growth_texture.create_view(&wgpu::TextureViewDescriptor {
+        dimension: Some(wgpu::TextureViewDimension::D2Array),
+        ..default()
+    });
+
+    let buffers = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: None,
+        layout: &pipeline.get_bind_group_layout(0),
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: params.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: uv_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: results.as_entire_binding(),
+            },
+        ],
+    });
+    //growth_textures is declared in group 3 like in the grass pipeline, the groups between are empty
+    let empty: Vec<_> = (1..3)
+        .map(|group| {
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: None,
+                layout: &pipeline.get_bind_group_layout(group),
+                entries: &[],
+            })
+        })
+        .collect();
+    let growth_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: None,
+        layout: &pipeline.get_bind_group_layout(3),
+        entries: &[wgpu::BindGroupEntry {
+            binding: 0,
+            resource: wgpu::BindingResource::TextureView(&growth_view),
+        }],
+    });
+
+    let mut encoder =
+        device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+    {
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor { label: None });
+        pass.set_pipeline(&pipeline);
+        pass.set_bind_group(0, &buffers, &[]);
+        pass.set_bind_group(1, &empty[0], &[]);
+        pass.set_bind_group(2, &empty[1], &[]);
+        pass.set_bind_group(3, &growth_bind_group, &[]);
+        pass.dispatch_workgroups((count + 63) / 64, 1, 1);
+    }
+    encoder.copy_buffer_to_buffer(&results, 0, &readback, 0, size);
+    queue.submit(Some(encoder.finish()));
+
+    let slice = readback.slice(..);
+    slice.map_async(wgpu::MapMode::Read, |result| result.unwrap());
+    device.poll(wgpu::Maintain::Wait);
+    let view = slice.get_mapped_range();
+    let data: Vec<u32> = bytemuck::cast_slice(&view[..]).to_vec();
+    Some(data)
+}
+
+//Golden values of the cpu side, the shader tests below run grass_placement.wgsl and compare with them
+
+#[test]
+fn blade_placement_golden_values() {
+    let expected = [
+        (0, [0x416fbfb2, 0x41845f24], 1.3468302),
+        (1, [0x4084e452, 0x40783982], 0.979155),
+        (2, [0x4187f8b8, 0x41b78591], 2.9203906),
+    ];
+    for (index, [x, z], yaw) in expected {
+        let (position, blade_yaw) = chunk(0).blade_placement(index);
+        assert_eq!([position.x.to_bits(), position.y.to_bits()], [x, z]);
+        assert_eq!(blade_yaw, yaw);
+    }
+
+    let (position, yaw) = chunk(1234).blade_placement(2);
+    assert_eq!([position.x.to_bits(), position.y.to_bits()], [0x41bf3e82, 0x402ab9aa]);
+    assert_eq!(yaw, 0.21839191);
+}
+
+#[test]
+fn seeds_change_the_layout() {
+    let a: Vec<_> = (0..16).map(|i| chunk(1).blade_placement(i).0).collect();
+    let b: Vec<_> = (0..16).map(|i| chunk(2).blade_placement(i).0).collect();
+    assert!(a.iter().zip(b.iter()).all(|(a, b)| a != b));
+}
+
+#[test]
+fn shader_module_is_valid() {
+    //The compute entry of the test below too, so a shader error shows up without a gpu
+    let source = placement_module() + PLACEMENT_COMPUTE_WGSL;
+    let module = naga::front::wgsl::parse_str(&source)
+        .unwrap_or_else(|error| panic!("{}", error.emit_to_string(&source)));
+    naga::valid::Validator::new(
+        naga::valid::ValidationFlags::all(),
+        naga::valid::Capabilities::empty(),
+    )
+    .validate(&module)
+    .unwrap();
+    assert!(!GRASS_PLACEMENT_WGSL.contains("textureSample"), "growth has to be loaded, the sampler filters differently");
+    for function in [
+        "pcg_hash",
+        "hash_to_unit",
+        "blade_hash",
+        "blade_placement",
+        "blade_density_threshold",
+        "growth_at",
+    ] {
+        assert!(
+            module
+                .functions
+                .iter()
+                .any(|(_, f)| f.name.as_deref() == Some(function)),
+            "{} missing",
+            function
+        );
+    }
+}
+
+#[test]
+fn shader_matches_blade_placement_and_sample_growth() {
+    let grass = ChunkGrass {
+        chunk_half_extents: [15.0, 7.5],
+        ..chunk(1234)
+    };
+    //4x3 texels in 2 layers, index % 3 == 2 checks the layer clamp
+    let growth = Image::new(
+        Extent3d {
+            width: 4,
+            height: 3,
+            depth_or_array_layers: 2,
+        },
+        TextureDimension::D2,
+        (0..24u32).map(|i| (pcg_hash(i) >> 24) as u8).collect(),
+        TextureFormat::R8Unorm,
+    );
+    //Inside and past the edges
+    let uvs: Vec<[f32; 2]> = (0..512u32)
+        .map(|i| {
+            let hash = pcg_hash(i ^ 0x5bd1e995);
+            [
+                hash_to_unit(hash) * 1.4 - 0.2,
+                hash_to_unit(pcg_hash(hash)) * 1.4 - 0.2,
+            ]
+        })
+        .collect();
+
+    let results = match run_placement(grass.seed, grass.chunk_half_extents, &uvs, &growth) {
+        Some(results) => results,
+        None => {
+            eprintln!("no gpu adapter with compute shaders, skipping");
+            return;
+        }
+    };
+
+    for (index, result) in results.chunks(8).enumerate() {
+        let index = index as u32;
+        assert_eq!(result[0], pcg_hash(index), "pcg_hash({})", index);
+        assert_eq!(f32::from_bits(result[1]), hash_to_unit(pcg_hash(index)));
+        assert_eq!(result[2], pcg_hash(grass.seed ^ pcg_hash(index)));
+
+        let (position, yaw) = grass.blade_placement(index);
+        assert_eq!(
+            [result[3], result[4], result[5]],
+            [position.x.to_bits(), position.y.to_bits(), yaw.to_bits()],
+            "blade {}",
+            index
+        );
+        assert_eq!(
+            f32::from_bits(result[6]),
+            grass.blade_density_threshold(index)
+        );
+
+        //The bilinear blend matches up to float rounding
+        let uv = uvs[index as usize];
+        let expected = sample_growth(&growth, (index % 3) as i32, Vec2::new(uv[0], uv[1]));
+        let growth_at = f32::from_bits(result[7]);
+        assert!(
+            (growth_at - expected).abs() < 1e-5,
+            "uv {:?}: {} != {}",
+            uv,
+            growth_at,
+            expected
+        );
+    }
+}
+
+#[test]
+fn growth_is_sampled_bilinear() {
+    let image = growth_image(&[[0, 255, 255, 0], [51, 51, 51, 51]]);
+    //Texel centers
+    assert_eq!(sample_growth(&image, 0, Vec2::new(0.25, 0.25)), 0.0);
+    assert_eq!(sample_growth(&image, 0, Vec2::new(0.75, 0.25)), 1.0);
+    //Between all four texels
+    assert_eq!(sample_growth(&image, 0, Vec2::new(0.5, 0.5)), 0.5);
+    //Clamped at the edges
+    assert_eq!(sample_growth(&image, 0, Vec2::new(-1.0, 0.0)), 0.0);
+    assert_eq!(sample_growth(&image, 1, Vec2::new(0.3, 0.9)), 0.2);
+}
+
+#[test]
+fn blades_follow_growth() {
+    let grid_config = GridConfig {
+        grid_center_xy: [15.0, 15.0],
+        grid_half_extents: [15.0, 15.0],
+    };
+    let transform = GlobalTransform::default();
+    let grass = chunk(7);
+
+    let grown = growth_image(&[[255; 4]]);
+    for (index, blade) in grass
+        .blades(&transform, &grid_config, &grown, &FlatGround)
+        .enumerate()
+    {
+        let (position, yaw) = grass.blade_placement(index as u32);
+        assert_eq!(blade.position, Vec3::new(position.x, 0.0, position.y));
+        assert_eq!(blade.yaw, yaw);
+        assert_eq!(blade.growth, 1.0);
+        assert_eq!(blade.height, 1.6 * 0.6);
+    }
+
+    let bare = growth_image(&[[0; 4]]);
+    assert!(grass
+        .blades(&transform, &grid_config, &bare, &FlatGround)
+        .all(|blade| blade.height == 0.0));
+}
+
+#[test]
+fn density_scales_with_max_growth() {
+    let grid_config = GridConfig {
+        grid_center_xy: [15.0, 15.0],
+        grid_half_extents: [15.0, 15.0],
+    };
+    let sparse = growth_image(&[[51; 4]]);
+    assert!((chunk(0).max_growth(&grid_config, &sparse) - 0.2).abs() < 1e-5);
+
+    //10 blades per square metre over 30x30 metres at 0.2 growth
+    let grass = chunk(0).with_density(10.0, &grid_config, &sparse);
+    assert_eq!(grass.nr_instances, 1800);
+
+    //Growth at the max keeps the blades, half of it thins them out to about half
+    let transform = GlobalTransform::default();
+    assert!((0..64).all(|index| grass.blade(index, &transform, &grid_config, &sparse, &FlatGround).visible));
+    let half = growth_image(&[[25; 4]]);
+    let visible = grass
+        .blades(&transform, &grid_config, &half, &FlatGround)
+        .filter(|blade| blade.visible)
+        .count();
+    assert!((700..1100).contains(&visible), "{} of 1800 visible", visible);
+
+    let bare = growth_image(&[[0; 4]]);
+    let grass = chunk(0).with_density(10.0, &grid_config, &bare);
+    assert_eq!(grass.nr_instances, 0);
+}
+
+#[test]
+fn more_growth_only_adds_blades() {
+    let grid_config = GridConfig {
+        grid_center_xy: [15.0, 15.0],
+        grid_half_extents: [15.0, 15.0],
+    };
+    let transform = GlobalTransform::default();
+    let grass = chunk(5).with_density(2.0, &grid_config, &growth_image(&[[128; 4]]));
+    let visible = |growth: u8| -> Vec<bool> {
+        let image = growth_image(&[[growth; 4]]);
+        grass
+            .blades(&transform, &grid_config, &image, &FlatGround)
+            .map(|blade| blade.visible)
+            .collect()
+    };
+    let mut previous = visible(0);
+    assert!(previous.iter().all(|visible| !visible));
+    for growth in [32, 64, 127, 128, 129, 200, 255] {
+        let current = visible(growth);
+        assert!(previous.iter().zip(current.iter()).all(|(before, now)| !before || *now));
+        previous = current;
+    }
+    //At the max every blade is drawn
+    assert!(visible(128).iter().all(|visible| *visible));
+}
+
+#[test]
+fn mixed_growth_is_denser_where_it_grows_more() {
+    let grid_config = GridConfig {
+        grid_center_xy: [15.0, 15.0],
+        grid_half_extents: [15.0, 15.0],
+    };
+    let transform = GlobalTransform::default();
+    //0.2 growth on the left texels and 1 on the right, blended in between
+    let mixed = growth_image(&[[51, 255, 51, 255]]);
+    let grass = chunk(3).with_density(10.0, &grid_config, &mixed);
+    assert_eq!(grass.max_growth, 1.0);
+    assert_eq!(grass.nr_instances, 9000);
+
+    let (mut low, mut high) = (0, 0);
+    for blade in grass
+        .blades(&transform, &grid_config, &mixed, &FlatGround)
+        .filter(|blade| blade.visible)
+    {
+        if blade.position.x < 15.0 {
+            low += 1;
+        } else {
+            high += 1;
+        }
+    }
+    //Both halves are 15x30 metres, the growth averages 0.3 over the left half and 0.9 over the right.
+    //Scaling by the chunk average capped the right half at 6 blades per square metre
+    let (low, high) = (low as f32 / 450.0, high as f32 / 450.0);
+    assert!(high > low);
+    assert!((8.5..9.5).contains(&high), "{} blades per square metre", high);
+    assert!((2.5..3.5).contains(&low), "{} blades per square metre", low);
+}
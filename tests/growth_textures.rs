@@ -0,0 +1,210 @@
+use bevy::{
+    prelude::*,
+    render::render_resource::{Extent3d, TextureDimension, TextureFormat},
+};
+use bevy_efficient_forest_rendering::chunk_grass::{
+    sample_growth, ChunkGrass, GridConfig, GrowthError, GrowthLayer, GrowthPaint, GrowthTextures,
+    GrowthTexturesConfig,
+};
+
+fn growth_textures(config: GrowthTexturesConfig) -> (GrowthTextures, Image) {
+    let mut world = World::new();
+    world.insert_resource(Assets::<Image>::default());
+    world.insert_resource(config);
+    let growth_textures = GrowthTextures::from_world(&mut world);
+    let image = world
+        .resource::<Assets<Image>>()
+        .get(&growth_textures.growth_texture_array_handle)
+        .unwrap()
+        .clone();
+    (growth_textures, image)
+}
+
+#[test]
+fn layers_are_named() {
+    let (growth_textures, image) = growth_textures(GrowthTexturesConfig::default());
+    assert_eq!(growth_textures.layer("noise_1"), Some(0));
+    assert_eq!(growth_textures.layer("noise_2"), Some(1));
+    assert_eq!(growth_textures.layer("missing"), None);
+    assert_eq!(image.texture_descriptor.size.depth_or_array_layers, 2);
+
+    let grass = ChunkGrass::default().with_growth_layer("noise_2", &growth_textures);
+    assert_eq!(grass.growth_texture_id, 1);
+}
+
+#[test]
+fn unknown_layers_are_errors() {
+    let (mut growth_textures, _) = growth_textures(GrowthTexturesConfig::default());
+    let grass = ChunkGrass::default().try_with_growth_layer("missing", &growth_textures);
+    assert!(matches!(grass, Err(GrowthError::UnknownLayer(name)) if name == "missing"));
+    //Logged, the layer stays as it was
+    let grass = ChunkGrass::default()
+        .with_growth_layer("noise_2", &growth_textures)
+        .with_growth_layer("missing", &growth_textures);
+    assert_eq!(grass.growth_texture_id, 1);
+
+    let grid_config = GridConfig {
+        grid_center_xy: [0.0, 0.0],
+        grid_half_extents: [1.0, 1.0],
+    };
+    let paint = growth_textures.paint_circle(2, &grid_config, Vec2::ZERO, 1.0, GrowthPaint::Set(0.0));
+    assert!(matches!(paint, Err(GrowthError::LayerOutOfRange(2))));
+    assert!(matches!(
+        growth_textures.set_texel(-1, UVec2::ZERO, 0.0),
+        Err(GrowthError::LayerOutOfRange(-1))
+    ));
+    assert_eq!(growth_textures.revision(), 0);
+}
+
+#[test]
+fn broken_configs_are_errors() {
+    let mut images = Assets::<Image>::default();
+    let no_layers = GrowthTexturesConfig {
+        layers: Vec::new(),
+        ..default()
+    };
+    assert!(matches!(GrowthTextures::new(&no_layers, &mut images), Err(GrowthError::NoLayers)));
+
+    let float_image = Image::new(
+        Extent3d {
+            width: 1,
+            height: 1,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        vec![0; 4],
+        TextureFormat::R32Float,
+    );
+    let float_layer = GrowthTexturesConfig {
+        layers: vec![GrowthLayer::image("float", float_image)],
+        ..default()
+    };
+    assert!(matches!(
+        GrowthTextures::new(&float_layer, &mut images),
+        Err(GrowthError::UnsupportedFormat(TextureFormat::R32Float))
+    ));
+
+    //The resource falls back to the default layers
+    let (growth_textures, _) = growth_textures(float_layer);
+    assert_eq!(growth_textures.layer_names, ["noise_1", "noise_2"]);
+}
+
+#[test]
+fn image_layers_are_resampled() {
+    //2x2 rgba, only the red channel is used
+    let painted = Image::new(
+        Extent3d {
+            width: 2,
+            height: 2,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        vec![
+            0, 9, 9, 255, 255, 9, 9, 255, //
+            255, 9, 9, 255, 0, 9, 9, 255,
+        ],
+        TextureFormat::Rgba8Unorm,
+    );
+    let (growth_textures, image) = growth_textures(GrowthTexturesConfig {
+        resolution: UVec2::splat(4),
+        layers: vec![
+            GrowthLayer::perlin("noise", 3, 0.2),
+            GrowthLayer::image("painted", painted),
+        ],
+    });
+    let layer = growth_textures.layer("painted").unwrap();
+    assert_eq!(image.texture_descriptor.size.width, 4);
+    //Corner texels keep the painted values, the center is their average
+    assert_eq!(sample_growth(&image, layer, Vec2::new(0.125, 0.125)), 0.0);
+    assert_eq!(sample_growth(&image, layer, Vec2::new(0.875, 0.125)), 1.0);
+    assert!((sample_growth(&image, layer, Vec2::new(0.5, 0.5)) - 0.5).abs() < 1e-3);
+}
+
+fn texel(growth_textures: &GrowthTextures, layer: i32, x: u32, y: u32) -> u8 {
+    let resolution = growth_textures.resolution();
+    growth_textures.image().data[((layer as u32 * resolution.y + y) * resolution.x + x) as usize]
+}
+
+#[test]
+fn painting_changes_the_cpu_copy() {
+    //One texel per metre over 0..10
+    let grid_config = GridConfig {
+        grid_center_xy: [5.0, 5.0],
+        grid_half_extents: [5.0, 5.0],
+    };
+    let (mut growth_textures, _) = growth_textures(GrowthTexturesConfig {
+        resolution: UVec2::splat(10),
+        layers: vec![GrowthLayer::perlin("a", 1, 0.1), GrowthLayer::perlin("b", 2, 0.1)],
+    });
+    let before = growth_textures.image().data.clone();
+
+    growth_textures
+        .paint_circle(1, &grid_config, Vec2::new(5.0, 5.0), 1.0, GrowthPaint::Set(0.0))
+        .unwrap();
+    assert_eq!(growth_textures.revision(), 1);
+    //Texel centers within a metre of (5, 5)
+    for (x, y) in [(4, 4), (5, 4), (4, 5), (5, 5)] {
+        assert_eq!(texel(&growth_textures, 1, x, y), 0);
+    }
+    assert_eq!(texel(&growth_textures, 1, 3, 4), before[(10 * 10 + 4 * 10 + 3) as usize]);
+    //The other layer is untouched
+    assert_eq!(growth_textures.image().data[..100], before[..100]);
+
+    growth_textures
+        .paint_circle(1, &grid_config, Vec2::new(5.0, 5.0), 1.0, GrowthPaint::Add(0.5))
+        .unwrap();
+    assert_eq!(texel(&growth_textures, 1, 5, 5), 128);
+    growth_textures
+        .paint_circle(1, &grid_config, Vec2::new(5.0, 5.0), 1.0, GrowthPaint::Add(-2.0))
+        .unwrap();
+    assert_eq!(texel(&growth_textures, 1, 5, 5), 0);
+
+    growth_textures.set_texel(0, UVec2::new(9, 9), 1.0).unwrap();
+    assert_eq!(texel(&growth_textures, 0, 9, 9), 255);
+    assert_eq!(growth_textures.revision(), 4);
+
+    //Outside of the grid nothing changes
+    growth_textures
+        .paint_circle(0, &grid_config, Vec2::new(-20.0, 5.0), 1.0, GrowthPaint::Set(1.0))
+        .unwrap();
+    assert_eq!(growth_textures.revision(), 4);
+}
+
+#[test]
+fn brush_mask_weights_the_paint() {
+    let grid_config = GridConfig {
+        grid_center_xy: [1.0, 1.0],
+        grid_half_extents: [1.0, 1.0],
+    };
+    let (mut growth_textures, _) = growth_textures(GrowthTexturesConfig {
+        resolution: UVec2::splat(2),
+        layers: vec![GrowthLayer::image(
+            "full",
+            Image::new(
+                Extent3d {
+                    width: 1,
+                    height: 1,
+                    depth_or_array_layers: 1,
+                },
+                TextureDimension::D2,
+                vec![255],
+                TextureFormat::R8Unorm,
+            ),
+        )],
+    });
+    //Left column of the mask mows, the right one keeps the growth
+    let mask = Image::new(
+        Extent3d {
+            width: 2,
+            height: 1,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        vec![255, 0],
+        TextureFormat::R8Unorm,
+    );
+    growth_textures
+        .paint_brush(0, &grid_config, Vec2::ONE, Vec2::splat(2.0), &mask, GrowthPaint::Set(0.0))
+        .unwrap();
+    assert_eq!(growth_textures.image().data, vec![0, 255, 0, 255]);
+}
@@ -0,0 +1,129 @@
+use bevy::{
+    prelude::*,
+    render::{
+        mesh::{Indices, PrimitiveTopology},
+        render_resource::{Extent3d, TextureDimension, TextureFormat},
+    },
+};
+use bevy_efficient_forest_rendering::impostor::{ImpostorBakeError, ImpostorBaker};
+
+const RED: [u8; 4] = [255, 0, 0, 255];
+const GREEN: [u8; 4] = [0, 255, 0, 255];
+const GREY: [u8; 4] = [100, 150, 200, 255];
+
+/// 2x2 quad in the xy plane facing +z, red on the left half of its texture and green on the right
+fn quad() -> (Mesh, Image) {
+    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+    mesh.insert_attribute(
+        Mesh::ATTRIBUTE_POSITION,
+        vec![[-1.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 2.0, 0.0], [-1.0, 2.0, 0.0]],
+    );
+    mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, vec![[0.0, 1.0], [1.0, 1.0], [1.0, 0.0], [0.0, 0.0]]);
+    mesh.set_indices(Some(Indices::U32(vec![0, 1, 2, 0, 2, 3])));
+    let texture = texture(2, [RED, GREEN].concat());
+    (mesh, texture)
+}
+
+fn texture(width: u32, data: Vec<u8>) -> Image {
+    texture_with_format(width, data, TextureFormat::Rgba8UnormSrgb)
+}
+
+fn texture_with_format(width: u32, data: Vec<u8>, format: TextureFormat) -> Image {
+    Image::new(
+        Extent3d {
+            width,
+            height: 1,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        data,
+        format,
+    )
+}
+
+fn texel(image: &Image, x: u32, y: u32) -> [u8; 4] {
+    let i = ((y * image.texture_descriptor.size.width + x) * 4) as usize;
+    image.data[i..i + 4].try_into().unwrap()
+}
+
+#[test]
+fn frames_are_laid_out_in_a_grid() {
+    let (mesh, texture) = quad();
+    for (frames, columns, rows) in [(1, 1, 1), (4, 2, 2), (5, 3, 2), (9, 3, 3), (10, 4, 3)] {
+        let atlas = ImpostorBaker {
+            frames,
+            tile_size: 4,
+            ..default()
+        }
+        .bake(&mesh, &texture)
+        .unwrap();
+        assert_eq!(atlas.frames, frames);
+        assert_eq!(atlas.columns, columns);
+        let size = atlas.image.texture_descriptor.size;
+        assert_eq!((size.width, size.height), (columns * 4, rows * 4));
+    }
+}
+
+#[test]
+fn frames_are_seen_from_around_the_y_axis() {
+    let (mesh, texture) = quad();
+    let atlas = ImpostorBaker {
+        frames: 4,
+        tile_size: 8,
+        ..default()
+    }
+    .bake(&mesh, &texture)
+    .unwrap();
+    assert_eq!(atlas.half_width, 1.0);
+    assert_eq!((atlas.min_y, atlas.max_y), (0.0, 2.0));
+
+    //Frame 0 looks at the front, the quad fills the tile
+    assert_eq!(texel(&atlas.image, 1, 4), RED);
+    assert_eq!(texel(&atlas.image, 6, 4), GREEN);
+    //Frame 2 looks at the back, mirrored
+    assert_eq!(texel(&atlas.image, 1, 12), GREEN);
+    assert_eq!(texel(&atlas.image, 6, 12), RED);
+    //Frames 1 and 3 see the quad edge on, nothing is opaque
+    for y in 0..16 {
+        for x in 8..16 {
+            assert_eq!(texel(&atlas.image, x, y)[3], 0);
+        }
+    }
+}
+
+#[test]
+fn atlas_keeps_the_texture_format() {
+    let (mesh, _) = quad();
+    for format in [TextureFormat::Rgba8Unorm, TextureFormat::Rgba8UnormSrgb] {
+        let texture = texture_with_format(2, [GREY, GREY].concat(), format);
+        let atlas = ImpostorBaker {
+            frames: 1,
+            tile_size: 4,
+            ..default()
+        }
+        .bake(&mesh, &texture)
+        .unwrap();
+        assert_eq!(atlas.image.texture_descriptor.format, format);
+        //Same bytes in the same color space, including the dilated border
+        assert!(atlas.image.data.chunks_exact(4).all(|texel| texel[..3] == GREY[..3]));
+    }
+}
+
+#[test]
+fn empty_bakes_are_errors() {
+    let (mesh, texture) = quad();
+    let no_frames = ImpostorBaker {
+        frames: 0,
+        ..default()
+    };
+    assert!(matches!(no_frames.bake(&mesh, &texture), Err(ImpostorBakeError::NoFrames)));
+    let no_tile = ImpostorBaker {
+        tile_size: 0,
+        ..default()
+    };
+    assert!(matches!(no_tile.bake(&mesh, &texture), Err(ImpostorBakeError::EmptyTile)));
+    assert!(matches!(
+        ImpostorBaker::default().bake(&mesh, &texture(0, Vec::new())),
+        Err(ImpostorBakeError::EmptyTexture)
+    ));
+}
@@ -0,0 +1,36 @@
+use bevy::prelude::*;
+use bevy_efficient_forest_rendering::chunk_instancing::{select_lod, MeshLod};
+
+fn lods(distances: &[f32]) -> Vec<MeshLod> {
+    distances
+        .iter()
+        .map(|distance| MeshLod {
+            mesh: Handle::default(),
+            distance: *distance,
+        })
+        .collect()
+}
+
+#[test]
+fn lods_switch_at_their_distance() {
+    let lods = lods(&[50.0, 100.0]);
+    assert_eq!(select_lod(&lods, 0.0), None);
+    assert_eq!(select_lod(&lods, 49.99), None);
+    assert_eq!(select_lod(&lods, 50.0), Some(0));
+    assert_eq!(select_lod(&lods, 99.99), Some(0));
+    assert_eq!(select_lod(&lods, 100.0), Some(1));
+    assert_eq!(select_lod(&lods, f32::INFINITY), Some(1));
+}
+
+#[test]
+fn unsorted_lods() {
+    let lods = lods(&[100.0, 50.0]);
+    assert_eq!(select_lod(&lods, 75.0), Some(1));
+    assert_eq!(select_lod(&lods, 150.0), Some(0));
+}
+
+#[test]
+fn no_lods() {
+    assert_eq!(select_lod(&[], 1000.0), None);
+    assert_eq!(select_lod(&lods(&[50.0]), f32::NAN), None);
+}
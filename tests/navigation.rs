@@ -0,0 +1,170 @@
+use bevy::prelude::*;
+use bevy_efficient_forest_rendering::{
+    chunk_instancing::{ChunkInstancing, Instance, InstanceFormat},
+    navigation::{NavigationFootprint, NavigationGrid, NavigationPlugin},
+};
+use bevy_inspector_egui::InspectableRegistry;
+
+/// One metre cells, small chunks so the paths cross chunk borders
+fn app() -> App {
+    let mut app = App::new();
+    app.init_resource::<InspectableRegistry>()
+        .insert_resource(NavigationGrid::new(1.0, 8))
+        .add_plugin(NavigationPlugin);
+    app
+}
+
+/// Instances on the centers of `cells`
+fn layer(cells: impl IntoIterator<Item = IVec2>) -> ChunkInstancing {
+    let mut chunk_instancing = ChunkInstancing::default();
+    chunk_instancing.instance_format = InstanceFormat::Full;
+    chunk_instancing.set_instances(
+        cells
+            .into_iter()
+            .map(|cell| Instance::new(Vec3::new(cell.x as f32 + 0.5, 0.0, cell.y as f32 + 0.5), Quat::IDENTITY, Vec3::ONE))
+            .collect(),
+    );
+    chunk_instancing
+}
+
+fn spawn_layer(app: &mut App, cells: impl IntoIterator<Item = IVec2>, footprint: NavigationFootprint) -> Entity {
+    let entity = app
+        .world
+        .spawn()
+        .insert(layer(cells))
+        .insert(GlobalTransform::identity())
+        .insert(footprint)
+        .id();
+    app.update();
+    entity
+}
+
+/// Neighbouring cells only, no cell blocked and no corner of a blocked cell cut
+fn assert_walkable(grid: &NavigationGrid, path: &[IVec2]) {
+    for pair in path.windows(2) {
+        let step = pair[1] - pair[0];
+        assert!(step.abs().max_element() == 1, "{:?} to {:?} is no step", pair[0], pair[1]);
+        assert!(!grid.is_blocked(pair[1]));
+        if step.x != 0 && step.y != 0 {
+            assert!(!grid.is_blocked(pair[0] + IVec2::new(step.x, 0)));
+            assert!(!grid.is_blocked(pair[0] + IVec2::new(0, step.y)));
+        }
+    }
+}
+
+#[test]
+fn open_ground_is_a_straight_line() {
+    let grid = NavigationGrid::new(1.0, 8);
+    let path = grid.find_path_cells(IVec2::ZERO, IVec2::new(10, 0)).unwrap();
+    assert_eq!(path, (0..=10).map(|x| IVec2::new(x, 0)).collect::<Vec<_>>());
+}
+
+#[test]
+fn blocked_goal_has_no_path() {
+    let mut app = app();
+    spawn_layer(&mut app, [IVec2::new(10, 0)], NavigationFootprint::blocking(0.1));
+    let grid = app.world.resource::<NavigationGrid>();
+    assert!(grid.is_blocked(IVec2::new(10, 0)));
+    assert_eq!(grid.find_path_cells(IVec2::ZERO, IVec2::new(10, 0)), None);
+    assert!(grid.find_path(Vec2::new(0.5, 0.5), Vec2::new(10.5, 0.5)).is_none());
+}
+
+#[test]
+fn walls_are_walked_around() {
+    let mut app = app();
+    //Wall across x = 5 from y = -3 to 3
+    spawn_layer(&mut app, (-3..=3).map(|y| IVec2::new(5, y)), NavigationFootprint::blocking(0.1));
+    let grid = app.world.resource::<NavigationGrid>();
+    let path = grid.find_path_cells(IVec2::ZERO, IVec2::new(10, 0)).unwrap();
+    assert_eq!(path[0], IVec2::ZERO);
+    assert_eq!(*path.last().unwrap(), IVec2::new(10, 0));
+    assert_walkable(grid, &path);
+    //Around the end of the wall
+    assert!(path.iter().any(|cell| cell.x == 5 && cell.y.abs() >= 4));
+
+    let points = grid.find_path(Vec2::new(0.2, 0.3), Vec2::new(10.7, 0.1)).unwrap();
+    assert_eq!(points.len(), path.len());
+    assert_eq!(points[0], Vec2::new(0.2, 0.3));
+    assert_eq!(*points.last().unwrap(), Vec2::new(10.7, 0.1));
+    assert_eq!(points[1], grid.cell_center(path[1]));
+}
+
+#[test]
+fn expensive_cells_are_avoided() {
+    let mut app = app();
+    //Slow 3 x 3 patch in the way, a short detour is cheaper than walking through
+    let patch = (4..=6).flat_map(|x| (-1..=1).map(move |y| IVec2::new(x, y)));
+    spawn_layer(&mut app, patch.clone(), NavigationFootprint::cost(0.1, 10.0));
+    let grid = app.world.resource::<NavigationGrid>();
+    for cell in patch {
+        assert!(!grid.is_blocked(cell));
+        assert_eq!(grid.cost(cell), Some(11.0));
+    }
+    let path = grid.find_path_cells(IVec2::ZERO, IVec2::new(10, 0)).unwrap();
+    assert_walkable(grid, &path);
+    assert!(path.iter().all(|cell| grid.cost(*cell) == Some(1.0)));
+}
+
+#[test]
+fn footprints_follow_the_instances() {
+    let mut app = app();
+    let wall: Vec<IVec2> = (-3..=3).map(|y| IVec2::new(5, y)).collect();
+    let entity = spawn_layer(&mut app, wall.clone(), NavigationFootprint::blocking(0.1));
+    assert!(wall.iter().all(|cell| app.world.resource::<NavigationGrid>().is_blocked(*cell)));
+
+    //Half of the wall moves away
+    *app.world.get_mut::<ChunkInstancing>(entity).unwrap() = layer(wall[..3].iter().copied());
+    app.update();
+    let grid = app.world.resource::<NavigationGrid>();
+    assert!(wall[..3].iter().all(|cell| grid.is_blocked(*cell)));
+    assert!(wall[3..].iter().all(|cell| !grid.is_blocked(*cell)));
+
+    //No footprint, no obstacles
+    app.world.entity_mut(entity).remove::<NavigationFootprint>();
+    app.update();
+    let grid = app.world.resource::<NavigationGrid>();
+    assert!(wall.iter().all(|cell| !grid.is_blocked(*cell)));
+    let path = grid.find_path_cells(IVec2::ZERO, IVec2::new(10, 0)).unwrap();
+    assert_eq!(path.len(), 11);
+}
+
+#[test]
+fn footprints_are_placed_like_the_rendered_mesh() {
+    let mut app = app();
+    //Model moved 2 m along x and doubled, the footprint follows the trunk and grows with it
+    let mut chunk_instancing = layer([IVec2::new(5, 0)]);
+    chunk_instancing.model_transform = Transform::from_xyz(2.0, 0.0, 0.0).with_scale(Vec3::splat(2.0));
+    app.world
+        .spawn()
+        .insert(chunk_instancing)
+        .insert(GlobalTransform::identity())
+        .insert(NavigationFootprint::blocking(0.6));
+    //Scaled chunk, the instance at (2.5, 0.5) ends up at (5, 1)
+    app.world
+        .spawn()
+        .insert(layer([IVec2::new(2, 0)]))
+        .insert(GlobalTransform::from_scale(Vec3::splat(2.0)))
+        .insert(NavigationFootprint::blocking(0.4));
+    app.update();
+
+    let grid = app.world.resource::<NavigationGrid>();
+    let blocked: Vec<IVec2> = (-2..=10)
+        .flat_map(|x| (-2..=4).map(move |y| IVec2::new(x, y)))
+        .filter(|cell| grid.is_blocked(*cell))
+        .collect();
+    let mut expected = vec![
+        //Radius 1.2 around (7.5, 0.5)
+        IVec2::new(6, 0),
+        IVec2::new(7, -1),
+        IVec2::new(7, 0),
+        IVec2::new(7, 1),
+        IVec2::new(8, 0),
+        //Radius 0.8 around (5, 1)
+        IVec2::new(4, 0),
+        IVec2::new(4, 1),
+        IVec2::new(5, 0),
+        IVec2::new(5, 1),
+    ];
+    expected.sort_by_key(|cell| (cell.x, cell.y));
+    assert_eq!(blocked, expected);
+}
@@ -0,0 +1,130 @@
+use bevy::{
+    prelude::*,
+    render::render_resource::{Extent3d, TextureDimension, TextureFormat},
+};
+use bevy_efficient_forest_rendering::{
+    chunk_instancing::Instance,
+    scatter::{DensityMap, PoissonScatter, ScatterLayer},
+};
+
+const TREE: usize = 0;
+const BUSH: usize = 1;
+
+fn forest() -> PoissonScatter {
+    PoissonScatter::new(30.0)
+        .with_layer(ScatterLayer::new(4.0))
+        .with_layer(ScatterLayer::new(1.5).with_exclusion(TREE, 1.0))
+}
+
+fn positions(instances: &[Instance]) -> Vec<Vec2> {
+    instances
+        .iter()
+        .map(|instance| Vec2::new(instance.pos_xyz[0], instance.pos_xyz[2]))
+        .collect()
+}
+
+#[test]
+fn layers_keep_min_distance() {
+    let scatter = forest();
+    for (layer, instances) in scatter.scatter(7).iter().enumerate() {
+        let points = positions(instances);
+        assert!(!points.is_empty());
+        for (i, a) in points.iter().enumerate() {
+            for b in points[i + 1..].iter() {
+                assert!(a.distance(*b) >= scatter.layers[layer].min_distance);
+            }
+        }
+    }
+}
+
+#[test]
+fn exclusion_between_layers() {
+    let layers = forest().scatter(7);
+    for tree in positions(&layers[TREE]) {
+        for bush in positions(&layers[BUSH]) {
+            assert!(tree.distance(bush) >= 1.0);
+        }
+    }
+}
+
+#[test]
+fn neighbouring_chunks_keep_spacing() {
+    let scatter = forest();
+    let chunk_size = scatter.chunk_size;
+    let left = scatter.scatter(1);
+    let right = scatter.scatter(2);
+    for (layer, (left, right)) in left.iter().zip(right.iter()).enumerate() {
+        for a in positions(left) {
+            for b in positions(right) {
+                let b = b + Vec2::new(chunk_size, 0.0);
+                assert!(a.distance(b) >= scatter.layers[layer].min_distance);
+            }
+        }
+    }
+    //Bushes of one chunk also keep away from trees of the next
+    for tree in positions(&left[TREE]) {
+        for bush in positions(&right[BUSH]) {
+            assert!(tree.distance(bush + Vec2::new(chunk_size, 0.0)) >= 1.0);
+        }
+    }
+}
+
+#[test]
+fn margins() {
+    let scatter = forest();
+    assert_eq!(scatter.margin(TREE), 2.0);
+    assert_eq!(scatter.margin(BUSH), 0.75);
+    for (layer, instances) in scatter.scatter(3).iter().enumerate() {
+        let margin = scatter.margin(layer);
+        for point in positions(instances) {
+            assert!(point.x >= margin && point.x < scatter.chunk_size - margin);
+            assert!(point.y >= margin && point.y < scatter.chunk_size - margin);
+        }
+    }
+}
+
+#[test]
+fn deterministic() {
+    let a = forest().scatter(42);
+    let b = forest().scatter(42);
+    for (a, b) in a.iter().zip(b.iter()) {
+        assert_eq!(a.len(), b.len());
+        for (a, b) in a.iter().zip(b.iter()) {
+            assert_eq!(a.pos_xyz.map(f32::to_bits), b.pos_xyz.map(f32::to_bits));
+            assert_eq!(a.rotation.to_array().map(f32::to_bits), b.rotation.to_array().map(f32::to_bits));
+        }
+    }
+    assert_ne!(positions(&a[TREE]), positions(&forest().scatter(43)[TREE]));
+}
+
+#[test]
+fn appending_a_layer_keeps_earlier_layers() {
+    let two = forest().scatter(5);
+    let three = forest()
+        .with_layer(ScatterLayer::new(0.5).with_exclusion(TREE, 0.5))
+        .scatter(5);
+    assert_eq!(positions(&two[TREE]), positions(&three[TREE]));
+}
+
+#[test]
+fn max_instances() {
+    let layers = PoissonScatter::new(30.0)
+        .with_layer(ScatterLayer::new(1.0).with_max_instances(10))
+        .scatter(1);
+    assert_eq!(layers[0].len(), 10);
+}
+
+#[test]
+fn density_maps_read_the_red_channel() {
+    let size = Extent3d {
+        width: 1,
+        height: 1,
+        depth_or_array_layers: 1,
+    };
+    let rgba = Image::new(size, TextureDimension::D2, vec![255, 0, 0, 255], TextureFormat::Rgba8Unorm);
+    let bgra = Image::new(size, TextureDimension::D2, vec![0, 0, 255, 255], TextureFormat::Bgra8Unorm);
+    for image in [rgba, bgra] {
+        let map = DensityMap::from_image(&image, Vec2::ZERO, Vec2::ONE).unwrap();
+        assert_eq!(map.sample(0.5, 0.5), 1.0);
+    }
+}
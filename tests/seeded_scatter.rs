@@ -0,0 +1,84 @@
+use bevy::prelude::*;
+use bevy_efficient_forest_rendering::{
+    chunk_instancing::ChunkInstancing,
+    rng::{chunk_seed, ForestRng},
+};
+
+//Golden values, if these change every seeded world changes with them
+
+#[test]
+fn forest_rng_is_pcg32() {
+    //Reference output of the pcg32 demo (seed 42, stream 54)
+    let mut rng = ForestRng::with_stream(42, 54);
+    assert_eq!(
+        [rng.next_u32(), rng.next_u32(), rng.next_u32()],
+        [0xa15c02b7, 0x7b47f409, 0xba1d3330]
+    );
+}
+
+#[test]
+fn forest_rng_default_stream() {
+    let mut rng = ForestRng::new(42);
+    let values: Vec<u32> = (0..6).map(|_| rng.next_u32()).collect();
+    assert_eq!(
+        values,
+        [492690617, 1919685028, 3561993920, 683038915, 1183706632, 413921556]
+    );
+}
+
+#[test]
+fn chunk_seeds_are_stable() {
+    assert_eq!(chunk_seed(1234, [3, -2], 0), 0x18fbfb215bd7343b);
+    assert_eq!(chunk_seed(1234, [3, -2], 1), 0x5a89b8afbaa1a099);
+}
+
+#[test]
+fn seeded_instances_are_bit_identical() {
+    let chunk_instancing = ChunkInstancing::new_seeded(
+        3,
+        Handle::default(),
+        Transform::identity(),
+        32.0,
+        chunk_seed(1234, [3, -2], 0),
+    );
+    let bits: Vec<[u32; 6]> = chunk_instancing
+        .instances()
+        .iter()
+        .map(|instance| {
+            [
+                instance.pos_xyz[0].to_bits(),
+                instance.pos_xyz[1].to_bits(),
+                instance.pos_xyz[2].to_bits(),
+                instance.pos_xyz[3].to_bits(),
+                instance.rotation.y.to_bits(),
+                instance.rotation.w.to_bits(),
+            ]
+        })
+        .collect();
+    assert_eq!(
+        bits,
+        [
+            [0x41e9d234, 0x00000000, 0x41f4e259, 0x3f4fb09c, 0x3f7daf29, 0xbe096c48],
+            [0x4049db30, 0x00000000, 0x40a6cd10, 0x3f512c5c, 0x3f38c5ca, 0xbf312fc3],
+            [0x41353a26, 0x00000000, 0x41c699eb, 0x3f4be850, 0xbf7ff143, 0xbcadbb6e],
+        ]
+    );
+}
+
+#[test]
+fn same_seed_same_instances() {
+    let seed = chunk_seed(99, [0, 0], 2);
+    let a = ChunkInstancing::new_seeded(500, Handle::default(), Transform::identity(), 16.0, seed);
+    let b = ChunkInstancing::new_seeded(500, Handle::default(), Transform::identity(), 16.0, seed);
+    for (a, b) in a.instances().iter().zip(b.instances().iter()) {
+        assert_eq!(a.pos_xyz.map(f32::to_bits), b.pos_xyz.map(f32::to_bits));
+        assert_eq!(a.rotation.to_array().map(f32::to_bits), b.rotation.to_array().map(f32::to_bits));
+    }
+}
+
+#[test]
+fn different_chunks_differ() {
+    let a = ChunkInstancing::new_seeded(8, Handle::default(), Transform::identity(), 16.0, chunk_seed(99, [0, 0], 0));
+    let b = ChunkInstancing::new_seeded(8, Handle::default(), Transform::identity(), 16.0, chunk_seed(99, [0, 1], 0));
+    assert_ne!(a.instances()[0].pos_xyz, b.instances()[0].pos_xyz);
+}
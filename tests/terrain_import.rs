@@ -0,0 +1,32 @@
+use bevy::{
+    prelude::*,
+    render::render_resource::{Extent3d, TextureDimension, TextureFormat},
+};
+use bevy_efficient_forest_rendering::terrain::{ForestTerrain, TerrainError};
+
+#[test]
+fn raw_heights_need_two_samples_per_side() {
+    let terrain = ForestTerrain::from_raw(&[0; 8], UVec2::splat(2), Vec2::ZERO, Vec2::ONE, 0.0, 1.0);
+    assert!(terrain.is_ok());
+    for resolution in [UVec2::new(1, 4), UVec2::new(4, 1), UVec2::ZERO] {
+        let bytes = vec![0; (resolution.x * resolution.y * 2) as usize];
+        let terrain = ForestTerrain::from_raw(&bytes, resolution, Vec2::ZERO, Vec2::ONE, 0.0, 1.0);
+        assert!(matches!(terrain, Err(TerrainError::TooSmall(r)) if r == resolution));
+    }
+}
+
+#[test]
+fn image_heights_need_two_samples_per_side() {
+    let image = Image::new(
+        Extent3d {
+            width: 3,
+            height: 1,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        vec![0; 3],
+        TextureFormat::R8Unorm,
+    );
+    let terrain = ForestTerrain::from_image(&image, Vec2::ZERO, Vec2::ONE, 0.0, 1.0);
+    assert!(matches!(terrain, Err(TerrainError::TooSmall(_))));
+}